@@ -0,0 +1,7 @@
+// The per-frame camera data, matching CameraUniformBlock
+layout(set = 1, binding = 0) uniform CameraBlock {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    vec4 position;
+} camera;
@@ -1,5 +1,8 @@
 #version 450
 #extension GL_ARB_separate_shader_objects : enable
+#extension GL_GOOGLE_include_directive : require
+
+#include "include/camera.glsl"
 
 layout(constant_id = 0) const float scale = 1.0f;
 
@@ -8,11 +11,15 @@ layout(location = 1) in vec2 a_TexCoord;
 
 layout(location = 0) out vec2 vs_TexCoord;
 
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+} push;
+
 out gl_PerVertex {
     vec4 gl_Position;
 };
 
 void main() {
     vs_TexCoord = a_TexCoord;
-    gl_Position = vec4(scale * a_Position, 1.0);
+    gl_Position = camera.view_projection * push.model * vec4(scale * a_Position, 1.0);
 }
\ No newline at end of file
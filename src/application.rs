@@ -0,0 +1,29 @@
+use errors::*;
+use graphics::adapter::AdapterConfig;
+use winit::WindowEvent;
+use Renderer;
+
+/// The callbacks used by `libcorporation::run` to drive a game
+/// through the lifetime of the engine's frame loop
+pub trait Application {
+    /// The requirements used to choose a graphics adapter, which
+    /// can be overridden by setting the `CORPORATION_ADAPTER` environment variable
+    fn adapter_config(&self) -> AdapterConfig {
+        AdapterConfig::default()
+    }
+
+    /// Called once the renderer has been created, before the first frame
+    fn init(&mut self, renderer: &mut Renderer) -> Result<()>;
+
+    /// Called for each window event raised since the previous frame
+    fn handle_event(&mut self, _event: &WindowEvent) {}
+
+    /// Called once per frame with the time elapsed since the previous frame, in seconds
+    fn update(&mut self, delta_time: f32) -> Result<()>;
+
+    /// Called once per frame, after update, to draw the application
+    fn render(&mut self, renderer: &mut Renderer) -> Result<()>;
+
+    /// Called once the frame loop has finished, including when it ends with an error, before the renderer is destroyed
+    fn shutdown(&mut self, _renderer: &mut Renderer) {}
+}
@@ -1,11 +1,108 @@
+extern crate cgmath;
 extern crate error_chain;
 extern crate libcorporation;
 extern crate env_logger;
+extern crate winit;
+
+use cgmath::*;
+use libcorporation::application::Application;
+use libcorporation::camera::Camera;
+use libcorporation::errors::*;
+use libcorporation::graphics::model::ModelState;
+use libcorporation::mesh::Importer;
+use libcorporation::{Backend, Renderer};
+use winit::WindowEvent;
+
+const MODEL_FILE_PATH: &str = "resources/models/box/box.obj";
+
+/// The centre of the box model, which the camera orbits around
+const ORBIT_TARGET: [f32; 3] = [3.12, 1.27, 5.82];
+
+/// Radians per second that the camera orbits around the model
+const ORBIT_SPEED: f32 = 0.5;
+const ORBIT_RADIUS: f32 = 8.0;
+const ORBIT_HEIGHT: f32 = 4.0;
+
+const FIELD_OF_VIEW: f32 = 65.0;
+const Z_NEAR: f32 = 0.1;
+const Z_FAR: f32 = 100.0;
+
+/// Draws the box model with a camera orbiting around it
+struct Sandbox {
+    camera: Camera<f32>,
+    model_state: Option<ModelState<Backend>>,
+    orbit_angle: f32,
+}
+
+impl Sandbox {
+    fn new() -> Self {
+        let mut camera = Camera::default();
+        camera.set_projection_matrix(640.0, 480.0, FIELD_OF_VIEW, Z_NEAR, Z_FAR);
+
+        Sandbox {
+            camera,
+            model_state: None,
+            orbit_angle: 0.0,
+        }
+    }
+}
+
+impl Application for Sandbox {
+    fn init(&mut self, renderer: &mut Renderer) -> Result<()> {
+        let scene = Importer::load(MODEL_FILE_PATH)?;
+
+        self.model_state = Some(unsafe { renderer.create_model(&scene)? });
+
+        Ok(())
+    }
+
+    fn handle_event(&mut self, event: &WindowEvent) {
+        // The projection's aspect ratio must follow the window's, or the model is stretched:
+        if let WindowEvent::Resized(size) = event {
+            if size.width > 0.0 && size.height > 0.0 {
+                self.camera
+                    .set_projection_matrix(size.width as f32, size.height as f32, FIELD_OF_VIEW, Z_NEAR, Z_FAR);
+            }
+        }
+    }
+
+    fn update(&mut self, delta_time: f32) -> Result<()> {
+        self.orbit_angle += ORBIT_SPEED * delta_time;
+
+        let target = Point3::from(ORBIT_TARGET);
+
+        self.camera.set_position(
+            target.x + ORBIT_RADIUS * self.orbit_angle.cos(),
+            target.y + ORBIT_HEIGHT,
+            target.z + ORBIT_RADIUS * self.orbit_angle.sin(),
+        );
+        self.camera.look_at(target, Vector3::unit_y());
+
+        Ok(())
+    }
+
+    fn render(&mut self, renderer: &mut Renderer) -> Result<()> {
+        if let Some(model_state) = self.model_state.as_ref() {
+            unsafe {
+                renderer.render_frame(model_state, &self.camera)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn shutdown(&mut self, _renderer: &mut Renderer) {
+        // GPU resources must be released while the renderer is still alive:
+        self.model_state.take();
+    }
+}
 
 fn main() {
     env_logger::init();
 
-    if let Err(ref e) = libcorporation::run() {
+    let mut sandbox = Sandbox::new();
+
+    if let Err(ref e) = libcorporation::run(&mut sandbox) {
         use std::io::Write;
         use error_chain::ChainedError;
 
@@ -15,4 +112,4 @@ fn main() {
         writeln!(stderr, "{}", e.display_chain()).expect(errmsg);
         std::process::exit(1);
     }
-}
\ No newline at end of file
+}
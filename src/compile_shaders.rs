@@ -0,0 +1,68 @@
+extern crate env_logger;
+extern crate error_chain;
+extern crate libcorporation;
+
+use libcorporation::errors::*;
+use libcorporation::graphics::shader::{CompileOptions, Kind, SHADER_DIR};
+use libcorporation::graphics::shader_cache::{self, find_files, ShaderCache, ShaderManifest};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Compiles every shader in the shaders directory into the SPIR-V cache, with both the
+/// debug and release options, so that neither kind of build has to compile them at startup.
+/// The manifest is rewritten to list only these shaders, which is all builds without the compiler load.
+///
+/// The shaders are always read from the source tree, as this runs from beside the executables of the build being
+/// packaged. The SPIR-V is written to the directory given as the first argument, e.g. `shaders/spirv` within that
+/// build, or otherwise to the directory the engine reads it from.
+fn run() -> Result<()> {
+    let output_dir = env::args_os().nth(1).map_or_else(shader_cache::spirv_cache_dir, PathBuf::from);
+    let cache = ShaderCache::new(&output_dir);
+    let mut manifest = ShaderManifest::new();
+    let mut shader_count = 0;
+
+    for path in find_files(Path::new(SHADER_DIR)) {
+        let kind = match Kind::from_path(&path) {
+            Some(kind) => kind,
+            None => continue, // Included files are compiled as part of the shaders that include them
+        };
+
+        let file_path = path.to_string_lossy();
+        let source = fs::read_to_string(&path)?;
+
+        for options in &[
+            CompileOptions::debug().include_dir(SHADER_DIR),
+            CompileOptions::release().include_dir(SHADER_DIR),
+        ] {
+            cache.load_or_compile(&file_path, &kind, &source, options)?;
+
+            let key = ShaderCache::key(&kind, &source, options);
+            manifest.insert(&file_path, options, cache.entry_path(&file_path, options, &key));
+        }
+
+        println!("Compiled {}", file_path);
+        shader_count += 1;
+    }
+
+    manifest.save(&output_dir)?;
+
+    println!("Compiled {} shader(s) into {}", shader_count, output_dir.display());
+
+    Ok(())
+}
+
+fn main() {
+    env_logger::init();
+
+    if let Err(ref e) = run() {
+        use error_chain::ChainedError;
+        use std::io::Write;
+
+        let stderr = &mut ::std::io::stderr();
+        let errmsg = "Error writing to stderr";
+
+        writeln!(stderr, "{}", e.display_chain()).expect(errmsg);
+        std::process::exit(1);
+    }
+}
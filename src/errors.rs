@@ -1 +1,110 @@
-error_chain!{}
\ No newline at end of file
+use std::fmt::Debug;
+
+error_chain! {
+    foreign_links {
+        Io(::std::io::Error);
+    }
+
+    errors {
+        AssetNotFound(path: String) {
+            description("asset not found")
+            display("Asset not found: '{}'", path)
+        }
+
+        ImportFailed(path: String, reason: String) {
+            description("failed to import asset")
+            display("Failed to import '{}': {}", path, reason)
+        }
+
+        ShaderCompileFailed(name: String, log: String) {
+            description("failed to compile shader")
+            display("Failed to compile shader '{}':\n{}", name, log)
+        }
+
+        ShaderSourceError(file: String, line: u32, message: String) {
+            description("error in shader source")
+            display("Error in shader '{}' at line {}: {}", file, line, message)
+        }
+
+        ShaderCompilerUnavailable(name: String) {
+            description("shader compiler unavailable")
+            display("No precompiled SPIR-V was found for shader '{}', and runtime shader compilation is disabled", name)
+        }
+
+        InvalidSpirv(reason: String) {
+            description("invalid SPIR-V")
+            display("Invalid SPIR-V: {}", reason)
+        }
+
+        ShaderInterfaceMismatch(reason: String) {
+            description("shader interface does not match the pipeline")
+            display("The shaders don't match the pipeline layout: {}", reason)
+        }
+
+        UnsupportedTextureFormat(format: String) {
+            description("unsupported texture format")
+            display("Unsupported texture format: {}", format)
+        }
+
+        InvalidTextureData(reason: String) {
+            description("invalid texture data")
+            display("Invalid texture data: {}", reason)
+        }
+
+        InvalidTextureUpdate(reason: String) {
+            description("invalid texture update")
+            display("Invalid texture update: {}", reason)
+        }
+
+        OutOfDeviceMemory(size: u64) {
+            description("out of device memory")
+            display("Out of device memory while allocating {} bytes", size)
+        }
+
+        NoSuitableAdapter {
+            description("no suitable graphics adapter")
+            display("No graphics adapter supports rendering to this window")
+        }
+
+        SurfaceLost(reason: String) {
+            description("window surface lost")
+            display("The window surface can no longer be rendered to: {}", reason)
+        }
+
+        InvalidUniformLayout(block: String, reason: String) {
+            description("uniform block does not follow the std140 layout")
+            display("Uniform block '{}' does not follow the std140 layout: {}", block, reason)
+        }
+
+        Graphics(operation: String, reason: String) {
+            description("graphics operation failed")
+            display("Failed to {}: {}", operation, reason)
+        }
+    }
+}
+
+/// Converts the errors returned by gfx_hal into `ErrorKind::Graphics`
+pub trait GraphicsResultExt<T> {
+    /// Names the operation that failed, e.g. "create buffer"
+    fn graphics_err(self, operation: &str) -> Result<T>;
+}
+
+impl<T, E: Debug> GraphicsResultExt<T> for ::std::result::Result<T, E> {
+    fn graphics_err(self, operation: &str) -> Result<T> {
+        self.map_err(|error| ErrorKind::Graphics(operation.to_string(), format!("{:?}", error)).into())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn should_convert_graphics_error() {
+        let result: ::std::result::Result<(), &str> = Err("OutOfHostMemory");
+
+        let error = result.graphics_err("create buffer").unwrap_err();
+
+        assert_eq!("Failed to create buffer: \"OutOfHostMemory\"", error.to_string());
+    }
+}
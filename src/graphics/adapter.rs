@@ -1,4 +1,196 @@
+use errors::*;
+use gfx_hal::adapter::DeviceType;
 use gfx_hal::*;
+use std::env;
+
+/// Environment variable used to force a particular adapter, by index or by name
+pub const ADAPTER_ENV_VAR: &str = "CORPORATION_ADAPTER";
+
+/// Picks a specific adapter rather than letting them be scored
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdapterOverride {
+  /// The position of the adapter in the enumerated list
+  Index(usize),
+  /// A case-insensitive substring of the adapter's name
+  Name(String),
+}
+
+impl AdapterOverride {
+  /// Numeric values select an adapter by index, anything else by name
+  pub fn parse(value: &str) -> Option<Self> {
+    let value = value.trim();
+
+    if value.is_empty() {
+      return None;
+    }
+
+    Some(match value.parse::<usize>() {
+      Ok(index) => AdapterOverride::Index(index),
+      Err(_) => AdapterOverride::Name(value.to_string()),
+    })
+  }
+
+  fn matches(&self, index: usize, candidate: &AdapterCandidate) -> bool {
+    match *self {
+      AdapterOverride::Index(override_index) => override_index == index,
+      AdapterOverride::Name(ref name) => candidate.name.to_lowercase().contains(&name.to_lowercase()),
+    }
+  }
+}
+
+/// The requirements an adapter must meet to be chosen, along with an optional override
+#[derive(Debug, Clone, Default)]
+pub struct AdapterConfig {
+  pub adapter_override: Option<AdapterOverride>,
+  /// The smallest amount of device local memory accepted, in bytes
+  pub min_device_memory: u64,
+  pub min_texture_size: usize,
+  pub min_vertex_input_attributes: usize,
+}
+
+impl AdapterConfig {
+  pub fn with_override(mut self, adapter_override: AdapterOverride) -> Self {
+    self.adapter_override = Some(adapter_override);
+    self
+  }
+
+  pub fn min_device_memory(mut self, min_device_memory: u64) -> Self {
+    self.min_device_memory = min_device_memory;
+    self
+  }
+
+  pub fn min_texture_size(mut self, min_texture_size: usize) -> Self {
+    self.min_texture_size = min_texture_size;
+    self
+  }
+
+  pub fn min_vertex_input_attributes(mut self, min_vertex_input_attributes: usize) -> Self {
+    self.min_vertex_input_attributes = min_vertex_input_attributes;
+    self
+  }
+
+  /// Replaces the override with the one given by `CORPORATION_ADAPTER`, if it is set
+  pub fn apply_env_override(self) -> Self {
+    let env_override = env::var(ADAPTER_ENV_VAR).ok().and_then(|value| AdapterOverride::parse(&value));
+
+    match env_override {
+      Some(adapter_override) => self.with_override(adapter_override),
+      None => self,
+    }
+  }
+}
+
+/// The properties of an adapter that are considered when choosing between them
+#[derive(Debug, Clone)]
+pub struct AdapterCandidate {
+  pub name: String,
+  pub device_type: DeviceType,
+  pub device_memory: u64,
+  pub supports_surface: bool,
+  pub max_texture_size: usize,
+  pub max_vertex_input_attributes: usize,
+}
+
+impl AdapterCandidate {
+  pub fn new<B: Backend>(adapter: &Adapter<B>, surface: &B::Surface) -> Self {
+    let memory_properties = adapter.physical_device.memory_properties();
+    let limits = adapter.physical_device.limits();
+
+    // Several memory types can share a heap, so each heap is only counted once:
+    let mut device_heaps: Vec<usize> = memory_properties
+      .memory_types
+      .iter()
+      .filter(|memory_type| memory_type.properties.contains(memory::Properties::DEVICE_LOCAL))
+      .map(|memory_type| memory_type.heap_index)
+      .collect();
+    device_heaps.sort();
+    device_heaps.dedup();
+
+    let supports_surface = adapter
+      .queue_families
+      .iter()
+      .any(|family| family.supports_graphics() && surface.supports_queue_family(family));
+
+    AdapterCandidate {
+      name: adapter.info.name.clone(),
+      device_type: adapter.info.device_type.clone(),
+      device_memory: device_heaps
+        .iter()
+        .map(|&heap_index| memory_properties.memory_heaps[heap_index])
+        .sum(),
+      supports_surface,
+      max_texture_size: limits.max_texture_size,
+      max_vertex_input_attributes: limits.max_vertex_input_attributes,
+    }
+  }
+
+  /// Returns the reason this adapter cannot be used, if any
+  fn rejection(&self, config: &AdapterConfig) -> Option<&'static str> {
+    if !self.supports_surface {
+      Some("no graphics queue can present to the surface")
+    } else if self.device_memory < config.min_device_memory {
+      Some("not enough device memory")
+    } else if self.max_texture_size < config.min_texture_size {
+      Some("maximum texture size is too small")
+    } else if self.max_vertex_input_attributes < config.min_vertex_input_attributes {
+      Some("too few vertex input attributes")
+    } else {
+      None
+    }
+  }
+
+  /// Discrete GPUs are preferred over integrated ones, then over software renderers,
+  /// with device memory breaking ties between adapters of the same type
+  fn score(&self) -> (u32, u64) {
+    let type_score = match self.device_type {
+      DeviceType::DiscreteGpu => 4,
+      DeviceType::IntegratedGpu => 3,
+      DeviceType::VirtualGpu => 2,
+      DeviceType::Other => 1,
+      DeviceType::Cpu => 0,
+    };
+
+    (type_score, self.device_memory)
+  }
+}
+
+/// Returns the index of the adapter to use, honouring the override where it names a suitable adapter
+pub fn select_adapter(candidates: &[AdapterCandidate], config: &AdapterConfig) -> Option<usize> {
+  if let Some(ref adapter_override) = config.adapter_override {
+    let overridden = candidates
+      .iter()
+      .enumerate()
+      .find(|&(index, candidate)| adapter_override.matches(index, candidate));
+
+    match overridden {
+      Some((index, candidate)) => match candidate.rejection(config) {
+        None => return Some(index),
+        Some(reason) => warn!(
+          "Ignoring adapter override {:?}, '{}' is unsuitable: {}",
+          adapter_override, candidate.name, reason
+        ),
+      },
+      None => warn!("Ignoring adapter override {:?}, no adapter matches it", adapter_override),
+    }
+  }
+
+  let mut selected: Option<(usize, (u32, u64))> = None;
+
+  for (index, candidate) in candidates.iter().enumerate() {
+    if let Some(reason) = candidate.rejection(config) {
+      info!("Rejecting adapter '{}': {}", candidate.name, reason);
+      continue;
+    }
+
+    // The first of several equally scored adapters is kept:
+    let score = candidate.score();
+    if selected.map_or(true, |(_, best_score)| score > best_score) {
+      selected = Some((index, score));
+    }
+  }
+
+  selected.map(|(index, _)| index)
+}
 
 /// Defines the capabilities of a single graphics adapter
 pub struct AdapterState<B: Backend> {
@@ -8,25 +200,110 @@ pub struct AdapterState<B: Backend> {
 }
 
 impl<B: Backend> AdapterState<B> {
-  pub fn new(adapters: &mut Vec<Adapter<B>>) -> Self {
+  pub fn new(adapters: &mut Vec<Adapter<B>>, surface: &B::Surface, config: &AdapterConfig) -> Result<Self> {
     for adapter in adapters.iter() {
       info!("Available {:?}", adapter.info);
     }
 
-    // TODO: Iterate and choose the most optimal adapter
-    warn!("Choosing Adapter(0) by default.");
+    let candidates: Vec<AdapterCandidate> = adapters.iter().map(|adapter| AdapterCandidate::new(adapter, surface)).collect();
 
-    let adapter = adapters.remove(0);
+    let adapter = match select_adapter(&candidates, config) {
+      Some(index) => adapters.remove(index),
+      None => bail!(ErrorKind::NoSuitableAdapter),
+    };
+
+    info!("Choosing {:?}", adapter.info);
 
     let mem_types = adapter.physical_device.memory_properties().memory_types;
     let limits = adapter.physical_device.limits();
 
     info!("Adapter Limits: {:?}", &limits);
 
-    AdapterState {
+    Ok(AdapterState {
       adapter: Some(adapter),
       mem_types,
       limits,
+    })
+  }
+}
+
+#[cfg(test)]
+pub mod test {
+  use super::*;
+
+  fn candidate(name: &str, device_type: DeviceType, device_memory: u64) -> AdapterCandidate {
+    AdapterCandidate {
+      name: name.to_string(),
+      device_type,
+      device_memory,
+      supports_surface: true,
+      max_texture_size: 16384,
+      max_vertex_input_attributes: 16,
     }
   }
+
+  fn laptop_adapters() -> Vec<AdapterCandidate> {
+    vec![
+      candidate("Intel(R) UHD Graphics 630", DeviceType::IntegratedGpu, 1 << 30),
+      candidate("llvmpipe (LLVM 7.0, 256 bits)", DeviceType::Cpu, 4 << 30),
+      candidate("NVIDIA GeForce GTX 1050", DeviceType::DiscreteGpu, 2 << 30),
+    ]
+  }
+
+  #[test]
+  fn should_prefer_discrete_adapter() {
+    assert_eq!(Some(2), select_adapter(&laptop_adapters(), &AdapterConfig::default()));
+  }
+
+  #[test]
+  fn should_prefer_more_device_memory() {
+    let adapters = vec![
+      candidate("Small", DeviceType::DiscreteGpu, 2 << 30),
+      candidate("Large", DeviceType::DiscreteGpu, 8 << 30),
+    ];
+
+    assert_eq!(Some(1), select_adapter(&adapters, &AdapterConfig::default()));
+  }
+
+  #[test]
+  fn should_reject_unsuitable_adapters() {
+    let mut adapters = laptop_adapters();
+    adapters[2].supports_surface = false;
+
+    assert_eq!(Some(0), select_adapter(&adapters, &AdapterConfig::default()));
+
+    let config = AdapterConfig::default().min_device_memory(3 << 30);
+    assert_eq!(Some(1), select_adapter(&adapters, &config));
+
+    let config = AdapterConfig::default().min_texture_size(32768);
+    assert_eq!(None, select_adapter(&adapters, &config));
+  }
+
+  #[test]
+  fn should_select_overridden_adapter() {
+    let config = AdapterConfig::default().with_override(AdapterOverride::Index(0));
+    assert_eq!(Some(0), select_adapter(&laptop_adapters(), &config));
+
+    let config = AdapterConfig::default().with_override(AdapterOverride::Name("intel".to_string()));
+    assert_eq!(Some(0), select_adapter(&laptop_adapters(), &config));
+  }
+
+  #[test]
+  fn should_ignore_unmatched_override() {
+    let config = AdapterConfig::default().with_override(AdapterOverride::Name("Radeon".to_string()));
+    assert_eq!(Some(2), select_adapter(&laptop_adapters(), &config));
+
+    let config = AdapterConfig::default().with_override(AdapterOverride::Index(3));
+    assert_eq!(Some(2), select_adapter(&laptop_adapters(), &config));
+  }
+
+  #[test]
+  fn should_parse_adapter_override() {
+    assert_eq!(Some(AdapterOverride::Index(1)), AdapterOverride::parse("1"));
+    assert_eq!(
+      Some(AdapterOverride::Name("GeForce".to_string())),
+      AdapterOverride::parse(" GeForce ")
+    );
+    assert_eq!(None, AdapterOverride::parse(""));
+  }
 }
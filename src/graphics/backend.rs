@@ -1,6 +1,7 @@
+use errors::*;
 use gfx_hal::format::{AsFormat, Rgba8Srgb};
 use gfx_hal::*;
-use graphics::adapter::AdapterState;
+use graphics::adapter::{AdapterConfig, AdapterState};
 use graphics::window::WindowState;
 
 pub type ColorFormat = Rgba8Srgb;
@@ -28,24 +29,32 @@ pub struct BackendState<B: Backend> {
 impl<B: Backend> BackendState<B> {
 
   #[cfg(any(feature = "vulkan", feature = "dx12", feature = "metal"))]
-  pub fn new(window_state: &mut WindowState) -> (BackendState<gfx_backend::Backend>, gfx_backend::Instance) {
-    let window = window_state.window_builder.take().unwrap().build(&window_state.event_loop).unwrap();
+  pub fn new(
+    window_state: &mut WindowState,
+    adapter_config: &AdapterConfig,
+  ) -> Result<(BackendState<gfx_backend::Backend>, gfx_backend::Instance)> {
+    let window = window_state
+      .window_builder
+      .take()
+      .unwrap()
+      .build(&window_state.event_loop)
+      .graphics_err("create window")?;
 
     let instance = gfx_backend::Instance::create("libcorporation", 1);
     let surface = instance.create_surface(&window);
     let mut adapters = instance.enumerate_adapters();
 
     let backend_state = BackendState {
-      adapter_state: AdapterState::new(&mut adapters),
+      adapter_state: AdapterState::new(&mut adapters, &surface, adapter_config)?,
       surface,
       window,
     };
 
-    (backend_state, instance)
+    Ok((backend_state, instance))
   }
 
   #[cfg(feature = "gl")]
-  pub fn new(window_state: &mut WindowState) -> (BackendState<gfx_backend::Backend>, ()) {
+  pub fn new(window_state: &mut WindowState, adapter_config: &AdapterConfig) -> Result<(BackendState<gfx_backend::Backend>, ())> {
     let window = {
       let builder = gfx_backend::config_context(gfx_backend::glutin::ContextBuilder::new(), ColorFormat::SELF, None).with_vsync(true);
 
@@ -53,22 +62,22 @@ impl<B: Backend> BackendState<B> {
         window_state.window_builder.take().unwrap(),
         builder,
         window_state.borrow_event_loop(),
-      ).unwrap()
+      ).graphics_err("create window")?
     };
 
     let surface = gfx_backend::Surface::from_window(window);
     let mut adapters = surface.enumerate_adapters();
 
     let backend_state = BackendState {
-      adapter_state: AdapterState::new(&mut adapters),
+      adapter_state: AdapterState::new(&mut adapters, &surface, adapter_config)?,
       surface
     };
 
-    (backend_state, ())
+    Ok((backend_state, ()))
   }
 
   #[cfg(feature = "empty")]
-  pub fn new(window_state: &mut WindowState) {
+  pub fn new(window_state: &mut WindowState, adapter_config: &AdapterConfig) {
 
   }
 }
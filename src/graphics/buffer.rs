@@ -1,135 +1,225 @@
+use errors::*;
 use gfx_hal::memory as m;
 use gfx_hal::*;
-use graphics::adapter::AdapterState;
 use graphics::device::DeviceState;
 use graphics::image::Image;
+use graphics::memory::MemoryAllocation;
+use graphics::tracker::ResourceId;
+use graphics::vertex::VertexFormat;
 use std::cell::RefCell;
 use std::rc::Rc;
 
 pub struct BufferState<B: Backend, C: Capability> {
   device_state: Rc<RefCell<DeviceState<B, C>>>,
-  memory: Option<B::Memory>,
+  memory: Option<MemoryAllocation<B>>,
   pub buffer: Option<B::Buffer>,
   size: u64,
+  resource_id: ResourceId,
 }
 
 impl<B: Backend, C: Capability> BufferState<B, C> {
-  pub unsafe fn new<T>(
-    device_state: Rc<RefCell<DeviceState<B, C>>>,
-    buffer_source: &[T],
-    buffer_usage: buffer::Usage,
-    memory_types: &[MemoryType],
-  ) -> Self
+  pub unsafe fn new<T>(device_state: Rc<RefCell<DeviceState<B, C>>>, buffer_source: &[T], buffer_usage: buffer::Usage) -> Result<Self>
   where
     T: Copy,
   {
     let stride = std::mem::size_of::<T>() as u64;
     let upload_size = buffer_source.len() as u64 * stride;
 
-    let device = &device_state.borrow().device;
-
-    let mut buffer = device.create_buffer(upload_size, buffer_usage).unwrap();
-
-    let (memory, memory_size) = Self::allocate_buffer_memory(device, &buffer, memory_types, m::Properties::CPU_VISIBLE);
-
-    device.bind_buffer_memory(&memory, 0, &mut buffer).unwrap();
+    let buffer_state = Self::allocate(device_state, upload_size, buffer_usage, m::Properties::CPU_VISIBLE)?;
 
     // Write the data to the buffer
     {
-      let mut data_target = device.acquire_mapping_writer::<T>(&memory, 0..memory_size).unwrap();
+      let device_state = buffer_state.device_state.as_ref().borrow();
+      let device = &device_state.device;
+      let allocator = device_state.allocator.borrow();
+      let memory = buffer_state.memory.as_ref().unwrap();
+
+      let mut data_target = device
+        .acquire_mapping_writer::<T>(allocator.memory(memory), memory.range())
+        .graphics_err("map buffer memory")?;
       data_target[0..buffer_source.len()].copy_from_slice(&buffer_source);
-      device.release_mapping_writer(data_target).unwrap();
+      device.release_mapping_writer(data_target).graphics_err("unmap buffer memory")?;
     }
 
-    BufferState {
-      device_state: Rc::clone(&device_state),
-      memory: Some(memory),
-      buffer: Some(buffer),
-      size: memory_size,
-    }
+    Ok(buffer_state)
+  }
+
+  /// Creates an uninitialised buffer in device local memory, which is filled by transferring data into it
+  pub unsafe fn new_device_local(device_state: Rc<RefCell<DeviceState<B, C>>>, size: u64, buffer_usage: buffer::Usage) -> Result<Self> {
+    Self::allocate(
+      device_state,
+      size,
+      buffer_usage | buffer::Usage::TRANSFER_DST,
+      m::Properties::DEVICE_LOCAL,
+    )
+  }
+
+  /// Creates an uninitialised buffer in host visible memory, which is filled with `update_data`
+  pub unsafe fn new_host_visible(device_state: Rc<RefCell<DeviceState<B, C>>>, size: u64, buffer_usage: buffer::Usage) -> Result<Self> {
+    Self::allocate(device_state, size, buffer_usage, m::Properties::CPU_VISIBLE)
   }
 
-  /// Locates an available space in memory for the buffer depending on buffer requirements and memory property
-  pub fn find_buffer_memory(memory_types: &[MemoryType], memory_requirements: &m::Requirements, property: m::Properties) -> MemoryTypeId {
-    memory_types
-      .iter()
-      .enumerate()
-      .position(|(id, mem_type)| memory_requirements.type_mask & (1 << id) != 0 && mem_type.properties.contains(property))
-      .unwrap()
-      .into()
+  /// Creates a vertex buffer from vertices whose layout is described by VertexFormat
+  pub unsafe fn new_vertex_buffer<V>(device_state: Rc<RefCell<DeviceState<B, C>>>, vertices: &[V]) -> Result<Self>
+  where
+    V: VertexFormat,
+  {
+    Self::new::<V>(device_state, vertices, buffer::Usage::VERTEX)
   }
 
-  unsafe fn allocate_buffer_memory(
-    device: &B::Device,
-    buffer: &B::Buffer,
-    memory_types: &[MemoryType],
-    memory_property: m::Properties,
-  ) -> (B::Memory, u64) {
-    let buffer_mem_requirements = device.get_buffer_requirements(&buffer);
+  /// Overwrites the buffer contents from `offset` bytes onwards with the data provided
+  pub unsafe fn update_data<T>(&mut self, offset: u64, data_source: &[T]) -> Result<()>
+  where
+    T: Copy,
+  {
+    let stride = std::mem::size_of::<T>() as u64;
+    let update_size = data_source.len() as u64 * stride;
+
+    assert!(offset + update_size <= self.size, "Buffer update exceeds the size of the buffer");
 
-    let size = buffer_mem_requirements.size;
+    let device_state = self.device_state.as_ref().borrow();
+    let device = &device_state.device;
+    let allocator = device_state.allocator.borrow();
 
-    let upload_type = Self::find_buffer_memory(memory_types, &buffer_mem_requirements, memory_property);
+    let memory = self.memory.as_ref().unwrap();
+    let start = memory.offset() + offset;
 
-    let memory = device.allocate_memory(upload_type, size).unwrap();
+    let mut data_target = device
+      .acquire_mapping_writer::<T>(allocator.memory(memory), start..start + update_size)
+      .graphics_err("map buffer memory")?;
+    data_target[0..data_source.len()].copy_from_slice(data_source);
+    device.release_mapping_writer(data_target).graphics_err("unmap buffer memory")?;
 
-    (memory, size)
+    Ok(())
+  }
+
+  unsafe fn allocate(
+    device_state: Rc<RefCell<DeviceState<B, C>>>,
+    size: u64,
+    buffer_usage: buffer::Usage,
+    memory_properties: m::Properties,
+  ) -> Result<Self> {
+    let (buffer, memory, resource_id) = Self::create_bound_buffer(&device_state.as_ref().borrow(), size, buffer_usage, memory_properties)?;
+
+    Ok(BufferState {
+      device_state,
+      memory: Some(memory),
+      buffer: Some(buffer),
+      size,
+      resource_id,
+    })
   }
 
+  /// Creates a buffer holding the rows of blocks of the image, padded to the row pitch required for copies into an image
   pub unsafe fn new_texture(
     device_state: Rc<RefCell<DeviceState<B, C>>>,
-    device: &B::Device,
-    adapter_state: &AdapterState<B>,
+    device_state_ref: &DeviceState<B, C>,
     image: &Image,
+    row_alignment_mask: u32,
     buffer_usage: buffer::Usage,
-  ) -> Self {
-    let row_alignment_mask = adapter_state.limits.min_buffer_copy_pitch_alignment as u32 - 1;
-
+  ) -> Result<Self> {
+    let device = &device_state_ref.device;
     let upload_size = image.get_upload_size(row_alignment_mask);
 
-    let mut buffer = device.create_buffer(upload_size, buffer_usage).unwrap();
+    let (buffer, memory, resource_id) = Self::create_bound_buffer(device_state_ref, upload_size, buffer_usage, m::Properties::CPU_VISIBLE)?;
 
-    let (memory, memory_size) = Self::allocate_buffer_memory(device, &buffer, &adapter_state.mem_types, m::Properties::CPU_VISIBLE);
-
-    device.bind_buffer_memory(&memory, 0, &mut buffer).unwrap();
+    // Dropped if the buffer can't be written, which destroys the buffer and frees its memory:
+    let buffer_state = BufferState {
+      device_state: Rc::clone(&device_state),
+      memory: Some(memory),
+      buffer: Some(buffer),
+      size: upload_size,
+      resource_id,
+    };
 
     {
-      let mut data_target = device.acquire_mapping_writer::<u8>(&memory, 0..memory_size).unwrap();
+      let allocator = device_state_ref.allocator.borrow();
+      let memory = buffer_state.memory.as_ref().unwrap();
 
-      let (_width, height) = image.get_dimensions();
+      let mut data_target = device
+        .acquire_mapping_writer::<u8>(allocator.memory(memory), memory.range())
+        .graphics_err("map texture buffer memory")?;
 
-      let img = &image.get_image().clone().into_raw();
-      let row_pitch = image.row_pitch(row_alignment_mask);
+      // Parse each row of blocks of every level of the texture into the buffer:
+      for level in 0..image.mip_level_count() {
+        let level_data = &image.get_level(level).data;
+        let row_pitch = image.row_pitch(level, row_alignment_mask);
+        let level_offset = image.level_offset(level, row_alignment_mask) as usize;
 
-      // Parse each row of the texture into the buffer:
-      for y in 0..(height as usize) {
-        let row_range = image.row_range(y);
-        let row = &(img)[row_range.start..row_range.end];
+        for y in 0..(image.row_count(level) as usize) {
+          let row = &level_data[image.row_range(level, y)];
 
-        let dst_base = y * row_pitch as usize;
-        data_target[dst_base..dst_base + row.len()].copy_from_slice(row);
+          let dst_base = level_offset + y * row_pitch as usize;
+          data_target[dst_base..dst_base + row.len()].copy_from_slice(row);
+        }
       }
 
-      device.release_mapping_writer(data_target).unwrap();
+      device
+        .release_mapping_writer(data_target)
+        .graphics_err("unmap texture buffer memory")?;
     }
 
-    BufferState {
-      device_state: Rc::clone(&device_state),
-      memory: Some(memory),
-      buffer: Some(buffer),
-      size: upload_size,
+    Ok(buffer_state)
+  }
+
+  /// Creates a buffer with memory bound to it. The buffer is destroyed again if no memory can be bound,
+  /// and is only tracked once it has been, so nothing is leaked on failure.
+  unsafe fn create_bound_buffer(
+    device_state: &DeviceState<B, C>,
+    size: u64,
+    buffer_usage: buffer::Usage,
+    memory_properties: m::Properties,
+  ) -> Result<(B::Buffer, MemoryAllocation<B>, ResourceId)> {
+    let device = &device_state.device;
+
+    let mut buffer = device.create_buffer(size, buffer_usage).graphics_err("create buffer")?;
+
+    let memory = device_state
+      .allocator
+      .borrow_mut()
+      .bind_buffer(device, &mut buffer, memory_properties);
+
+    match memory {
+      Ok(memory) => Ok((buffer, memory, track_resource!(device_state, Buffer))),
+      Err(e) => {
+        device.destroy_buffer(buffer);
+        Err(e)
+      }
     }
   }
 
+  /// The size of the buffer's contents, in bytes
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
   pub fn get_buffer(&self) -> &B::Buffer {
     self.buffer.as_ref().unwrap()
   }
 
-  pub fn get_buffer_view(&self) -> buffer::IndexBufferView<B> {
+  pub fn get_buffer_view(&self, index_type: IndexType) -> buffer::IndexBufferView<B> {
     buffer::IndexBufferView {
       buffer: self.buffer.as_ref().unwrap(),
       offset: 0,
-      index_type: IndexType::U16,
+      index_type,
     }
   }
 }
+
+impl<B: Backend, C: Capability> Drop for BufferState<B, C> {
+  fn drop(&mut self) {
+    let device_state = self.device_state.as_ref().borrow();
+
+    unsafe {
+      if let Some(buffer) = self.buffer.take() {
+        device_state.device.destroy_buffer(buffer);
+      }
+
+      if let Some(memory) = self.memory.take() {
+        device_state.allocator.borrow_mut().free(&device_state.device, memory);
+      }
+    }
+
+    device_state.resource_tracker.borrow_mut().release(self.resource_id);
+  }
+}
@@ -0,0 +1,190 @@
+use gfx_hal::pso;
+use std::ops::Range;
+
+/// A scalar that can be assigned to a specialization constant
+pub trait SpecConstantValue: Copy {
+    /// The bytes of the value as the shader reads them
+    fn to_bytes(&self) -> [u8; 4];
+}
+
+impl SpecConstantValue for f32 {
+    fn to_bytes(&self) -> [u8; 4] {
+        self.to_bits().to_ne_bytes()
+    }
+}
+
+impl SpecConstantValue for u32 {
+    fn to_bytes(&self) -> [u8; 4] {
+        self.to_ne_bytes()
+    }
+}
+
+impl SpecConstantValue for i32 {
+    fn to_bytes(&self) -> [u8; 4] {
+        self.to_ne_bytes()
+    }
+}
+
+/// Booleans are 32 bits wide in SPIR-V
+impl SpecConstantValue for bool {
+    fn to_bytes(&self) -> [u8; 4] {
+        (*self as u32).to_ne_bytes()
+    }
+}
+
+/// The values given to the `layout(constant_id = N)` constants of a shader stage
+#[derive(Debug, Clone, Default)]
+pub struct SpecConstants {
+    constants: Vec<pso::SpecializationConstant>,
+    data: Vec<u8>,
+}
+
+impl SpecConstants {
+    pub fn new() -> Self {
+        SpecConstants::default()
+    }
+
+    /// Assigns a value to the constant with the given id, replacing any value it already had
+    pub fn set<T: SpecConstantValue>(&mut self, id: u32, value: T) {
+        let bytes = value.to_bytes();
+
+        match self.constants.iter().position(|constant| constant.id == id) {
+            Some(index) => {
+                let range = self.constants[index].range.clone();
+                self.data[range.start as usize..range.end as usize].copy_from_slice(&bytes);
+            }
+            None => {
+                let start = self.data.len() as u16;
+                self.data.extend_from_slice(&bytes);
+                self.constants.push(pso::SpecializationConstant {
+                    id,
+                    range: start..start + bytes.len() as u16,
+                });
+            }
+        }
+    }
+
+    pub fn with<T: SpecConstantValue>(mut self, id: u32, value: T) -> Self {
+        self.set(id, value);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.constants.is_empty()
+    }
+
+    /// Borrows the constants in the form expected by a shader entry point
+    pub fn specialization(&self) -> pso::Specialization {
+        pso::Specialization {
+            constants: &self.constants,
+            data: &self.data,
+        }
+    }
+}
+
+/// A struct written to a shader's `push_constant` block, whose size is a multiple of 4 bytes
+///
+/// # Safety
+///
+/// The block is copied into words byte for byte, so implementors must be `#[repr(C)]`, with fields laid out as the
+/// shader declares them, and must have no padding, whose bytes would be uninitialised. They must also be plain
+/// `Copy` data, holding no references or pointers.
+pub unsafe trait PushConstantBlock: Copy {
+    /// The size of the block in 32-bit words, the unit push constant ranges are measured in
+    fn word_count() -> u32 {
+        let size = std::mem::size_of::<Self>();
+        assert_eq!(0, size % 4, "Push constant blocks must be a multiple of 4 bytes");
+
+        (size / 4) as u32
+    }
+
+    /// Copies the block into the words passed when recording the push
+    fn to_words(&self) -> Vec<u32> {
+        let mut words = vec![0u32; Self::word_count() as usize];
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self as *const Self as *const u8,
+                words.as_mut_ptr() as *mut u8,
+                std::mem::size_of::<Self>(),
+            );
+        }
+
+        words
+    }
+}
+
+/// The per-draw data pushed for every mesh, matching `PushConstants` in the vertex shader
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ModelPushConstants {
+    pub model: [[f32; 4]; 4],
+}
+
+unsafe impl PushConstantBlock for ModelPushConstants {}
+
+/// Finds the range declared for exactly the given stages, measured in 32-bit words
+pub fn find_push_constant_range(
+    push_constants: &[(pso::ShaderStageFlags, Range<u32>)],
+    stages: pso::ShaderStageFlags,
+) -> Option<Range<u32>> {
+    push_constants
+        .iter()
+        .find(|&&(range_stages, _)| range_stages == stages)
+        .map(|&(_, ref range)| range.clone())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy)]
+    #[repr(C)]
+    struct MaterialPushConstants {
+        tint: [f32; 4],
+        texture_index: u32,
+        alpha_cutoff: f32,
+    }
+
+    unsafe impl PushConstantBlock for MaterialPushConstants {}
+
+    #[test]
+    fn should_pack_spec_constants() {
+        let spec_constants = SpecConstants::new().with(0, 0.5f32).with(3, true).with(0, 2.0f32);
+
+        let specialization = spec_constants.specialization();
+
+        assert_eq!(2, specialization.constants.len());
+        assert_eq!(3, specialization.constants[1].id);
+        assert_eq!(4..8, specialization.constants[1].range);
+        assert_eq!(&2.0f32.to_bits().to_ne_bytes(), &specialization.data[0..4]);
+        assert_eq!(&1u32.to_ne_bytes(), &specialization.data[4..8]);
+    }
+
+    #[test]
+    fn should_convert_push_constant_block_to_words() {
+        let block = MaterialPushConstants {
+            tint: [1.0, 0.5, 0.25, 1.0],
+            texture_index: 7,
+            alpha_cutoff: 0.5,
+        };
+
+        let words = block.to_words();
+
+        assert_eq!(6, MaterialPushConstants::word_count());
+        assert_eq!(0.5f32.to_bits(), words[1]);
+        assert_eq!(7, words[4]);
+        assert_eq!(16, ModelPushConstants::word_count());
+    }
+
+    #[test]
+    fn should_find_push_constant_range() {
+        let push_constants = vec![(pso::ShaderStageFlags::VERTEX, 0..16), (pso::ShaderStageFlags::FRAGMENT, 16..22)];
+
+        assert_eq!(
+            Some(16..22),
+            find_push_constant_range(&push_constants, pso::ShaderStageFlags::FRAGMENT)
+        );
+        assert_eq!(None, find_push_constant_range(&push_constants, pso::ShaderStageFlags::GEOMETRY));
+    }
+}
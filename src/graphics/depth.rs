@@ -0,0 +1,106 @@
+use errors::*;
+use gfx_hal::format as f;
+use gfx_hal::image as i;
+use gfx_hal::memory as m;
+use gfx_hal::*;
+use graphics::device::DeviceState;
+use graphics::memory::MemoryAllocation;
+use graphics::tracker::ResourceId;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Depth formats to try, in order of preference
+const DEPTH_FORMATS: [f::Format; 3] = [f::Format::D32Float, f::Format::D32FloatS8Uint, f::Format::D24UnormS8Uint];
+
+/// The depth/stencil image attached to every framebuffer of the swapchain
+pub struct DepthState<B: Backend> {
+    image: Option<B::Image>,
+    image_view: Option<B::ImageView>,
+    memory: Option<MemoryAllocation<B>>,
+    pub format: f::Format,
+    device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+    resource_ids: Vec<ResourceId>,
+}
+
+impl<B: Backend> DepthState<B> {
+    pub unsafe fn new(device_state: Rc<RefCell<DeviceState<B, Graphics>>>, extent: i::Extent) -> Result<Self> {
+        let (image, image_view, memory, format, resource_ids) = {
+            let device_state_ref = device_state.as_ref().borrow();
+            let device = &device_state_ref.device;
+
+            let format = Self::find_depth_format(&device_state_ref.phys_device);
+
+            let mut image = device
+                .create_image(
+                    i::Kind::D2(extent.width, extent.height, 1, 1),
+                    1,
+                    format,
+                    i::Tiling::Optimal,
+                    i::Usage::DEPTH_STENCIL_ATTACHMENT,
+                    i::ViewCapabilities::empty(),
+                )
+                .graphics_err("create depth image")?;
+            let mut resource_ids = vec![track_resource!(device_state_ref, Image)];
+
+            let memory = device_state_ref
+                .allocator
+                .borrow_mut()
+                .bind_image(device, &mut image, m::Properties::DEVICE_LOCAL)?;
+
+            let range = i::SubresourceRange {
+                aspects: format.surface_desc().aspects,
+                levels: 0..1,
+                layers: 0..1,
+            };
+
+            let image_view = device
+                .create_image_view(&image, i::ViewKind::D2, format, f::Swizzle::NO, range)
+                .graphics_err("create depth image view")?;
+            resource_ids.push(track_resource!(device_state_ref, ImageView));
+
+            (image, image_view, memory, format, resource_ids)
+        };
+
+        Ok(DepthState {
+            image: Some(image),
+            image_view: Some(image_view),
+            memory: Some(memory),
+            format,
+            device_state,
+            resource_ids,
+        })
+    }
+
+    /// Returns the first depth format that can be used as an optimally tiled attachment
+    pub fn find_depth_format(phys_device: &B::PhysicalDevice) -> f::Format {
+        DEPTH_FORMATS
+            .iter()
+            .cloned()
+            .find(|format| {
+                phys_device
+                    .format_properties(Some(*format))
+                    .optimal_tiling
+                    .contains(f::ImageFeature::DEPTH_STENCIL_ATTACHMENT)
+            })
+            .unwrap_or(DEPTH_FORMATS[0])
+    }
+
+    pub fn get_image_view(&self) -> &B::ImageView {
+        self.image_view.as_ref().unwrap()
+    }
+}
+
+impl<B: Backend> Drop for DepthState<B> {
+    fn drop(&mut self) {
+        let device_state = self.device_state.as_ref().borrow();
+        let device = &device_state.device;
+
+        unsafe {
+            device.destroy_image_view(self.image_view.take().unwrap());
+            device.destroy_image(self.image.take().unwrap());
+            device_state.allocator.borrow_mut().free(device, self.memory.take().unwrap());
+        }
+
+        device_state.resource_tracker.borrow_mut().release_all(&self.resource_ids);
+    }
+}
@@ -1,17 +1,39 @@
+use errors::*;
 use gfx_hal::*;
 use graphics::device::DeviceState;
+use graphics::tracker::ResourceId;
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::borrow::Borrow;
+
+/// The number of sets the first pool is created with, doubling for each pool after it
+pub const INITIAL_SETS_PER_POOL: usize = 16;
+
+pub const MAX_SETS_PER_POOL: usize = 1024;
+
+/// The descriptors of each type a pool reserves for every set it can hold
+const DEFAULT_DESCRIPTORS_PER_SET: &[(pso::DescriptorType, usize)] = &[
+  (pso::DescriptorType::SampledImage, 2),
+  (pso::DescriptorType::Sampler, 2),
+  (pso::DescriptorType::CombinedImageSampler, 2),
+  (pso::DescriptorType::UniformBuffer, 1),
+  (pso::DescriptorType::UniformBufferDynamic, 1),
+  (pso::DescriptorType::StorageBuffer, 1),
+];
 
 pub struct DescriptorSetLayout<B: Backend, C: Capability> {
   pub device_state: Rc<RefCell<DeviceState<B, C>>>,
   layout: Option<B::DescriptorSetLayout>,
+  bindings: Vec<pso::DescriptorSetLayoutBinding>,
+  resource_id: ResourceId,
 }
 
+/// A set allocated from the device's descriptor allocator, which keeps its layout alive.
+/// The layout may be shared by many sets, such as those of every texture.
 pub struct DescriptorSet<B: Backend, C: Capability> {
-  pub layout: DescriptorSetLayout<B, C>,
+  pub layout: Rc<DescriptorSetLayout<B, C>>,
   pub set: Option<B::DescriptorSet>,
+  pool_index: usize,
+  pool_generation: u64,
 }
 
 pub struct DescriptorSetWrite<W> {
@@ -21,27 +43,66 @@ pub struct DescriptorSetWrite<W> {
 }
 
 impl<B: Backend, C: Capability> DescriptorSetLayout<B, C> {
-  pub unsafe fn new(device_state: Rc<RefCell<DeviceState<B, C>>>, bindings: Vec<pso::DescriptorSetLayoutBinding>) -> Self {
-    let layout = device_state.as_ref().borrow().device.create_descriptor_set_layout(&bindings, &[]).ok();
+  pub unsafe fn new(device_state: Rc<RefCell<DeviceState<B, C>>>, bindings: Vec<pso::DescriptorSetLayoutBinding>) -> Result<Self> {
+    let (layout, resource_id) = {
+      let device_state = device_state.as_ref().borrow();
 
-    DescriptorSetLayout { device_state, layout }
+      let layout = device_state
+        .device
+        .create_descriptor_set_layout(&bindings, &[])
+        .graphics_err("create descriptor set layout")?;
+
+      (layout, track_resource!(device_state, DescriptorSetLayout))
+    };
+
+    Ok(DescriptorSetLayout {
+      device_state,
+      layout: Some(layout),
+      bindings,
+      resource_id,
+    })
   }
 
-  pub unsafe fn create_set(self, pool: &mut B::DescriptorPool) -> DescriptorSet<B, C> {
-    let set = pool.allocate_set(self.layout.as_ref().unwrap()).unwrap();
+  /// Allocates a set with this layout from the device's descriptor allocator, for a layout used by a single set
+  pub unsafe fn create_set(self) -> Result<DescriptorSet<B, C>> {
+    Self::create_shared_set(Rc::new(self))
+  }
 
-    DescriptorSet {
+  /// Allocates a set with a layout shared with other sets, which is destroyed once none of them are left
+  pub unsafe fn create_shared_set(layout: Rc<Self>) -> Result<DescriptorSet<B, C>> {
+    let (set, pool_index, pool_generation) = {
+      let device_state = layout.device_state.as_ref().borrow();
+      let mut descriptor_allocator = device_state.descriptor_allocator.borrow_mut();
+
+      descriptor_allocator.allocate_set(&device_state, layout.get_layout(), &layout.bindings)?
+    };
+
+    Ok(DescriptorSet {
       set: Some(set),
-      layout: self,
-    }
+      layout,
+      pool_index,
+      pool_generation,
+    })
+  }
+
+  pub fn bindings(&self) -> &[pso::DescriptorSetLayoutBinding] {
+    &self.bindings
+  }
+
+  pub fn get_layout(&self) -> &B::DescriptorSetLayout {
+    self.layout.as_ref().unwrap()
   }
 }
 
 impl<B: Backend, C: Capability> Drop for DescriptorSetLayout<B, C> {
   fn drop(&mut self) {
-    // unsafe {
-    //   device.destroy_descriptor_set_layout(self.layout.take().unwrap());
-    // }
+    let device_state = self.device_state.as_ref().borrow();
+
+    unsafe {
+      device_state.device.destroy_descriptor_set_layout(self.layout.take().unwrap());
+    }
+
+    device_state.resource_tracker.borrow_mut().release(self.resource_id);
   }
 }
 
@@ -66,6 +127,244 @@ impl<B: Backend, C: Capability> DescriptorSet<B, C> {
   }
 
   pub fn get_layout(&self) -> &B::DescriptorSetLayout {
-    self.layout.layout.borrow().as_ref().unwrap()
+    self.layout.get_layout()
+  }
+}
+
+impl<B: Backend, C: Capability> Drop for DescriptorSet<B, C> {
+  fn drop(&mut self) {
+    if let Some(set) = self.set.take() {
+      let device_state = self.layout.device_state.as_ref().borrow();
+
+      unsafe {
+        device_state
+          .descriptor_allocator
+          .borrow_mut()
+          .free_set(self.pool_index, self.pool_generation, set);
+      }
+    }
+  }
+}
+
+struct DescriptorPool<B: Backend> {
+  raw: B::DescriptorPool,
+  resource_id: ResourceId,
+  max_sets: usize,
+  allocated_sets: usize,
+  // Replaced when the pool is reset, so sets allocated before then aren't freed twice:
+  generation: u64,
+}
+
+/// Hands out descriptor sets from a list of pools, creating a larger pool whenever the existing ones are exhausted
+pub struct DescriptorAllocator<B: Backend> {
+  pools: Vec<DescriptorPool<B>>,
+  descriptors_per_set: Vec<(pso::DescriptorType, usize)>,
+  next_pool_sets: usize,
+  next_generation: u64,
+}
+
+impl<B: Backend> DescriptorAllocator<B> {
+  pub fn new() -> Self {
+    DescriptorAllocator {
+      pools: Vec::new(),
+      descriptors_per_set: DEFAULT_DESCRIPTORS_PER_SET.to_vec(),
+      next_pool_sets: INITIAL_SETS_PER_POOL,
+      next_generation: 0,
+    }
+  }
+
+  /// Sets the descriptors of each type reserved per set when new pools are created
+  pub fn with_descriptors_per_set(mut self, descriptors_per_set: Vec<(pso::DescriptorType, usize)>) -> Self {
+    self.descriptors_per_set = descriptors_per_set;
+    self
+  }
+
+  /// Allocates a set from the newest pool with room for it, returning the set with the index and generation of its pool
+  pub unsafe fn allocate_set<C: Capability>(
+    &mut self,
+    device_state: &DeviceState<B, C>,
+    layout: &B::DescriptorSetLayout,
+    bindings: &[pso::DescriptorSetLayoutBinding],
+  ) -> Result<(B::DescriptorSet, usize, u64)> {
+    for (pool_index, pool) in self.pools.iter_mut().enumerate().rev() {
+      if pool.allocated_sets == pool.max_sets {
+        continue;
+      }
+
+      match pool.raw.allocate_set(layout) {
+        Ok(set) => {
+          pool.allocated_sets += 1;
+          return Ok((set, pool_index, pool.generation));
+        }
+        Err(pso::AllocationError::OutOfPoolMemory) | Err(pso::AllocationError::FragmentedPool) => continue,
+        Err(e) => return Err(e).graphics_err("allocate descriptor set"),
+      }
+    }
+
+    // Every pool is exhausted, so the set is allocated from a new one:
+    let max_sets = self.next_pool_sets;
+    let ranges = pool_ranges(&self.descriptors_per_set, bindings, max_sets);
+
+    let mut raw = device_state
+      .device
+      .create_descriptor_pool(max_sets, &ranges)
+      .graphics_err("create descriptor pool")?;
+
+    let set = match raw.allocate_set(layout) {
+      Ok(set) => set,
+      Err(e) => {
+        device_state.device.destroy_descriptor_pool(raw);
+        return Err(e).graphics_err("allocate descriptor set");
+      }
+    };
+
+    info!("Created a descriptor pool for {} sets", max_sets);
+
+    self.pools.push(DescriptorPool {
+      raw,
+      resource_id: track_resource!(device_state, DescriptorPool),
+      max_sets,
+      allocated_sets: 1,
+      generation: self.next_generation,
+    });
+    self.next_pool_sets = next_pool_size(max_sets);
+    self.next_generation += 1;
+
+    Ok((set, self.pools.len() - 1, self.next_generation - 1))
+  }
+
+  /// Returns a set to its pool, unless the pool has been reset since the set was allocated
+  pub unsafe fn free_set(&mut self, pool_index: usize, pool_generation: u64, set: B::DescriptorSet) {
+    // Pools that were trimmed or reset have already released the set:
+    if let Some(pool) = self.pools.get_mut(pool_index).filter(|pool| pool.generation == pool_generation) {
+      pool.raw.free_sets(Some(set));
+      pool.allocated_sets -= 1;
+    }
+  }
+
+  /// Frees every set allocated so far. None of them may be used or bound afterwards.
+  pub unsafe fn reset(&mut self) {
+    for pool in &mut self.pools {
+      pool.raw.reset();
+      pool.allocated_sets = 0;
+      pool.generation = self.next_generation;
+      self.next_generation += 1;
+    }
+  }
+
+  pub fn pool_count(&self) -> usize {
+    self.pools.len()
+  }
+
+  pub fn allocated_sets(&self) -> usize {
+    self.pools.iter().map(|pool| pool.allocated_sets).sum()
+  }
+
+  /// Destroys the pools at the end of the list that no longer hold any sets.
+  /// Pools in use are kept, as the sets allocated from them refer to them by index.
+  pub unsafe fn trim<C: Capability>(&mut self, device_state: &DeviceState<B, C>) {
+    while self.pools.last().map_or(false, |pool| pool.allocated_sets == 0) {
+      let pool = self.pools.pop().unwrap();
+
+      device_state.device.destroy_descriptor_pool(pool.raw);
+      device_state.resource_tracker.borrow_mut().release(pool.resource_id);
+    }
+  }
+
+  /// Destroys every pool, which must be done before the device is destroyed
+  pub unsafe fn dispose<C: Capability>(&mut self, device_state: &DeviceState<B, C>) {
+    for pool in self.pools.drain(..) {
+      device_state.device.destroy_descriptor_pool(pool.raw);
+      device_state.resource_tracker.borrow_mut().release(pool.resource_id);
+    }
+  }
+}
+
+/// The size of the pool created after one holding `max_sets` sets
+fn next_pool_size(max_sets: usize) -> usize {
+  (max_sets * 2).min(MAX_SETS_PER_POOL)
+}
+
+/// The descriptors a pool of `max_sets` sets reserves, with enough of every type for
+/// the layout that triggered its creation, even if the defaults don't include them
+fn pool_ranges(
+  descriptors_per_set: &[(pso::DescriptorType, usize)],
+  bindings: &[pso::DescriptorSetLayoutBinding],
+  max_sets: usize,
+) -> Vec<pso::DescriptorRangeDesc> {
+  let mut ranges: Vec<pso::DescriptorRangeDesc> = descriptors_per_set
+    .iter()
+    .map(|&(ty, count)| pso::DescriptorRangeDesc {
+      ty,
+      count: count * max_sets,
+    })
+    .collect();
+
+  for binding in bindings {
+    let required = binding.count * max_sets;
+
+    match ranges.iter_mut().find(|range| range.ty == binding.ty) {
+      Some(range) => range.count = range.count.max(required),
+      None => ranges.push(pso::DescriptorRangeDesc {
+        ty: binding.ty,
+        count: required,
+      }),
+    }
+  }
+
+  ranges
+}
+
+#[cfg(test)]
+pub mod test {
+  use super::*;
+
+  #[test]
+  fn should_grow_pools_up_to_maximum() {
+    let mut max_sets = INITIAL_SETS_PER_POOL;
+    let mut sizes = vec![max_sets];
+
+    while max_sets < MAX_SETS_PER_POOL {
+      max_sets = next_pool_size(max_sets);
+      sizes.push(max_sets);
+    }
+
+    assert_eq!(vec![16, 32, 64, 128, 256, 512, 1024], sizes);
+    assert_eq!(MAX_SETS_PER_POOL, next_pool_size(MAX_SETS_PER_POOL));
+  }
+
+  #[test]
+  fn should_reserve_descriptors_for_layout() {
+    let bindings = vec![
+      pso::DescriptorSetLayoutBinding {
+        binding: 0,
+        ty: pso::DescriptorType::SampledImage,
+        stage_flags: pso::ShaderStageFlags::FRAGMENT,
+        count: 4,
+        immutable_samplers: false,
+      },
+      pso::DescriptorSetLayoutBinding {
+        binding: 1,
+        ty: pso::DescriptorType::StorageImage,
+        stage_flags: pso::ShaderStageFlags::FRAGMENT,
+        count: 1,
+        immutable_samplers: false,
+      },
+    ];
+
+    let descriptors_per_set = [(pso::DescriptorType::SampledImage, 2), (pso::DescriptorType::Sampler, 2)];
+
+    let ranges = pool_ranges(&descriptors_per_set, &bindings, 16);
+
+    let counts: Vec<(pso::DescriptorType, usize)> = ranges.iter().map(|range| (range.ty, range.count)).collect();
+
+    assert_eq!(
+      vec![
+        (pso::DescriptorType::SampledImage, 64),
+        (pso::DescriptorType::Sampler, 32),
+        (pso::DescriptorType::StorageImage, 16),
+      ],
+      counts
+    );
   }
 }
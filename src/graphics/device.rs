@@ -1,26 +1,68 @@
+use errors::*;
 use gfx_hal::*;
+use graphics::descriptor::DescriptorAllocator;
+use graphics::memory::MemoryAllocator;
+use graphics::sampler::SamplerCache;
+use graphics::tracker::ResourceTracker;
+use std::cell::RefCell;
 
 pub struct DeviceState<B: Backend, C: Capability> {
   pub device: B::Device,
   pub phys_device: B::PhysicalDevice,
   pub queue_group: QueueGroup<B, C>,
+  pub allocator: RefCell<MemoryAllocator<B>>,
+  pub descriptor_allocator: RefCell<DescriptorAllocator<B>>,
+  pub resource_tracker: RefCell<ResourceTracker>,
+  pub sampler_cache: RefCell<SamplerCache<B>>,
 }
 
 impl<B: Backend, C: Capability> DeviceState<B, C> {
-  pub fn new(adapter: Adapter<B>, surface: &B::Surface) -> Self {
-    let (device, queue_group) = adapter
-      .open_with::<_, C>(1, |family| surface.supports_queue_family(family))
-      .unwrap();
+  pub fn new(adapter: Adapter<B>, surface: &B::Surface) -> Result<Self> {
+    let limits = adapter.physical_device.limits();
+    let allocator = MemoryAllocator::new(adapter.physical_device.memory_properties(), &limits);
 
-    DeviceState {
+    // Anisotropic filtering is only enabled where the device supports it, samplers fall back to trilinear otherwise:
+    let features = adapter.physical_device.features() & Features::SAMPLER_ANISOTROPY;
+    let max_anisotropy = if features.contains(Features::SAMPLER_ANISOTROPY) {
+      limits.max_sampler_anisotropy.min(u8::max_value() as f32) as u8
+    } else {
+      0
+    };
+
+    let (device, queue_group) = Self::open_device(&adapter, features, |family| surface.supports_queue_family(family))?;
+
+    Ok(DeviceState {
       device,
       queue_group,
       phys_device: adapter.physical_device,
-    }
+      allocator: RefCell::new(allocator),
+      descriptor_allocator: RefCell::new(DescriptorAllocator::new()),
+      resource_tracker: RefCell::new(ResourceTracker::default()),
+      sampler_cache: RefCell::new(SamplerCache::new(max_anisotropy)),
+    })
+  }
+
+  /// Opens the device with a single queue from the first family with capability `C` that passes the selector.
+  /// This is `Adapter::open_with`, but with optional features enabled.
+  fn open_device<F>(adapter: &Adapter<B>, features: Features, selector: F) -> Result<(B::Device, QueueGroup<B, C>)>
+  where
+    F: Fn(&B::QueueFamily) -> bool,
+  {
+    let family = adapter
+      .queue_families
+      .iter()
+      .find(|family| C::supported_by(family.queue_type()) && selector(family))
+      .ok_or(ErrorKind::NoSuitableAdapter)?;
+
+    let Gpu { device, mut queues } = unsafe { adapter.physical_device.open(&[(family, &[1.0][..])], features) }.graphics_err("open device")?;
+
+    let queue_group = queues.take::<C>(family.id()).ok_or(ErrorKind::NoSuitableAdapter)?;
+
+    Ok((device, queue_group))
   }
 
   /// Create a command pool from the provided queue type capability
-  pub unsafe fn create_command_pool(&self) -> CommandPool<B, C>
+  pub unsafe fn create_command_pool(&self) -> Result<CommandPool<B, C>>
   where
     B: Backend,
     C: Capability,
@@ -28,6 +70,16 @@ impl<B: Backend, C: Capability> DeviceState<B, C> {
     self
       .device
       .create_command_pool_typed(&self.queue_group, pool::CommandPoolCreateFlags::empty())
-      .unwrap()
+      .graphics_err("create command pool")
+  }
+}
+
+impl<B: Backend, C: Capability> Drop for DeviceState<B, C> {
+  fn drop(&mut self) {
+    unsafe {
+      self.descriptor_allocator.borrow_mut().dispose(self);
+      self.sampler_cache.borrow_mut().dispose(self);
+      self.allocator.borrow_mut().dispose(&self.device);
+    }
   }
 }
@@ -1,10 +1,13 @@
+use errors::*;
 use gfx_hal::format as f;
 use gfx_hal::image as i;
 use gfx_hal::*;
+use graphics::depth::DepthState;
 use graphics::device::DeviceState;
 use graphics::image::COLOR_RANGE;
 use graphics::renderer::RenderPassState;
 use graphics::swapchain::SwapchainState;
+use graphics::tracker::ResourceId;
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -17,6 +20,7 @@ pub struct FramebufferState<B: Backend> {
     present_semaphores: Option<Vec<B::Semaphore>>,
     last_semaphore_index: usize,
     device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+    resource_ids: Vec<ResourceId>,
 }
 
 impl<B: Backend> FramebufferState<B> {
@@ -24,7 +28,10 @@ impl<B: Backend> FramebufferState<B> {
         device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
         render_pass_state: &RenderPassState<B>,
         swapchain_state: &mut SwapchainState<B>,
-    ) -> Self {
+        depth_state: &DepthState<B>,
+    ) -> Result<Self> {
+        let mut resource_ids = Vec::new();
+
         let (frame_images, framebuffers) = match swapchain_state.backbuffer.take().unwrap() {
             Backbuffer::Images(images) => {
                 let extent = i::Extent {
@@ -36,28 +43,36 @@ impl<B: Backend> FramebufferState<B> {
                 let pairs = images
                     .into_iter()
                     .map(|image| {
+                        let device_state = device_state.as_ref().borrow();
+
                         let rtv = device_state
-                            .as_ref()
-                            .borrow()
                             .device
                             .create_image_view(&image, i::ViewKind::D2, swapchain_state.format, f::Swizzle::NO, COLOR_RANGE.clone())
-                            .unwrap();
+                            .graphics_err("create swapchain image view")?;
+                        resource_ids.push(track_resource!(device_state, ImageView));
 
-                        (image, rtv)
+                        Ok((image, rtv))
                     })
-                    .collect::<Vec<_>>();
+                    .collect::<Result<Vec<_>>>()?;
 
                 let fbos = pairs
                     .iter()
                     .map(|&(_, ref rtv)| {
-                        device_state
-                            .as_ref()
-                            .borrow()
+                        let device_state = device_state.as_ref().borrow();
+
+                        let framebuffer = device_state
                             .device
-                            .create_framebuffer(render_pass_state.render_pass.as_ref().unwrap(), Some(rtv), extent)
-                            .unwrap()
+                            .create_framebuffer(
+                                render_pass_state.render_pass.as_ref().unwrap(),
+                                vec![rtv, depth_state.get_image_view()],
+                                extent,
+                            )
+                            .graphics_err("create framebuffer")?;
+                        resource_ids.push(track_resource!(device_state, Framebuffer));
+
+                        Ok(framebuffer)
                     })
-                    .collect();
+                    .collect::<Result<Vec<_>>>()?;
 
                 // (vec![], vec![])
                 (pairs, fbos)
@@ -77,22 +92,26 @@ impl<B: Backend> FramebufferState<B> {
         let mut present_semaphores: Vec<B::Semaphore> = vec![];
 
         for _ in 0..iter_count {
-            fences.push(device_state.as_ref().borrow().device.create_fence(true).unwrap());
+            let device_state = device_state.as_ref().borrow();
+
+            fences.push(device_state.device.create_fence(true).graphics_err("create fence")?);
+            resource_ids.push(track_resource!(device_state, Fence));
 
             command_pools.push(
                 device_state
-                    .as_ref()
-                    .borrow()
                     .device
-                    .create_command_pool_typed(&device_state.as_ref().borrow().queue_group, pool::CommandPoolCreateFlags::empty())
-                    .unwrap(),
+                    .create_command_pool_typed(&device_state.queue_group, pool::CommandPoolCreateFlags::empty())
+                    .graphics_err("create command pool")?,
             );
+            resource_ids.push(track_resource!(device_state, CommandPool));
 
-            acquire_semaphores.push(device_state.as_ref().borrow().device.create_semaphore().unwrap());
-            present_semaphores.push(device_state.as_ref().borrow().device.create_semaphore().unwrap());
+            acquire_semaphores.push(device_state.device.create_semaphore().graphics_err("create semaphore")?);
+            present_semaphores.push(device_state.device.create_semaphore().graphics_err("create semaphore")?);
+            resource_ids.push(track_resource!(device_state, Semaphore));
+            resource_ids.push(track_resource!(device_state, Semaphore));
         }
 
-        FramebufferState {
+        Ok(FramebufferState {
             command_pools: Some(command_pools),
             acquire_semaphores: Some(acquire_semaphores),
             present_semaphores: Some(present_semaphores),
@@ -101,7 +120,13 @@ impl<B: Backend> FramebufferState<B> {
             framebuffer_fences: Some(fences),
             device_state: Rc::clone(&device_state),
             last_semaphore_index: 0,
-        }
+            resource_ids,
+        })
+    }
+
+    /// The number of framebuffers, and so the number of frames that can be in flight at once
+    pub fn frame_count(&self) -> usize {
+        self.framebuffers.as_ref().unwrap().len()
     }
 
     /// Returns the next available framebuffer semaphore index
@@ -149,7 +174,8 @@ impl<B: Backend> FramebufferState<B> {
 
 impl<B: Backend> Drop for FramebufferState<B> {
     fn drop(&mut self) {
-        let device = &self.device_state.as_ref().borrow().device;
+        let device_state = self.device_state.as_ref().borrow();
+        let device = &device_state.device;
 
         unsafe {
             for fence in self.framebuffer_fences.take().unwrap() {
@@ -177,5 +203,7 @@ impl<B: Backend> Drop for FramebufferState<B> {
                 device.destroy_image_view(rtv);
             }
         }
+
+        device_state.resource_tracker.borrow_mut().release_all(&self.resource_ids);
     }
 }
@@ -1,317 +1,640 @@
+use errors::*;
 use gfx_hal::format as f;
-use gfx_hal::format::AsFormat;
 use gfx_hal::image as i;
 use gfx_hal::memory as m;
-use gfx_hal::pso::PipelineStage;
 use gfx_hal::*;
-use graphics::adapter::AdapterState;
-use graphics::backend::ColorFormat;
-use graphics::buffer::BufferState;
-use graphics::descriptor::{DescriptorSet, DescriptorSetLayout, DescriptorSetWrite};
 use graphics::device::DeviceState;
+use graphics::memory::MemoryAllocation;
+use graphics::texel::{self, BlockLayout};
+use graphics::texture_file;
+use graphics::tracker::ResourceId;
 use image;
 use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::BufReader;
 use std::ops::Range;
+use std::path::Path;
 use std::rc::Rc;
 
-/// The number of bytes per value of an RGBA image
-pub const RGBA_IMAGE_STRIDE: usize = 4;
-
 pub const COLOR_RANGE: i::SubresourceRange = i::SubresourceRange {
     aspects: f::Aspects::COLOR,
     levels: 0..1,
     layers: 0..1,
 };
 
-pub struct ImageState<B: Backend> {
-    pub descriptor_set: DescriptorSet<B, Graphics>,
-    sampler: Option<B::Sampler>,
-    buffer: Option<B::Buffer>,
+/// A sampled image in device local memory, along with the view it is read through.
+/// Its contents are copied in by `UploadBatch::upload_image`, or drawn by a `RenderTarget`.
+pub struct TextureState<B: Backend> {
+    device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+    format: f::Format,
+    dimensions: (u32, u32),
+    mip_levels: i::Level,
     image_view: Option<B::ImageView>,
     image: Option<B::Image>,
-    memory: Option<B::Memory>,
-    image_fence: Option<B::Fence>,
+    memory: Option<MemoryAllocation<B>>,
+    resource_ids: Vec<ResourceId>,
 }
 
-impl<B: Backend> ImageState<B> {
+impl<B: Backend> TextureState<B> {
+    /// Creates an image with the format, size and number of levels of the texture, without filling it
     pub unsafe fn new(
-        mut descriptor_set: DescriptorSet<B, Graphics>,
-        device_state: &mut DeviceState<B, Graphics>,
-        adapter_state: &AdapterState<B>,
-        texture: Image,
-        usage: buffer::Usage,
-        staging_pool: &mut CommandPool<B, Graphics>,
-    ) -> Self {
-        const MIP_LEVELS: u8 = 1;
-
-        let mut image_buffer_state = BufferState::new_texture(
-            Rc::clone(&descriptor_set.layout.device_state),
-            &device_state.device,
-            adapter_state,
-            &texture,
-            usage,
-        );
+        device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+        device_state_ref: &DeviceState<B, Graphics>,
+        texture: &Image,
+    ) -> Result<Self> {
+        Self::create(
+            device_state,
+            device_state_ref,
+            texture.get_format(),
+            texture.get_dimensions(),
+            texture.mip_level_count(),
+            i::Usage::TRANSFER_DST | i::Usage::SAMPLED,
+        )
+    }
+
+    /// Creates a single level image that is drawn to as a colour attachment, then sampled like any other texture
+    pub unsafe fn new_render_target(
+        device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+        device_state_ref: &DeviceState<B, Graphics>,
+        format: f::Format,
+        dimensions: (u32, u32),
+    ) -> Result<Self> {
+        Self::create(
+            device_state,
+            device_state_ref,
+            format,
+            dimensions,
+            1,
+            i::Usage::COLOR_ATTACHMENT | i::Usage::SAMPLED,
+        )
+    }
+
+    unsafe fn create(
+        device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+        device_state_ref: &DeviceState<B, Graphics>,
+        format: f::Format,
+        dimensions: (u32, u32),
+        mip_levels: i::Level,
+        usage: i::Usage,
+    ) -> Result<Self> {
+        // Block compressed formats in particular are only supported by some devices:
+        let required_features = if usage.contains(i::Usage::COLOR_ATTACHMENT) {
+            f::ImageFeature::SAMPLED | f::ImageFeature::COLOR_ATTACHMENT
+        } else {
+            f::ImageFeature::SAMPLED
+        };
+
+        let format_properties = device_state_ref.phys_device.format_properties(Some(format));
+
+        if !format_properties.optimal_tiling.contains(required_features) {
+            bail!(ErrorKind::UnsupportedTextureFormat(format!("{:?}", format)));
+        }
 
-        let image_buffer = image_buffer_state.buffer.take().unwrap();
+        let device = &device_state_ref.device;
+        let (width, height) = dimensions;
 
-        let mut image = device_state
-            .device
+        let mut image = device
             .create_image(
-                texture.get_kind().clone(),
-                MIP_LEVELS,
-                ColorFormat::SELF,
+                i::Kind::D2(width, height, 1, 1),
+                mip_levels,
+                format,
                 i::Tiling::Optimal,
-                i::Usage::TRANSFER_DST | i::Usage::SAMPLED,
+                usage,
                 i::ViewCapabilities::empty(),
             )
-            .unwrap();
+            .graphics_err("create texture image")?;
+
+        let memory = device_state_ref
+            .allocator
+            .borrow_mut()
+            .bind_image(device, &mut image, m::Properties::DEVICE_LOCAL);
+
+        let memory = match memory {
+            Ok(memory) => memory,
+            Err(e) => {
+                device.destroy_image(image);
+                return Err(e);
+            }
+        };
+
+        // Dropped if the view can't be created, which destroys the image and frees its memory:
+        let mut texture_state = TextureState {
+            device_state,
+            format,
+            dimensions,
+            mip_levels,
+            image_view: None,
+            image: Some(image),
+            memory: Some(memory),
+            resource_ids: vec![track_resource!(device_state_ref, Image)],
+        };
 
-        let device = &mut device_state.device;
-        let requirements = device.get_image_requirements(&image);
+        let range = i::SubresourceRange {
+            aspects: f::Aspects::COLOR,
+            levels: 0..mip_levels,
+            layers: 0..1,
+        };
 
-        let device_memory_type =
-            BufferState::<B, Graphics>::find_buffer_memory(&adapter_state.mem_types, &requirements, m::Properties::DEVICE_LOCAL);
+        let image_view = device
+            .create_image_view(texture_state.get_image(), i::ViewKind::D2, format, f::Swizzle::NO, range)
+            .graphics_err("create texture image view")?;
+        texture_state.image_view = Some(image_view);
+        texture_state.resource_ids.push(track_resource!(device_state_ref, ImageView));
 
-        let device_image_memory = device.allocate_memory(device_memory_type, requirements.size).unwrap();
+        Ok(texture_state)
+    }
 
-        device.bind_image_memory(&device_image_memory, 0, &mut image).unwrap();
+    pub fn get_format(&self) -> f::Format {
+        self.format
+    }
 
-        let image_view = device
-            .create_image_view(&image, i::ViewKind::D2, ColorFormat::SELF, f::Swizzle::NO, COLOR_RANGE)
-            .unwrap();
-
-        let sampler = device
-            .create_sampler(i::SamplerInfo::new(i::Filter::Linear, i::WrapMode::Clamp))
-            .unwrap();
-
-        descriptor_set.write_to_state(
-            device,
-            vec![
-                DescriptorSetWrite {
-                    binding: 0,
-                    array_offset: 0,
-                    descriptors: Some(pso::Descriptor::Image(&image_view, i::Layout::Undefined)),
-                },
-                DescriptorSetWrite {
-                    binding: 1,
-                    array_offset: 0,
-                    descriptors: Some(pso::Descriptor::Sampler(&sampler)),
-                },
-            ],
-        );
+    pub fn get_dimensions(&self) -> (u32, u32) {
+        self.dimensions
+    }
 
-        let transferred_image_fence = device.create_fence(false).unwrap();
+    pub fn mip_level_count(&self) -> i::Level {
+        self.mip_levels
+    }
 
-        {
-            let row_alignment_mask = adapter_state.limits.min_buffer_copy_pitch_alignment as u32 - 1;
-            let row_pitch = texture.row_pitch(row_alignment_mask);
-            let (image_width, image_height) = texture.get_dimensions();
+    pub fn get_level_dimensions(&self, level: i::Level) -> (u32, u32) {
+        let (width, height) = self.dimensions;
+        ((width >> level).max(1), (height >> level).max(1))
+    }
 
-            // Copy the buffer to texture
-            let mut cmd_buffer = staging_pool.acquire_command_buffer::<command::OneShot>();
-            cmd_buffer.begin();
+    pub fn get_image(&self) -> &B::Image {
+        self.image.as_ref().unwrap()
+    }
 
-            let image_barrier = m::Barrier::Image {
-                states: (i::Access::empty(), i::Layout::Undefined)..(i::Access::TRANSFER_WRITE, i::Layout::ShaderReadOnlyOptimal),
-                target: &image,
-                families: None,
-                range: COLOR_RANGE.clone(),
-            };
+    pub fn get_image_view(&self) -> &B::ImageView {
+        self.image_view.as_ref().unwrap()
+    }
+}
 
-            cmd_buffer.pipeline_barrier(
-                PipelineStage::TRANSFER..PipelineStage::FRAGMENT_SHADER,
-                m::Dependencies::empty(),
-                &[image_barrier],
-            );
-
-            cmd_buffer.copy_buffer_to_image(
-                &image_buffer,
-                &image,
-                i::Layout::TransferDstOptimal,
-                &[command::BufferImageCopy {
-                    buffer_offset: 0,
-                    buffer_width: row_pitch / RGBA_IMAGE_STRIDE as u32,
-                    buffer_height: image_width,
-                    image_layers: i::SubresourceLayers {
-                        aspects: f::Aspects::COLOR,
-                        level: 0,
-                        layers: 0..1,
-                    },
-                    image_offset: i::Offset { x: 0, y: 0, z: 0 },
-                    image_extent: i::Extent {
-                        width: image_width,
-                        height: image_height,
-                        depth: 1,
-                    },
-                }],
-            );
-
-            let image_barrier = m::Barrier::Image {
-                states: (i::Access::TRANSFER_WRITE, i::Layout::TransferDstOptimal)
-                    ..(i::Access::SHADER_READ, i::Layout::ShaderReadOnlyOptimal),
-                target: &image,
-                families: None,
-                range: COLOR_RANGE.clone(),
-            };
+impl<B: Backend> Drop for TextureState<B> {
+    fn drop(&mut self) {
+        let device_state = self.device_state.as_ref().borrow();
+        let device = &device_state.device;
 
-            cmd_buffer.pipeline_barrier(
-                PipelineStage::TRANSFER..PipelineStage::FRAGMENT_SHADER,
-                m::Dependencies::empty(),
-                &[image_barrier],
-            );
+        unsafe {
+            if let Some(image_view) = self.image_view.take() {
+                device.destroy_image_view(image_view);
+            }
 
-            cmd_buffer.finish();
+            device.destroy_image(self.image.take().unwrap());
 
-            device_state.queue_group.queues[0].submit_nosemaphores(std::iter::once(&cmd_buffer), Some(&transferred_image_fence));
+            device_state.allocator.borrow_mut().free(device, self.memory.take().unwrap());
         }
 
-        ImageState {
-            descriptor_set: descriptor_set,
-            buffer: Some(image_buffer),
-            image: Some(image),
-            image_view: Some(image_view),
-            sampler: Some(sampler),
-            memory: Some(device_image_memory),
-            image_fence: Some(transferred_image_fence),
-        }
+        device_state.resource_tracker.borrow_mut().release_all(&self.resource_ids);
     }
+}
 
-    pub unsafe fn load_image_to_state(
-        device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
-        adapter_state: &AdapterState<B>,
-        image_file_path: &str,
-        descriptor_set: DescriptorSetLayout<B, Graphics>,
-    ) -> Self {
-        let device_state_ref = device_state.as_ref().borrow();
-        let device = &device_state_ref.device;
+/// Whether the colour channels of an image are sRGB encoded. Colour textures are usually sRGB,
+/// while normal maps and other data must be linear to be sampled without being altered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+}
 
-        let mut desc_pool = device
-            .create_descriptor_pool(
-                1,
-                &[
-                    pso::DescriptorRangeDesc {
-                        ty: pso::DescriptorType::SampledImage,
-                        count: 1,
-                    },
-                    pso::DescriptorRangeDesc {
-                        ty: pso::DescriptorType::Sampler,
-                        count: 1,
-                    },
-                ],
-            )
-            .ok();
+/// The format HDR images are loaded as, which takes half the memory of `Rgba32Float`
+pub const HDR_FORMAT: f::Format = f::Format::Rgba16Float;
 
-        let descriptor_set = descriptor_set.create_set(desc_pool.as_mut().unwrap());
+/// A single level of an image, holding its rows of texel blocks without any padding
+pub struct ImageLevel {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
 
-        let image_data = Loader::from_file(&image_file_path).expect("Failed to load image");
+pub struct Image {
+    format: f::Format,
+    kind: i::Kind,
+    /// The full size image followed by each level of its mip chain
+    levels: Vec<ImageLevel>,
+}
 
-        let mut staging_pool = device
-            .create_command_pool_typed(&device_state_ref.queue_group, pool::CommandPoolCreateFlags::empty())
-            .unwrap();
+impl Image {
+    /// Creates an 8 bit per channel image without a mip chain
+    pub fn from_rgba(image: image::RgbaImage, color_space: ColorSpace) -> Self {
+        let format = match color_space {
+            ColorSpace::Srgb => f::Format::Rgba8Srgb,
+            ColorSpace::Linear => f::Format::Rgba8Unorm,
+        };
+        let (width, height) = image.dimensions();
+
+        Image {
+            format,
+            kind: i::Kind::D2(width, height, 1, 1),
+            levels: vec![ImageLevel {
+                width,
+                height,
+                data: image.into_raw(),
+            }],
+        }
+    }
 
-        let image_state = ImageState::new(
-            descriptor_set,
-            &mut device_state.borrow_mut(),
-            adapter_state,
-            image_data,
-            buffer::Usage::TRANSFER_SRC,
-            &mut staging_pool,
-        );
+    /// Creates an image of an uncompressed format from linear RGBA values, without a mip chain
+    pub fn from_texels(format: f::Format, width: u32, height: u32, texels: &[[f32; 4]]) -> Result<Self> {
+        let data = texel::encode_rgba(format, texels).ok_or_else(|| ErrorKind::UnsupportedTextureFormat(format!("{:?}", format)))?;
+
+        Image::from_levels(format, vec![ImageLevel { width, height, data }])
+    }
+
+    /// Creates an image from a complete set of levels, such as the precomputed mip chain of a compressed texture
+    pub fn from_levels(format: f::Format, levels: Vec<ImageLevel>) -> Result<Self> {
+        let block_layout = texel::block_layout(format).ok_or_else(|| ErrorKind::UnsupportedTextureFormat(format!("{:?}", format)))?;
+
+        let (width, height) = match levels.first() {
+            Some(level) => (level.width, level.height),
+            None => bail!(ErrorKind::InvalidTextureData("the image has no levels".to_string())),
+        };
+
+        if width == 0 || height == 0 || levels.len() > max_level_count(width, height) as usize {
+            bail!(ErrorKind::InvalidTextureData(format!(
+                "{} levels were given for the {}x{} image",
+                levels.len(),
+                width,
+                height
+            )));
+        }
+
+        for (index, level) in levels.iter().enumerate() {
+            let expected_dimensions = ((width >> index).max(1), (height >> index).max(1));
 
-        image_state.wait_for_transfer();
+            if (level.width, level.height) != expected_dimensions {
+                bail!(ErrorKind::InvalidTextureData(format!(
+                    "level {} is {}x{}, rather than {}x{}",
+                    index, level.width, level.height, expected_dimensions.0, expected_dimensions.1
+                )));
+            }
 
-        device.destroy_command_pool(staging_pool.into_raw());
+            let expected_size = block_layout.image_size(level.width, level.height).ok_or_else(|| {
+                ErrorKind::InvalidTextureData(format!("level {} is too large to load at {}x{}", index, level.width, level.height))
+            })?;
+
+            if level.data.len() != expected_size {
+                bail!(ErrorKind::InvalidTextureData(format!(
+                    "level {} holds {} bytes, rather than {}",
+                    index,
+                    level.data.len(),
+                    expected_size
+                )));
+            }
+        }
 
-        image_state
+        Ok(Image {
+            format,
+            kind: i::Kind::D2(width, height, 1, 1),
+            levels,
+        })
     }
 
-    pub unsafe fn wait_for_transfer(&self) {
-        let device = &self.descriptor_set.layout.device_state.as_ref().borrow().device;
-        device.wait_for_fence(&self.image_fence.as_ref().unwrap(), !0).unwrap();
+    /// Generates the full mip chain of the image, replacing any it already had.
+    /// Block compressed images are left as they are, as their mip chains are compressed along with them.
+    pub fn with_mips(mut self) -> Self {
+        if let Some(mips) = generate_mips(self.format, &self.levels[0]) {
+            self.levels.truncate(1);
+            self.levels.extend(mips);
+        }
+
+        self
     }
 
-    pub fn get_layout(&self) -> &B::DescriptorSetLayout {
-        self.descriptor_set.get_layout()
+    pub fn get_format(&self) -> f::Format {
+        self.format
     }
-}
 
-impl<B: Backend> Drop for ImageState<B> {
-    fn drop(&mut self) {
-        let device = self.descriptor_set.layout.device_state.as_ref();
-        let device = &device.borrow().device;
+    pub fn get_kind(&self) -> &i::Kind {
+        &self.kind
+    }
 
-        let image_fence = self.image_fence.take().unwrap();
-        unsafe {
-            device.wait_for_fence(&image_fence, !0).unwrap();
-            device.destroy_fence(image_fence);
+    pub fn get_dimensions(&self) -> (u32, u32) {
+        self.get_level_dimensions(0)
+    }
 
-            device.destroy_sampler(self.sampler.take().unwrap());
-            device.destroy_image_view(self.image_view.take().unwrap());
-            device.destroy_image(self.image.take().unwrap());
-            device.destroy_buffer(self.buffer.take().unwrap());
+    /// The number of levels in the image, including the full size image
+    pub fn mip_level_count(&self) -> i::Level {
+        self.levels.len() as i::Level
+    }
 
-            device.free_memory(self.memory.take().unwrap());
+    /// The subresource range covering every level of the image
+    pub fn get_range(&self) -> i::SubresourceRange {
+        i::SubresourceRange {
+            levels: 0..self.mip_level_count(),
+            ..COLOR_RANGE
         }
     }
-}
 
-pub struct Image {
-    pub image: image::RgbaImage,
-    pub kind: i::Kind,
-}
+    /// Returns the full size image for level 0, or the level of the mip chain below it
+    pub fn get_level(&self, level: i::Level) -> &ImageLevel {
+        &self.levels[level as usize]
+    }
 
-impl Image {
-    pub fn get_image(&self) -> &image::RgbaImage {
-        &self.image
+    pub fn get_level_dimensions(&self, level: i::Level) -> (u32, u32) {
+        let level = self.get_level(level);
+        (level.width, level.height)
     }
 
-    pub fn get_kind(&self) -> &i::Kind {
-        &self.kind
+    fn block_layout(&self) -> BlockLayout {
+        // The format was checked when the image was created:
+        texel::block_layout(self.format).unwrap()
     }
 
-    pub fn get_dimensions(&self) -> (u32, u32) {
-        self.image.dimensions()
+    /// The number of bytes in each row of blocks once it is padded for copying into an image
+    pub fn row_pitch(&self, level: i::Level, row_alignment_mask: u32) -> u32 {
+        let (width, height) = self.get_level_dimensions(level);
+        let block_layout = self.block_layout();
+        let (blocks_wide, _) = block_layout.block_count(width, height);
+
+        (blocks_wide * block_layout.size + row_alignment_mask) & !row_alignment_mask
     }
 
-    pub fn row_pitch(&self, row_alignment_mask: u32) -> u32 {
-        let (width, _) = self.get_dimensions();
-        (width * RGBA_IMAGE_STRIDE as u32 + row_alignment_mask) & !row_alignment_mask
+    /// The number of rows of blocks in the level
+    pub fn row_count(&self, level: i::Level) -> u32 {
+        let (width, height) = self.get_level_dimensions(level);
+        let (_, blocks_high) = self.block_layout().block_count(width, height);
+
+        blocks_high
     }
 
     /// Returns a range which denotes the start and finish of bytes
-    /// that belong to the row provided by row_index
-    pub fn row_range(&self, row_index: usize) -> Range<usize> {
-        let (width, _) = self.get_dimensions();
-        let width = width as usize;
+    /// that belong to the row of blocks provided by row_index
+    pub fn row_range(&self, level: i::Level, row_index: usize) -> Range<usize> {
+        let (width, height) = self.get_level_dimensions(level);
+        let block_layout = self.block_layout();
+        let (blocks_wide, _) = block_layout.block_count(width, height);
+        let row_size = blocks_wide as usize * block_layout.size as usize;
+
+        row_index * row_size..(row_index + 1) * row_size
+    }
+
+    /// The width of the level in the upload buffer, measured in texels, as a copy into the image expects
+    pub fn buffer_width(&self, level: i::Level, row_alignment_mask: u32) -> u32 {
+        let block_layout = self.block_layout();
+        self.row_pitch(level, row_alignment_mask) / block_layout.size * block_layout.width
+    }
 
-        let row_index_start = row_index * width * RGBA_IMAGE_STRIDE;
-        let row_index_end = (row_index + 1) * width * RGBA_IMAGE_STRIDE;
+    /// The height of the level in the upload buffer, measured in texels and rounded up to whole blocks
+    pub fn buffer_height(&self, level: i::Level) -> u32 {
+        self.row_count(level) * self.block_layout().height
+    }
+
+    /// Returns the size of a single level once its rows are padded to the row pitch
+    pub fn get_level_size(&self, level: i::Level, row_alignment_mask: u32) -> u64 {
+        u64::from(self.row_count(level)) * u64::from(self.row_pitch(level, row_alignment_mask))
+    }
 
-        row_index_start..row_index_end
+    /// Returns where the level starts in the upload buffer, which holds each level in turn
+    pub fn level_offset(&self, level: i::Level, row_alignment_mask: u32) -> u64 {
+        (0..level).map(|level| self.get_level_size(level, row_alignment_mask)).sum()
     }
 
-    /// Returns the complete size of the image
+    /// Returns the complete size of the image, including its mip chain
     pub fn get_upload_size(&self, row_alignment_mask: u32) -> u64 {
-        let (_, height) = self.get_dimensions();
-        let row_pitch = self.row_pitch(row_alignment_mask);
-        (height * row_pitch) as u64
+        self.level_offset(self.mip_level_count(), row_alignment_mask)
     }
 }
 
+/// The number of levels in the full mip chain of an image of the given size, including the full size image
+pub fn max_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).leading_zeros()
+}
+
+/// Halves the level repeatedly until it is 1x1, returning every level after the first.
+/// Returns `None` for block compressed formats, which can't be filtered without decompressing them.
+pub fn generate_mips(format: f::Format, level: &ImageLevel) -> Option<Vec<ImageLevel>> {
+    let mut texels = texel::decode_rgba(format, &level.data)?;
+    let (mut width, mut height) = (level.width, level.height);
+    let mut mips = Vec::new();
+
+    while width > 1 || height > 1 {
+        let (mip_width, mip_height, mip_texels) = downsample(width, height, &texels);
+
+        mips.push(ImageLevel {
+            width: mip_width,
+            height: mip_height,
+            data: texel::encode_rgba(format, &mip_texels)?,
+        });
+
+        width = mip_width;
+        height = mip_height;
+        texels = mip_texels;
+    }
+
+    Some(mips)
+}
+
+/// Averages each 2x2 block of texels into one. The texels are linear values, as averaging sRGB
+/// values would otherwise darken the smaller levels. When a dimension is odd, the last texel along it
+/// averages the last three source texels, so that the final row or column isn't lost.
+fn downsample(width: u32, height: u32, texels: &[[f32; 4]]) -> (u32, u32, Vec<[f32; 4]>) {
+    let (mip_width, mip_height) = ((width / 2).max(1), (height / 2).max(1));
+    let mut mip_texels = Vec::with_capacity(mip_width as usize * mip_height as usize);
+
+    for y in 0..mip_height {
+        for x in 0..mip_width {
+            // The last texel reaches the end of the source, which also covers a dimension of 1 that isn't halved:
+            let footprint = |index: u32, size: u32, mip_size: u32| {
+                if index + 1 == mip_size {
+                    2 * index..size
+                } else {
+                    2 * index..2 * index + 2
+                }
+            };
+
+            let xs = footprint(x, width, mip_width);
+            let ys = footprint(y, height, mip_height);
+
+            let mut sum = [0.0f32; 4];
+            let mut count = 0.0;
+
+            for source_y in ys {
+                for source_x in xs.clone() {
+                    let texel = &texels[(source_y * width + source_x) as usize];
+
+                    for channel in 0..4 {
+                        sum[channel] += texel[channel];
+                    }
+
+                    count += 1.0;
+                }
+            }
+
+            mip_texels.push([sum[0] / count, sum[1] / count, sum[2] / count, sum[3] / count]);
+        }
+    }
+
+    (mip_width, mip_height, mip_texels)
+}
+
 pub struct Loader;
 
 impl Loader {
-    /// Loads a texture from file
-    pub fn from_file(image_file_path: &str) -> Option<Image> {
-        match image::open(image_file_path) {
-            Ok(image) => {
-                let image = image.to_rgba();
-                let (width, height) = image.dimensions();
-                let kind = i::Kind::D2(width, height, 1, 1);
-
-                Some(Image { image, kind })
-            }
-            Err(_) => None,
+    /// Loads a colour texture from file, along with its mip chain
+    pub fn from_file(image_file_path: &str) -> Result<Image> {
+        Loader::from_file_with_color_space(image_file_path, ColorSpace::Srgb)
+    }
+
+    /// Loads a texture holding data such as normals, which is sampled without converting it from sRGB
+    pub fn from_file_linear(image_file_path: &str) -> Result<Image> {
+        Loader::from_file_with_color_space(image_file_path, ColorSpace::Linear)
+    }
+
+    /// Loads a texture from file, choosing how to read it from the file's extension:
+    /// * `dds` and `ktx2` files are loaded in the format they were saved in, including any block compression and mip chain
+    /// * `hdr` files are loaded as `HDR_FORMAT`
+    /// * `exr` files are rejected, as the `image` crate can't decode OpenEXR, and HDR textures can be saved as `.hdr` or KTX2 instead
+    /// * anything else is decoded to 8 bits per channel
+    ///
+    /// The colour space is only used by files that don't specify one themselves.
+    pub fn from_file_with_color_space(image_file_path: &str, color_space: ColorSpace) -> Result<Image> {
+        let path = Path::new(image_file_path);
+
+        if !path.is_file() {
+            bail!(ErrorKind::AssetNotFound(image_file_path.to_string()));
         }
+
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+
+        let image = match extension.as_ref().map(String::as_str) {
+            Some("dds") => fs::read(path)
+                .map_err(Error::from)
+                .and_then(|bytes| texture_file::parse_dds(&bytes, color_space)),
+            Some("ktx2") => fs::read(path)
+                .map_err(Error::from)
+                .and_then(|bytes| texture_file::parse_ktx2(&bytes)),
+            Some("hdr") => Loader::load_hdr(path),
+            Some("exr") => Err(ErrorKind::UnsupportedTextureFormat("OpenEXR, convert the image to .hdr or KTX2".to_string()).into()),
+            _ => image::open(path)
+                .map(|image| Image::from_rgba(image.to_rgba(), color_space).with_mips())
+                .map_err(|error| error.to_string().into()),
+        };
+
+        image.map_err(|error| ErrorKind::ImportFailed(image_file_path.to_string(), error.to_string()).into())
+    }
+
+    fn load_hdr(path: &Path) -> Result<Image> {
+        let decoder = image::hdr::HDRDecoder::new(BufReader::new(File::open(path)?)).map_err(|error| error.to_string())?;
+        let metadata = decoder.metadata();
+
+        let texels: Vec<[f32; 4]> = decoder
+            .read_image_hdr()
+            .map_err(|error| error.to_string())?
+            .iter()
+            .map(|pixel| [pixel[0], pixel[1], pixel[2], 1.0])
+            .collect();
+
+        Ok(Image::from_texels(HDR_FORMAT, metadata.width, metadata.height, &texels)?.with_mips())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn should_generate_mip_chain() {
+        let image = Image::from_rgba(image::RgbaImage::from_pixel(8, 2, image::Rgba([255, 0, 0, 128])), ColorSpace::Srgb).with_mips();
+
+        let dimensions: Vec<(u32, u32)> = (0..image.mip_level_count())
+            .map(|level| image.get_level_dimensions(level))
+            .collect();
+
+        assert_eq!(vec![(8, 2), (4, 1), (2, 1), (1, 1)], dimensions);
+        assert_eq!(vec![255, 0, 0, 128], image.get_level(3).data);
+    }
+
+    #[test]
+    fn should_average_odd_rows_and_columns() {
+        let image = image::RgbaImage::from_fn(3, 1, |x, _| image::Rgba([[0, 30, 90][x as usize], 0, 0, 255]));
+        let image = Image::from_rgba(image, ColorSpace::Linear).with_mips();
+
+        // The single texel of the smaller level averages all three columns:
+        assert_eq!((1, 1), image.get_level_dimensions(1));
+        assert_eq!(vec![40, 0, 0, 255], image.get_level(1).data);
+
+        let image = image::RgbaImage::from_fn(2, 5, |_, y| image::Rgba([0, [10, 20, 30, 40, 80][y as usize], 0, 255]));
+        let image = Image::from_rgba(image, ColorSpace::Linear).with_mips();
+
+        assert_eq!(vec![0, 15, 0, 255, 0, 50, 0, 255], image.get_level(1).data);
+    }
+
+    #[test]
+    fn should_average_in_linear_space() {
+        let image = image::RgbaImage::from_fn(2, 2, |x, _| {
+            if x == 0 {
+                image::Rgba([0, 0, 0, 0])
+            } else {
+                image::Rgba([255, 255, 255, 255])
+            }
+        });
+
+        let srgb = Image::from_rgba(image.clone(), ColorSpace::Srgb).with_mips();
+        let linear = Image::from_rgba(image, ColorSpace::Linear).with_mips();
+
+        // Half of full intensity is 188 in sRGB, rather than the 128 a plain average would give:
+        assert_eq!(vec![188, 188, 188, 128], srgb.get_level(1).data);
+        assert_eq!(vec![128, 128, 128, 128], linear.get_level(1).data);
+    }
+
+    #[test]
+    fn should_generate_half_float_mips() {
+        let texels = vec![[0.0, 0.0, 0.0, 1.0], [4.0, 2.0, 1.0, 1.0]];
+        let image = Image::from_texels(HDR_FORMAT, 2, 1, &texels).unwrap().with_mips();
+
+        assert_eq!(2, image.mip_level_count());
+        assert_eq!(
+            Some(vec![[2.0, 1.0, 0.5, 1.0]]),
+            texel::decode_rgba(HDR_FORMAT, &image.get_level(1).data)
+        );
+    }
+
+    #[test]
+    fn should_pad_levels_to_row_pitch() {
+        let image = Image::from_rgba(image::RgbaImage::new(4, 4), ColorSpace::Srgb).with_mips();
+        let row_alignment_mask = 255;
+
+        assert_eq!(256, image.row_pitch(2, row_alignment_mask));
+        assert_eq!(0, image.level_offset(0, row_alignment_mask));
+        assert_eq!(4 * 256, image.level_offset(1, row_alignment_mask));
+        assert_eq!(6 * 256, image.level_offset(2, row_alignment_mask));
+        assert_eq!(7 * 256, image.get_upload_size(row_alignment_mask));
+    }
+
+    #[test]
+    fn should_measure_block_compressed_levels() {
+        let levels = [(8, 8, 32), (4, 4, 8), (2, 2, 8), (1, 1, 8)]
+            .iter()
+            .map(|&(width, height, size)| ImageLevel {
+                width,
+                height,
+                data: vec![0; size],
+            })
+            .collect();
+        let image = Image::from_levels(f::Format::Bc1RgbaSrgb, levels).unwrap().with_mips();
+        let row_alignment_mask = 255;
+
+        assert_eq!(4, image.mip_level_count());
+        assert_eq!(2, image.row_count(0));
+        assert_eq!(16..32, image.row_range(0, 1));
+        assert_eq!(1, image.row_count(3));
+        assert_eq!(128, image.buffer_width(3, row_alignment_mask));
+        assert_eq!(4, image.buffer_height(3));
+        assert_eq!(5 * 256, image.get_upload_size(row_alignment_mask));
+    }
+
+    #[test]
+    fn should_reject_mismatched_levels() {
+        let levels = vec![ImageLevel {
+            width: 4,
+            height: 4,
+            data: vec![0; 4],
+        }];
+
+        assert!(Image::from_levels(f::Format::Bc3Srgb, levels).is_err());
     }
 }
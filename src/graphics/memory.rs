@@ -0,0 +1,503 @@
+use errors::*;
+use gfx_hal::memory as m;
+use gfx_hal::*;
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// The size of the blocks requested from the driver, which smaller allocations are carved from
+pub const DEFAULT_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Small heaps are split into at least this many blocks, so one block can't exhaust the heap
+const MIN_BLOCKS_PER_HEAP: u64 = 8;
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    if alignment <= 1 {
+        value
+    } else {
+        (value + alignment - 1) / alignment * alignment
+    }
+}
+
+/// Tracks the unused ranges of a single block of memory, merging neighbouring ranges as they are freed
+#[derive(Debug)]
+pub struct FreeList {
+    size: u64,
+    free_ranges: Vec<Range<u64>>,
+}
+
+impl FreeList {
+    pub fn new(size: u64) -> Self {
+        FreeList {
+            size,
+            free_ranges: vec![0..size],
+        }
+    }
+
+    /// Reserves the first range large enough to hold `size` bytes at the given alignment, returning its offset
+    pub fn allocate(&mut self, size: u64, alignment: u64) -> Option<u64> {
+        let position = self
+            .free_ranges
+            .iter()
+            .position(|range| align_up(range.start, alignment) + size <= range.end)?;
+
+        let range = self.free_ranges.remove(position);
+        let offset = align_up(range.start, alignment);
+
+        // Any padding before the allocation and space left after it remain free:
+        if offset + size < range.end {
+            self.free_ranges.insert(position, offset + size..range.end);
+        }
+
+        if range.start < offset {
+            self.free_ranges.insert(position, range.start..offset);
+        }
+
+        Some(offset)
+    }
+
+    pub fn free(&mut self, range: Range<u64>) {
+        let position = self
+            .free_ranges
+            .iter()
+            .position(|free_range| free_range.start > range.start)
+            .unwrap_or(self.free_ranges.len());
+
+        self.free_ranges.insert(position, range);
+
+        // Merge with the following range, then the preceding one:
+        if position + 1 < self.free_ranges.len() && self.free_ranges[position].end == self.free_ranges[position + 1].start {
+            let next = self.free_ranges.remove(position + 1);
+            self.free_ranges[position].end = next.end;
+        }
+
+        if position > 0 && self.free_ranges[position - 1].end == self.free_ranges[position].start {
+            let current = self.free_ranges.remove(position);
+            self.free_ranges[position - 1].end = current.end;
+        }
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.size - self.free_ranges.iter().map(|range| range.end - range.start).sum::<u64>()
+    }
+
+    pub fn is_unused(&self) -> bool {
+        self.used_bytes() == 0
+    }
+}
+
+/// A summary of the device memory held by the allocator
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MemoryStats {
+    /// Bytes allocated from the driver, whether or not they are in use
+    pub reserved_bytes: u64,
+    pub used_bytes: u64,
+    /// The number of driver allocations, each of which counts towards the device's allocation limit
+    pub block_count: usize,
+    pub allocation_count: usize,
+}
+
+impl MemoryStats {
+    pub fn free_bytes(&self) -> u64 {
+        self.reserved_bytes - self.used_bytes
+    }
+
+    fn add(&mut self, other: &MemoryStats) {
+        self.reserved_bytes += other.reserved_bytes;
+        self.used_bytes += other.used_bytes;
+        self.block_count += other.block_count;
+        self.allocation_count += other.allocation_count;
+    }
+}
+
+/// Buffers and optimally tiled images are kept in separate pools,
+/// so that they never need padding to the buffer/image granularity
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+struct PoolKey {
+    memory_type: usize,
+    linear: bool,
+}
+
+struct MemoryBlock<B: Backend> {
+    memory: B::Memory,
+    free_list: FreeList,
+}
+
+struct MemoryPool<B: Backend> {
+    blocks: Vec<Option<MemoryBlock<B>>>,
+    block_size: u64,
+    stats: MemoryStats,
+}
+
+enum AllocationSource<B: Backend> {
+    Block(usize),
+    Dedicated(B::Memory),
+}
+
+/// A range of device memory, either carved from a shared block or allocated for a single large resource
+pub struct MemoryAllocation<B: Backend> {
+    pool_key: PoolKey,
+    source: AllocationSource<B>,
+    offset: u64,
+    size: u64,
+}
+
+impl<B: Backend> MemoryAllocation<B> {
+    pub fn memory_type(&self) -> MemoryTypeId {
+        MemoryTypeId(self.pool_key.memory_type)
+    }
+
+    /// The offset of the allocation from the start of its memory object
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn range(&self) -> Range<u64> {
+        self.offset..self.offset + self.size
+    }
+}
+
+/// Sub-allocates buffers and images from a small number of large blocks of device memory,
+/// pooled per memory type, rather than making a driver allocation for every resource
+pub struct MemoryAllocator<B: Backend> {
+    memory_types: Vec<MemoryType>,
+    memory_heaps: Vec<u64>,
+    non_coherent_atom_size: u64,
+    pools: HashMap<PoolKey, MemoryPool<B>>,
+}
+
+impl<B: Backend> MemoryAllocator<B> {
+    pub fn new(memory_properties: MemoryProperties, limits: &Limits) -> Self {
+        MemoryAllocator {
+            memory_types: memory_properties.memory_types,
+            memory_heaps: memory_properties.memory_heaps,
+            non_coherent_atom_size: limits.non_coherent_atom_size as u64,
+            pools: HashMap::new(),
+        }
+    }
+
+    pub fn memory_types(&self) -> &[MemoryType] {
+        &self.memory_types
+    }
+
+    /// Locates the first memory type allowed by the requirements that has all of the given properties
+    pub fn find_memory_type(
+        memory_types: &[MemoryType],
+        requirements: &m::Requirements,
+        properties: m::Properties,
+    ) -> Result<MemoryTypeId> {
+        memory_types
+            .iter()
+            .enumerate()
+            .position(|(id, memory_type)| requirements.type_mask & (1 << id) != 0 && memory_type.properties.contains(properties))
+            .map(MemoryTypeId::from)
+            .ok_or_else(|| ErrorKind::Graphics("find memory type".to_string(), format!("no memory type supports {:?}", properties)).into())
+    }
+
+    /// Allocates memory for the buffer and binds it
+    pub unsafe fn bind_buffer(
+        &mut self,
+        device: &B::Device,
+        buffer: &mut B::Buffer,
+        properties: m::Properties,
+    ) -> Result<MemoryAllocation<B>> {
+        let requirements = device.get_buffer_requirements(buffer);
+        let allocation = self.allocate(device, &requirements, properties, true)?;
+
+        if let Err(e) = device.bind_buffer_memory(self.memory(&allocation), allocation.offset, buffer) {
+            self.free(device, allocation);
+            bail!(ErrorKind::Graphics("bind buffer memory".to_string(), format!("{:?}", e)));
+        }
+
+        Ok(allocation)
+    }
+
+    /// Allocates memory for the optimally tiled image and binds it
+    pub unsafe fn bind_image(
+        &mut self,
+        device: &B::Device,
+        image: &mut B::Image,
+        properties: m::Properties,
+    ) -> Result<MemoryAllocation<B>> {
+        let requirements = device.get_image_requirements(image);
+        let allocation = self.allocate(device, &requirements, properties, false)?;
+
+        if let Err(e) = device.bind_image_memory(self.memory(&allocation), allocation.offset, image) {
+            self.free(device, allocation);
+            bail!(ErrorKind::Graphics("bind image memory".to_string(), format!("{:?}", e)));
+        }
+
+        Ok(allocation)
+    }
+
+    /// Reserves memory meeting the requirements, with `linear` set for buffers and linearly tiled images
+    pub unsafe fn allocate(
+        &mut self,
+        device: &B::Device,
+        requirements: &m::Requirements,
+        properties: m::Properties,
+        linear: bool,
+    ) -> Result<MemoryAllocation<B>> {
+        let memory_type = Self::find_memory_type(&self.memory_types, requirements, properties)?;
+        let type_properties = self.memory_types[memory_type.0].properties;
+
+        // Mapped ranges of non-coherent memory are flushed in whole atoms, so allocations must not share one:
+        let (size, alignment) =
+            if type_properties.contains(m::Properties::CPU_VISIBLE) && !type_properties.contains(m::Properties::COHERENT) {
+                (
+                    align_up(requirements.size, self.non_coherent_atom_size),
+                    requirements.alignment.max(self.non_coherent_atom_size),
+                )
+            } else {
+                (requirements.size, requirements.alignment)
+            };
+
+        let pool_key = PoolKey {
+            memory_type: memory_type.0,
+            linear,
+        };
+
+        let block_size = self.block_size(memory_type);
+        let pool = self.pools.entry(pool_key).or_insert_with(|| MemoryPool {
+            blocks: Vec::new(),
+            block_size,
+            stats: MemoryStats::default(),
+        });
+
+        // Large resources get memory of their own rather than monopolising a block:
+        if size > pool.block_size / 2 {
+            let memory = device
+                .allocate_memory(memory_type, size)
+                .map_err(|_| ErrorKind::OutOfDeviceMemory(size))?;
+
+            pool.stats.add(&MemoryStats {
+                reserved_bytes: size,
+                used_bytes: size,
+                block_count: 1,
+                allocation_count: 1,
+            });
+
+            return Ok(MemoryAllocation {
+                pool_key,
+                source: AllocationSource::Dedicated(memory),
+                offset: 0,
+                size,
+            });
+        }
+
+        let existing = pool
+            .blocks
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, block)| {
+                block
+                    .as_mut()
+                    .and_then(|block| block.free_list.allocate(size, alignment))
+                    .map(|offset| (index, offset))
+            })
+            .next();
+
+        let (index, offset) = match existing {
+            Some(existing) => existing,
+            None => {
+                let memory = device
+                    .allocate_memory(memory_type, pool.block_size)
+                    .map_err(|_| ErrorKind::OutOfDeviceMemory(pool.block_size))?;
+
+                let mut free_list = FreeList::new(pool.block_size);
+                let offset = free_list.allocate(size, alignment).unwrap();
+
+                let block = Some(MemoryBlock { memory, free_list });
+
+                pool.stats.reserved_bytes += pool.block_size;
+                pool.stats.block_count += 1;
+
+                // Reuse the slot of a block that has since been freed:
+                let index = match pool.blocks.iter().position(Option::is_none) {
+                    Some(index) => {
+                        pool.blocks[index] = block;
+                        index
+                    }
+                    None => {
+                        pool.blocks.push(block);
+                        pool.blocks.len() - 1
+                    }
+                };
+
+                (index, offset)
+            }
+        };
+
+        pool.stats.used_bytes += size;
+        pool.stats.allocation_count += 1;
+
+        Ok(MemoryAllocation {
+            pool_key,
+            source: AllocationSource::Block(index),
+            offset,
+            size,
+        })
+    }
+
+    /// Returns the memory object the allocation was made from, for binding and mapping
+    pub fn memory<'a>(&'a self, allocation: &'a MemoryAllocation<B>) -> &'a B::Memory {
+        match allocation.source {
+            AllocationSource::Block(index) => &self.pools[&allocation.pool_key].blocks[index].as_ref().unwrap().memory,
+            AllocationSource::Dedicated(ref memory) => memory,
+        }
+    }
+
+    pub unsafe fn free(&mut self, device: &B::Device, allocation: MemoryAllocation<B>) {
+        let range = allocation.range();
+        let pool = self.pools.get_mut(&allocation.pool_key).unwrap();
+
+        pool.stats.used_bytes -= allocation.size;
+        pool.stats.allocation_count -= 1;
+
+        match allocation.source {
+            AllocationSource::Dedicated(memory) => {
+                device.free_memory(memory);
+
+                pool.stats.reserved_bytes -= allocation.size;
+                pool.stats.block_count -= 1;
+            }
+            AllocationSource::Block(index) => {
+                pool.blocks[index].as_mut().unwrap().free_list.free(range);
+
+                // One empty block is kept, so that repeatedly allocating and freeing doesn't thrash the driver:
+                let unused_blocks = pool
+                    .blocks
+                    .iter()
+                    .filter(|block| block.as_ref().map_or(false, |block| block.free_list.is_unused()))
+                    .count();
+
+                if unused_blocks > 1 && pool.blocks[index].as_ref().unwrap().free_list.is_unused() {
+                    device.free_memory(pool.blocks[index].take().unwrap().memory);
+
+                    pool.stats.reserved_bytes -= pool.block_size;
+                    pool.stats.block_count -= 1;
+                }
+            }
+        }
+    }
+
+    /// Totals the memory held across every memory type
+    pub fn stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats::default();
+
+        for pool in self.pools.values() {
+            stats.add(&pool.stats);
+        }
+
+        stats
+    }
+
+    pub fn memory_type_stats(&self, memory_type: MemoryTypeId) -> MemoryStats {
+        let mut stats = MemoryStats::default();
+
+        for (pool_key, pool) in &self.pools {
+            if pool_key.memory_type == memory_type.0 {
+                stats.add(&pool.stats);
+            }
+        }
+
+        stats
+    }
+
+    /// Frees every block, which must happen before the device is destroyed
+    pub unsafe fn dispose(&mut self, device: &B::Device) {
+        let stats = self.stats();
+        if stats.allocation_count > 0 {
+            warn!(
+                "Disposing of the memory allocator with {} allocations still in use",
+                stats.allocation_count
+            );
+        }
+
+        for (_, pool) in self.pools.drain() {
+            for block in pool.blocks.into_iter().filter_map(|block| block) {
+                device.free_memory(block.memory);
+            }
+        }
+    }
+
+    fn block_size(&self, memory_type: MemoryTypeId) -> u64 {
+        let heap_size = self.memory_heaps[self.memory_types[memory_type.0].heap_index];
+
+        DEFAULT_BLOCK_SIZE.min(heap_size / MIN_BLOCKS_PER_HEAP)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn should_allocate_aligned_ranges() {
+        let mut free_list = FreeList::new(1024);
+
+        assert_eq!(Some(0), free_list.allocate(100, 256));
+        assert_eq!(Some(256), free_list.allocate(100, 256));
+        assert_eq!(Some(100), free_list.allocate(50, 4));
+        assert_eq!(250, free_list.used_bytes());
+    }
+
+    #[test]
+    fn should_fail_when_exhausted() {
+        let mut free_list = FreeList::new(1024);
+
+        assert_eq!(Some(0), free_list.allocate(1000, 1));
+        assert_eq!(None, free_list.allocate(100, 1));
+        assert_eq!(Some(1000), free_list.allocate(24, 1));
+    }
+
+    #[test]
+    fn should_merge_freed_ranges() {
+        let mut free_list = FreeList::new(300);
+
+        let first = free_list.allocate(100, 1).unwrap();
+        let second = free_list.allocate(100, 1).unwrap();
+        let third = free_list.allocate(100, 1).unwrap();
+
+        free_list.free(first..first + 100);
+        free_list.free(third..third + 100);
+        assert_eq!(None, free_list.allocate(200, 1));
+
+        free_list.free(second..second + 100);
+        assert!(free_list.is_unused());
+        assert_eq!(Some(0), free_list.allocate(300, 1));
+    }
+
+    #[test]
+    fn should_find_memory_type() {
+        let memory_types = vec![
+            MemoryType {
+                properties: m::Properties::DEVICE_LOCAL,
+                heap_index: 0,
+            },
+            MemoryType {
+                properties: m::Properties::CPU_VISIBLE | m::Properties::COHERENT,
+                heap_index: 1,
+            },
+        ];
+
+        let requirements = m::Requirements {
+            size: 256,
+            alignment: 256,
+            type_mask: 0b11,
+        };
+
+        let memory_type = MemoryAllocator::<::Backend>::find_memory_type(&memory_types, &requirements, m::Properties::CPU_VISIBLE);
+        assert_eq!(MemoryTypeId(1), memory_type.unwrap());
+
+        let requirements = m::Requirements {
+            type_mask: 0b01,
+            ..requirements
+        };
+        let memory_type = MemoryAllocator::<::Backend>::find_memory_type(&memory_types, &requirements, m::Properties::CPU_VISIBLE);
+        assert!(memory_type.is_err());
+    }
+}
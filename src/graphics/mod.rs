@@ -1,30 +1,54 @@
 pub mod shader;
 pub mod texture;
+#[macro_use]
+pub mod vertex;
+#[macro_use]
+pub mod uniform;
+#[macro_use]
+pub mod tracker;
 
 pub mod adapter;
 pub mod backend;
 pub mod buffer;
+pub mod constants;
+pub mod depth;
 pub mod descriptor;
 pub mod device;
 pub mod framebuffer;
 pub mod image;
+pub mod memory;
+pub mod model;
 pub mod pipeline;
+pub mod reflect;
 pub mod renderer;
+pub mod sampler;
+pub mod shader_cache;
+pub mod streaming;
 pub mod swapchain;
-pub mod uniform;
+pub mod target;
+pub mod texel;
+pub mod texture_file;
+pub mod upload;
+pub mod watcher;
 pub mod window;
 
 pub type Vec2 = [f32; 2];
 pub type Vec3 = [f32; 3];
 pub type Vec4 = [f32; 4];
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
 #[allow(non_snake_case)]
 pub struct Vertex {
     pub a_Position: Vec3,
     pub a_TexCoord: Vec2,
 }
 
+vertex_format!(Vertex {
+    a_Position => 0,
+    a_TexCoord => 1,
+});
+
 impl Vertex {
     pub fn set_position(&mut self, position: Vec3) {
         self.a_Position = position;
@@ -0,0 +1,169 @@
+use cgmath::{Matrix4, SquareMatrix};
+use errors::*;
+use gfx_hal::*;
+use graphics::buffer::BufferState;
+use graphics::device::DeviceState;
+use graphics::image::ColorSpace;
+use graphics::sampler::SamplerDesc;
+use graphics::texture::{TextureHandle, TextureManager};
+use graphics::upload::UploadBatch;
+use graphics::Vertex;
+use material::TextureSlot;
+use mesh::Mesh;
+use scene::Node;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::slice::Iter;
+
+/// The texture slots that are loaded for each material, as only these are sampled when drawing
+const LOADED_SLOTS: &[TextureSlot] = &[TextureSlot::Diffuse];
+
+/// The textures of a Material, held by handle so that textures shared between materials are only loaded once
+#[derive(Debug, Clone, Default)]
+pub struct MaterialState {
+    textures: HashMap<TextureSlot, TextureHandle>,
+}
+
+impl MaterialState {
+    pub fn texture(&self, slot: TextureSlot) -> Option<&TextureHandle> {
+        self.textures.get(&slot)
+    }
+
+    pub fn set_texture(&mut self, slot: TextureSlot, texture: TextureHandle) {
+        self.textures.insert(slot, texture);
+    }
+}
+
+/// Colour textures are sRGB, while the rest hold data that must be sampled unaltered
+fn slot_color_space(slot: TextureSlot) -> ColorSpace {
+    match slot {
+        TextureSlot::Diffuse | TextureSlot::Specular | TextureSlot::Emissive | TextureSlot::Lightmap => ColorSpace::Srgb,
+        TextureSlot::Normal | TextureSlot::Height | TextureSlot::Opacity => ColorSpace::Linear,
+    }
+}
+
+/// The GPU resources required to draw a single Mesh
+pub struct MeshState<B: Backend> {
+    pub vertex_buffer: BufferState<B, Graphics>,
+    pub index_buffer: BufferState<B, Graphics>,
+    index_count: u32,
+    material: MaterialState,
+    transformation: Matrix4<f32>,
+}
+
+impl<B: Backend> MeshState<B> {
+    /// Queues the mesh's vertices and indices to be copied into device local buffers by the upload batch
+    pub unsafe fn new(
+        upload_batch: &mut UploadBatch<B>,
+        mesh: &Mesh,
+        transformation: Matrix4<f32>,
+        material: MaterialState,
+    ) -> Result<Self> {
+        let vertices: Vec<Vertex> = mesh.vertex_iter().cloned().collect();
+        let indices = mesh.indices();
+
+        let vertex_buffer = upload_batch.upload_vertex_buffer::<Vertex>(&vertices)?;
+
+        let index_buffer = upload_batch.upload_buffer::<u32>(&indices, buffer::Usage::INDEX)?;
+
+        Ok(MeshState {
+            vertex_buffer,
+            index_buffer,
+            index_count: indices.len() as u32,
+            material,
+            transformation,
+        })
+    }
+
+    pub fn index_count(&self) -> u32 {
+        self.index_count
+    }
+
+    pub fn material(&self) -> &MaterialState {
+        &self.material
+    }
+
+    /// Returns the transformation accumulated from the root of the scene
+    pub fn transformation(&self) -> &Matrix4<f32> {
+        &self.transformation
+    }
+}
+
+/// A scene::Node hierarchy flattened into a list of drawable meshes.
+/// The textures referenced by their materials are held by the TextureManager.
+pub struct ModelState<B: Backend> {
+    meshes: Vec<MeshState<B>>,
+}
+
+impl<B: Backend> ModelState<B> {
+    pub unsafe fn new(
+        device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+        root_node: &Node,
+        texture_manager: &mut TextureManager<B>,
+        sampler_desc: &SamplerDesc,
+    ) -> Result<Self> {
+        let mut meshes = Vec::new();
+
+        // Every mesh's geometry and textures are uploaded with a single submission:
+        let mut upload_batch = UploadBatch::new(Rc::clone(&device_state))?;
+
+        // The visitor cannot return early, so the first failure is kept and returned afterwards:
+        let mut first_error: Option<Error> = None;
+
+        root_node.visit(&Matrix4::identity(), &mut |node, transformation| {
+            if first_error.is_some() {
+                return;
+            }
+
+            for mesh in node.meshes() {
+                // Buffers cannot be created with a size of zero:
+                if mesh.vertex_iter().len() == 0 || mesh.face_iter().len() == 0 {
+                    warn!("Skipping empty mesh '{}' in node '{}'", mesh.name(), node.name());
+                    continue;
+                }
+
+                let mut material_state = MaterialState::default();
+
+                for &slot in LOADED_SLOTS {
+                    let path = match mesh.material().and_then(|material| material.texture(slot)) {
+                        Some(path) => path,
+                        None => continue,
+                    };
+
+                    // A broken texture shouldn't prevent the rest of the model from drawing:
+                    match texture_manager.load(&mut upload_batch, path, slot_color_space(slot), sampler_desc) {
+                        Ok(texture) => material_state.set_texture(slot, texture),
+                        Err(e) => warn!(
+                            "Failed to load {:?} texture '{}' of mesh '{}': {}",
+                            slot,
+                            path.display(),
+                            mesh.name(),
+                            e
+                        ),
+                    }
+                }
+
+                match MeshState::new(&mut upload_batch, mesh, *transformation, material_state) {
+                    Ok(mesh_state) => meshes.push(mesh_state),
+                    Err(e) => {
+                        first_error = Some(e);
+                        return;
+                    }
+                }
+            }
+        });
+
+        if let Some(e) = first_error {
+            return Err(e);
+        }
+
+        upload_batch.flush()?;
+
+        Ok(ModelState { meshes })
+    }
+
+    pub fn mesh_iter(&self) -> Iter<MeshState<B>> {
+        self.meshes.iter()
+    }
+}
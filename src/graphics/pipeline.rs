@@ -1,68 +1,292 @@
+use errors::*;
 use gfx_hal::format as f;
 use gfx_hal::*;
+use graphics::constants::{find_push_constant_range, PushConstantBlock, SpecConstantValue, SpecConstants};
+use graphics::descriptor::DescriptorSetLayout;
 use graphics::device::DeviceState;
+use graphics::reflect::{PipelineReflection, ShaderReflection};
 use graphics::shader;
-use graphics::Vertex;
+use graphics::shader_cache::{self, ShaderCache};
+use graphics::tracker::ResourceId;
 use std::cell::RefCell;
+use std::ops::Range;
 use std::rc::Rc;
 
+const ENTRY_MAIN: &str = "main";
+
+/// A single shader stage to be compiled from a GLSL source file
+#[derive(Debug, Clone)]
+pub struct ShaderDesc {
+    pub name: String,
+    pub kind: shader::Kind,
+    pub file_path: String,
+    pub spec_constants: SpecConstants,
+    pub compile_options: shader::CompileOptions,
+}
+
+impl ShaderDesc {
+    pub fn new(name: &str, kind: shader::Kind, file_path: &str) -> Self {
+        ShaderDesc {
+            name: name.to_string(),
+            kind,
+            file_path: file_path.to_string(),
+            spec_constants: SpecConstants::new(),
+            compile_options: shader::CompileOptions::default(),
+        }
+    }
+
+    pub fn compile_options(mut self, compile_options: shader::CompileOptions) -> Self {
+        self.compile_options = compile_options;
+        self
+    }
+
+    /// Sets the value of the `layout(constant_id = id)` constant when the pipeline is built
+    pub fn spec_constant<T: SpecConstantValue>(mut self, id: u32, value: T) -> Self {
+        self.spec_constants.set(id, value);
+        self
+    }
+
+    /// Reads the SPIR-V for the shader from the cache, compiling it first if needed
+    pub fn load_spirv(&self) -> Result<Vec<u8>> {
+        let cache = ShaderCache::new(shader_cache::spirv_cache_dir());
+
+        // Without a compiler the source is of no use, so builds without one don't need to ship it:
+        if !cfg!(feature = "runtime-shaders") {
+            return cache.load_precompiled(&self.file_path, &self.compile_options);
+        }
+
+        if !std::path::Path::new(&self.file_path).is_file() {
+            bail!(ErrorKind::AssetNotFound(self.file_path.clone()));
+        }
+
+        let shader_src = std::fs::read_to_string(&self.file_path)?;
+
+        // Precompiled SPIR-V is used when there is some for this source, otherwise the shader is compiled now:
+        cache.load_or_compile(&self.file_path, &self.kind, &shader_src, &self.compile_options)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VertexAttribute {
+    pub location: u32,
+    pub format: f::Format,
+    pub offset: u32,
+}
+
+/// Describes how the vertices of a single vertex buffer map onto shader inputs
+#[derive(Debug, Clone, PartialEq)]
+pub struct VertexLayout {
+    pub stride: u32,
+    pub attributes: Vec<VertexAttribute>,
+}
+
+impl VertexLayout {
+    pub fn new(stride: u32) -> Self {
+        VertexLayout {
+            stride,
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn with_attribute(mut self, location: u32, format: f::Format, offset: u32) -> Self {
+        self.attributes.push(VertexAttribute { location, format, offset });
+        self
+    }
+}
+
+/// The fixed-function and shader configuration used to build a PipelineState
+#[derive(Debug, Clone)]
+pub struct PipelineDesc {
+    pub vertex_shader: ShaderDesc,
+    pub fragment_shader: Option<ShaderDesc>,
+    pub primitive: Primitive,
+    pub rasterizer: pso::Rasterizer,
+    pub blend_state: pso::BlendState,
+    pub depth_stencil: pso::DepthStencilDesc,
+    pub vertex_layout: VertexLayout,
+    pub push_constants: Vec<(pso::ShaderStageFlags, Range<u32>)>,
+}
+
+impl PipelineDesc {
+    /// Creates a description for an alpha blended triangle list pipeline without depth testing
+    pub fn new(vertex_shader: ShaderDesc, vertex_layout: VertexLayout) -> Self {
+        PipelineDesc {
+            vertex_shader,
+            fragment_shader: None,
+            primitive: Primitive::TriangleList,
+            rasterizer: pso::Rasterizer::FILL,
+            blend_state: pso::BlendState::ALPHA,
+            depth_stencil: pso::DepthStencilDesc::default(),
+            vertex_layout,
+            push_constants: Vec::new(),
+        }
+    }
+
+    pub fn fragment_shader(mut self, fragment_shader: ShaderDesc) -> Self {
+        self.fragment_shader = Some(fragment_shader);
+        self
+    }
+
+    pub fn primitive(mut self, primitive: Primitive) -> Self {
+        self.primitive = primitive;
+        self
+    }
+
+    pub fn cull_face(mut self, cull_face: pso::Face) -> Self {
+        self.rasterizer.cull_face = cull_face;
+        self
+    }
+
+    pub fn front_face(mut self, front_face: pso::FrontFace) -> Self {
+        self.rasterizer.front_face = front_face;
+        self
+    }
+
+    pub fn polygon_mode(mut self, polygon_mode: pso::PolygonMode) -> Self {
+        self.rasterizer.polygon_mode = polygon_mode;
+        self
+    }
+
+    pub fn blend_state(mut self, blend_state: pso::BlendState) -> Self {
+        self.blend_state = blend_state;
+        self
+    }
+
+    pub fn depth_stencil(mut self, depth_stencil: pso::DepthStencilDesc) -> Self {
+        self.depth_stencil = depth_stencil;
+        self
+    }
+
+    /// Reserves a range of push constants, measured in 32-bit words, for the given stages
+    pub fn push_constant_range(mut self, stages: pso::ShaderStageFlags, range: Range<u32>) -> Self {
+        self.push_constants.push((stages, range));
+        self
+    }
+
+    /// Reserves a range for a push constant block after any ranges already reserved
+    pub fn push_constant_block<T: PushConstantBlock>(self, stages: pso::ShaderStageFlags) -> Self {
+        let start = self.push_constants.iter().map(|&(_, ref range)| range.end).max().unwrap_or(0);
+
+        self.push_constant_range(stages, start..start + T::word_count())
+    }
+
+    /// Returns true if the source file of any of the pipeline's stages is at the given path
+    pub fn uses_shader(&self, path: &std::path::Path) -> bool {
+        self.shaders()
+            .any(|shader_desc| std::path::Path::new(&shader_desc.file_path) == path)
+    }
+
+    /// Reads the descriptors, push constants and vertex inputs used by the pipeline's shaders
+    pub fn reflect(&self) -> Result<PipelineReflection> {
+        let stages = self
+            .shaders()
+            .map(|shader_desc| shader_desc.load_spirv().and_then(|spirv| ShaderReflection::from_spirv(&spirv)))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(PipelineReflection::new(&stages))
+    }
+
+    fn shaders(&self) -> impl Iterator<Item = &ShaderDesc> {
+        std::iter::once(&self.vertex_shader).chain(self.fragment_shader.iter())
+    }
+
+    pub unsafe fn build<B: Backend>(
+        &self,
+        descriptor_layouts: &[&DescriptorSetLayout<B, Graphics>],
+        render_pass: &B::RenderPass,
+        device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+    ) -> Result<PipelineState<B>> {
+        PipelineState::new(self.clone(), descriptor_layouts, render_pass, device_state)
+    }
+}
+
 pub struct PipelineState<B: Backend> {
     pub pipeline: Option<B::GraphicsPipeline>,
     pub pipeline_layout: Option<B::PipelineLayout>,
+    pub desc: PipelineDesc,
     device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+    resource_ids: Vec<ResourceId>,
 }
 
 impl<B: Backend> PipelineState<B> {
-    pub unsafe fn new<IS>(descriptor_layouts: IS, render_pass: &B::RenderPass, device_state: Rc<RefCell<DeviceState<B, Graphics>>>) -> Self
-    where
-        IS: IntoIterator,
-        IS::Item: std::borrow::Borrow<B::DescriptorSetLayout>,
-    {
+    /// Builds the pipeline, after checking that the set layouts, push constants and vertex layout match
+    /// what the shaders declare. Push constant ranges are taken from the shaders if none were reserved.
+    pub unsafe fn new(
+        mut desc: PipelineDesc,
+        descriptor_layouts: &[&DescriptorSetLayout<B, Graphics>],
+        render_pass: &B::RenderPass,
+        device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+    ) -> Result<Self> {
+        let vs_spirv = desc.vertex_shader.load_spirv()?;
+        let fs_spirv = match desc.fragment_shader {
+            Some(ref fragment_shader) => Some(fragment_shader.load_spirv()?),
+            None => None,
+        };
+
+        let mut stages = vec![ShaderReflection::from_spirv(&vs_spirv)?];
+
+        if let Some(ref fs_spirv) = fs_spirv {
+            stages.push(ShaderReflection::from_spirv(fs_spirv)?);
+        }
+
+        let reflection = PipelineReflection::new(&stages);
+
+        let set_layout_bindings: Vec<_> = descriptor_layouts.iter().map(|layout| layout.bindings()).collect();
+        reflection.validate_set_layouts(&set_layout_bindings)?;
+        reflection.validate_vertex_layout(&desc.vertex_layout)?;
+
+        if desc.push_constants.is_empty() {
+            desc.push_constants = reflection.push_constant_ranges();
+        } else {
+            reflection.validate_push_constants(&desc.push_constants)?;
+        }
+
         let device = &device_state.as_ref().borrow().device;
 
         let pipeline_layout = device
-            .create_pipeline_layout(descriptor_layouts, &[(pso::ShaderStageFlags::VERTEX, 0..8)])
-            .unwrap();
-
-        let vs_module = Self::create_shader_module(
-            "VS_SHADER",
-            shader::Kind::Vertex,
-            concat!(env!("CARGO_MANIFEST_DIR"), "/shaders/quad_150.vert"),
-            Rc::clone(&device_state),
-        );
+            .create_pipeline_layout(descriptor_layouts.iter().map(|layout| layout.get_layout()), &desc.push_constants)
+            .graphics_err("create pipeline layout")?;
 
-        let fs_module = Self::create_shader_module(
-            "FS_SHADER",
-            shader::Kind::Fragment,
-            concat!(env!("CARGO_MANIFEST_DIR"), "/shaders/quad_150.frag"),
-            Rc::clone(&device_state),
-        );
+        let shader_modules = device
+            .create_shader_module(&vs_spirv)
+            .graphics_err("create shader module")
+            .and_then(|vs_module| match fs_spirv {
+                Some(ref fs_spirv) => match device.create_shader_module(fs_spirv).graphics_err("create shader module") {
+                    Ok(fs_module) => Ok((vs_module, Some(fs_module))),
+                    Err(e) => {
+                        device.destroy_shader_module(vs_module);
+                        Err(e)
+                    }
+                },
+                None => Ok((vs_module, None)),
+            });
 
-        let pipeline = {
-            const ENTRY_MAIN: &str = "main";
+        let (vs_module, fs_module) = match shader_modules {
+            Ok(shader_modules) => shader_modules,
+            Err(e) => {
+                device.destroy_pipeline_layout(pipeline_layout);
+                return Err(e);
+            }
+        };
 
-            let (vs_entry, fs_entry) = (
-                pso::EntryPoint {
+        let pipeline = {
+            let shader_entries = pso::GraphicsShaderSet {
+                vertex: pso::EntryPoint {
                     entry: ENTRY_MAIN,
                     module: &vs_module,
-                    specialization: pso::Specialization {
-                        constants: &[pso::SpecializationConstant { id: 0, range: 0..4 }],
-                        data: std::mem::transmute::<&f32, &[u8; 4]>(&0.5f32),
-                    },
-                },
-                pso::EntryPoint {
-                    entry: ENTRY_MAIN,
-                    module: &fs_module,
-                    specialization: pso::Specialization::default(),
+                    specialization: desc.vertex_shader.spec_constants.specialization(),
                 },
-            );
-
-            let shader_entries = pso::GraphicsShaderSet {
-                vertex: vs_entry,
                 hull: None,
                 domain: None,
                 geometry: None,
-                fragment: Some(fs_entry),
+                fragment: match (fs_module.as_ref(), desc.fragment_shader.as_ref()) {
+                    (Some(fs_module), Some(fragment_shader)) => Some(pso::EntryPoint {
+                        entry: ENTRY_MAIN,
+                        module: fs_module,
+                        specialization: fragment_shader.spec_constants.specialization(),
+                    }),
+                    _ => None,
+                },
             };
 
             let subpass = pass::Subpass {
@@ -70,77 +294,166 @@ impl<B: Backend> PipelineState<B> {
                 main_pass: render_pass,
             };
 
-            let mut pipeline_description = pso::GraphicsPipelineDesc::new(
-                shader_entries,
-                Primitive::TriangleStrip,
-                pso::Rasterizer::FILL,
-                &pipeline_layout,
-                subpass,
-            );
+            let mut pipeline_description =
+                pso::GraphicsPipelineDesc::new(shader_entries, desc.primitive, desc.rasterizer.clone(), &pipeline_layout, subpass);
 
             pipeline_description
                 .blender
                 .targets
-                .push(pso::ColorBlendDesc(pso::ColorMask::ALL, pso::BlendState::ALPHA));
+                .push(pso::ColorBlendDesc(pso::ColorMask::ALL, desc.blend_state));
+
+            pipeline_description.depth_stencil = desc.depth_stencil;
 
             pipeline_description.vertex_buffers.push(pso::VertexBufferDesc {
                 binding: 0,
-                stride: std::mem::size_of::<Vertex>() as u32,
+                stride: desc.vertex_layout.stride,
                 rate: 0,
             });
 
-            pipeline_description.attributes.push(pso::AttributeDesc {
-                location: 0,
-                binding: 0,
-                element: pso::Element {
-                    format: f::Format::Rg32Float,
-                    offset: 0,
-                },
-            });
-
-            pipeline_description.attributes.push(pso::AttributeDesc {
-                location: 1,
-                binding: 0,
-                element: pso::Element {
-                    format: f::Format::Rg32Float,
-                    offset: 12,
-                },
-            });
+            for attribute in &desc.vertex_layout.attributes {
+                pipeline_description.attributes.push(pso::AttributeDesc {
+                    location: attribute.location,
+                    binding: 0,
+                    element: pso::Element {
+                        format: attribute.format,
+                        offset: attribute.offset,
+                    },
+                });
+            }
 
-            device.create_graphics_pipeline(&pipeline_description, None).unwrap()
+            device
+                .create_graphics_pipeline(&pipeline_description, None)
+                .graphics_err("create graphics pipeline")
         };
 
-        // Cleanup Shader module resources after use:
+        // Cleanup Shader module resources after use, whether or not the pipeline was created:
         device.destroy_shader_module(vs_module);
-        device.destroy_shader_module(fs_module);
 
-        PipelineState {
+        if let Some(fs_module) = fs_module {
+            device.destroy_shader_module(fs_module);
+        }
+
+        let pipeline = match pipeline {
+            Ok(pipeline) => pipeline,
+            Err(e) => {
+                device.destroy_pipeline_layout(pipeline_layout);
+                return Err(e);
+            }
+        };
+
+        let resource_ids = vec![
+            track_resource!(device_state.as_ref().borrow(), PipelineLayout),
+            track_resource!(device_state.as_ref().borrow(), GraphicsPipeline),
+        ];
+
+        Ok(PipelineState {
             pipeline: Some(pipeline),
             pipeline_layout: Some(pipeline_layout),
+            desc,
             device_state: Rc::clone(&device_state),
-        }
+            resource_ids,
+        })
     }
 
-    unsafe fn create_shader_module(
-        name: &str,
-        kind: shader::Kind,
-        file_path: &str,
-        device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
-    ) -> B::ShaderModule {
-        let shader_src = std::fs::read_to_string(file_path).expect(&format!("Failed to read shader from file: {}", name));
+    /// Records a push of the block into the range reserved for exactly the given stages
+    pub unsafe fn push_constants<T: PushConstantBlock>(
+        &self,
+        encoder: &mut command::RenderPassInlineEncoder<B>,
+        stages: pso::ShaderStageFlags,
+        block: &T,
+    ) {
+        let range = find_push_constant_range(&self.desc.push_constants, stages)
+            .unwrap_or_else(|| panic!("No push constant range was reserved for {:?}", stages));
 
-        let spirv_bin = shader::Loader::compile(name, &kind, &shader_src).expect(&format!("Failed to compile shader: {}", name));
+        assert!(
+            T::word_count() <= range.end - range.start,
+            "Push constant block is larger than the range reserved for it"
+        );
 
-        device_state.as_ref().borrow().device.create_shader_module(&spirv_bin).unwrap()
+        encoder.push_graphics_constants(self.pipeline_layout.as_ref().unwrap(), stages, range.start, &block.to_words());
     }
 }
 
 impl<B: Backend> Drop for PipelineState<B> {
     fn drop(&mut self) {
-        let device = &self.device_state.as_ref().borrow().device;
+        let device_state = self.device_state.as_ref().borrow();
         unsafe {
-            device.destroy_graphics_pipeline(self.pipeline.take().unwrap());
-            device.destroy_pipeline_layout(self.pipeline_layout.take().unwrap());
+            device_state.device.destroy_graphics_pipeline(self.pipeline.take().unwrap());
+            device_state.device.destroy_pipeline_layout(self.pipeline_layout.take().unwrap());
         }
+
+        device_state.resource_tracker.borrow_mut().release_all(&self.resource_ids);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use graphics::constants::ModelPushConstants;
+    use std::path::Path;
+
+    fn vertex_shader() -> ShaderDesc {
+        ShaderDesc::new("VS_TEST", shader::Kind::Vertex, "shaders/test.vert")
+    }
+
+    #[test]
+    fn should_describe_shader() {
+        let shader_desc = ShaderDesc::new("FS_TEST", shader::Kind::Fragment, "shaders/test.frag");
+
+        assert_eq!("FS_TEST", shader_desc.name);
+        assert_eq!(shader::Kind::Fragment, shader_desc.kind);
+        assert!(shader_desc.spec_constants.is_empty());
+        assert_eq!(shader::CompileOptions::default(), shader_desc.compile_options);
+
+        let shader_desc = shader_desc
+            .spec_constant(0, 0.5f32)
+            .compile_options(shader::CompileOptions::release());
+
+        assert_eq!(1, shader_desc.spec_constants.specialization().constants.len());
+        assert_eq!(shader::CompileOptions::release(), shader_desc.compile_options);
+    }
+
+    #[test]
+    fn should_create_pipeline_desc_with_defaults() {
+        let desc = PipelineDesc::new(vertex_shader(), VertexLayout::new(12));
+
+        assert!(desc.fragment_shader.is_none());
+        assert_eq!(Primitive::TriangleList, desc.primitive);
+        assert_eq!(pso::Rasterizer::FILL, desc.rasterizer);
+        assert_eq!(pso::BlendState::ALPHA, desc.blend_state);
+        assert_eq!(pso::DepthStencilDesc::default(), desc.depth_stencil);
+        assert!(desc.push_constants.is_empty());
+    }
+
+    #[test]
+    fn should_configure_pipeline_desc() {
+        let desc = PipelineDesc::new(vertex_shader(), VertexLayout::new(12))
+            .fragment_shader(ShaderDesc::new("FS_TEST", shader::Kind::Fragment, "shaders/test.frag"))
+            .primitive(Primitive::LineList)
+            .cull_face(pso::Face::BACK)
+            .front_face(pso::FrontFace::Clockwise)
+            .polygon_mode(pso::PolygonMode::Point)
+            .blend_state(pso::BlendState::Off)
+            .push_constant_range(pso::ShaderStageFlags::FRAGMENT, 0..4)
+            .push_constant_block::<ModelPushConstants>(pso::ShaderStageFlags::VERTEX);
+
+        assert_eq!(Primitive::LineList, desc.primitive);
+        assert_eq!(pso::Face::BACK, desc.rasterizer.cull_face);
+        assert_eq!(pso::FrontFace::Clockwise, desc.rasterizer.front_face);
+        assert_eq!(pso::PolygonMode::Point, desc.rasterizer.polygon_mode);
+        assert_eq!(pso::BlendState::Off, desc.blend_state);
+
+        // Push constant blocks are placed after the ranges already reserved:
+        assert_eq!(
+            vec![
+                (pso::ShaderStageFlags::FRAGMENT, 0..4),
+                (pso::ShaderStageFlags::VERTEX, 4..4 + ModelPushConstants::word_count()),
+            ],
+            desc.push_constants
+        );
+
+        assert!(desc.uses_shader(Path::new("shaders/test.vert")));
+        assert!(desc.uses_shader(Path::new("shaders/test.frag")));
+        assert!(!desc.uses_shader(Path::new("shaders/other.frag")));
     }
 }
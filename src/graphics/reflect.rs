@@ -0,0 +1,909 @@
+use errors::*;
+use gfx_hal::format as f;
+use gfx_hal::pso;
+use graphics::pipeline::VertexLayout;
+use std::collections::HashMap;
+use std::ops::Range;
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+const HEADER_WORD_COUNT: usize = 5;
+
+// The opcodes of the instructions that describe a shader's interface:
+const OP_NAME: u16 = 5;
+const OP_ENTRY_POINT: u16 = 15;
+const OP_TYPE_INT: u16 = 21;
+const OP_TYPE_FLOAT: u16 = 22;
+const OP_TYPE_VECTOR: u16 = 23;
+const OP_TYPE_MATRIX: u16 = 24;
+const OP_TYPE_IMAGE: u16 = 25;
+const OP_TYPE_SAMPLER: u16 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u16 = 27;
+const OP_TYPE_ARRAY: u16 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u16 = 29;
+const OP_TYPE_STRUCT: u16 = 30;
+const OP_TYPE_POINTER: u16 = 32;
+const OP_CONSTANT: u16 = 43;
+const OP_VARIABLE: u16 = 59;
+const OP_DECORATE: u16 = 71;
+const OP_MEMBER_DECORATE: u16 = 72;
+
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BUILT_IN: u32 = 11;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+const STORAGE_CLASS_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_CLASS_INPUT: u32 = 1;
+const STORAGE_CLASS_UNIFORM: u32 = 2;
+const STORAGE_CLASS_PUSH_CONSTANT: u32 = 9;
+const STORAGE_CLASS_STORAGE_BUFFER: u32 = 12;
+
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+/// A descriptor that a shader reads through `layout(set = S, binding = B)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReflectedBinding {
+    pub name: String,
+    pub set: u32,
+    pub binding: u32,
+    pub ty: pso::DescriptorType,
+    pub count: usize,
+    pub stage_flags: pso::ShaderStageFlags,
+}
+
+/// A vertex shader input, with the format of its attribute if it has a single-location scalar or vector type
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReflectedInput {
+    pub name: String,
+    pub location: u32,
+    pub format: Option<f::Format>,
+}
+
+/// The `layout(push_constant)` block of a shader stage, measured in bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReflectedPushConstants {
+    pub name: String,
+    pub stage_flags: pso::ShaderStageFlags,
+    pub range: Range<u32>,
+}
+
+/// The interface of a single shader stage, read from its SPIR-V
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShaderReflection {
+    pub stage_flags: pso::ShaderStageFlags,
+    pub bindings: Vec<ReflectedBinding>,
+    pub push_constants: Option<ReflectedPushConstants>,
+    pub inputs: Vec<ReflectedInput>,
+}
+
+impl ShaderReflection {
+    pub fn from_spirv(spirv: &[u8]) -> Result<Self> {
+        Module::parse(spirv)?.reflect()
+    }
+}
+
+/// The combined interface of every shader stage in a pipeline
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PipelineReflection {
+    pub bindings: Vec<ReflectedBinding>,
+    pub push_constants: Vec<ReflectedPushConstants>,
+    pub vertex_inputs: Vec<ReflectedInput>,
+}
+
+impl PipelineReflection {
+    pub fn new(stages: &[ShaderReflection]) -> Self {
+        let mut reflection = PipelineReflection::default();
+
+        for stage in stages {
+            for binding in &stage.bindings {
+                let existing = reflection
+                    .bindings
+                    .iter_mut()
+                    .find(|existing| existing.set == binding.set && existing.binding == binding.binding);
+
+                match existing {
+                    Some(existing) => {
+                        existing.stage_flags |= binding.stage_flags;
+                        existing.count = existing.count.max(binding.count);
+                    }
+                    None => reflection.bindings.push(binding.clone()),
+                }
+            }
+
+            reflection.push_constants.extend(stage.push_constants.clone());
+
+            if stage.stage_flags == pso::ShaderStageFlags::VERTEX {
+                reflection.vertex_inputs = stage.inputs.clone();
+            }
+        }
+
+        reflection.bindings.sort_by_key(|binding| (binding.set, binding.binding));
+
+        reflection
+    }
+
+    /// The bindings a set layout needs to hold everything the shaders read from the set
+    pub fn set_layout_bindings(&self, set: u32) -> Vec<pso::DescriptorSetLayoutBinding> {
+        self.bindings
+            .iter()
+            .filter(|binding| binding.set == set)
+            .map(|binding| pso::DescriptorSetLayoutBinding {
+                binding: binding.binding,
+                ty: binding.ty,
+                count: binding.count,
+                stage_flags: binding.stage_flags,
+                immutable_samplers: false,
+            })
+            .collect()
+    }
+
+    /// The push constant ranges, in 32-bit words, covering the block of every stage.
+    /// Stages whose blocks cover the same bytes share a range, as the block can then be pushed to them at once.
+    pub fn push_constant_ranges(&self) -> Vec<(pso::ShaderStageFlags, Range<u32>)> {
+        let mut ranges: Vec<(pso::ShaderStageFlags, Range<u32>)> = Vec::new();
+
+        for push_constants in &self.push_constants {
+            let range = push_constants.range.start / 4..(push_constants.range.end + 3) / 4;
+
+            match ranges.iter_mut().find(|&&mut (_, ref existing)| *existing == range) {
+                Some(&mut (ref mut stage_flags, _)) => *stage_flags |= push_constants.stage_flags,
+                None => ranges.push((push_constants.stage_flags, range)),
+            }
+        }
+
+        ranges
+    }
+
+    /// Checks that the set layouts, indexed by set number, hold a compatible binding for everything the shaders read
+    pub fn validate_set_layouts(&self, set_layouts: &[&[pso::DescriptorSetLayoutBinding]]) -> Result<()> {
+        for binding in &self.bindings {
+            let set_layout = match set_layouts.get(binding.set as usize) {
+                Some(set_layout) => set_layout,
+                None => bail!(ErrorKind::ShaderInterfaceMismatch(format!(
+                    "'{}' is in descriptor set {}, but the pipeline only has {} set layout(s)",
+                    binding.name,
+                    binding.set,
+                    set_layouts.len()
+                ))),
+            };
+
+            let layout_binding = match set_layout.iter().find(|layout_binding| layout_binding.binding == binding.binding) {
+                Some(layout_binding) => layout_binding,
+                None => bail!(ErrorKind::ShaderInterfaceMismatch(format!(
+                    "'{}' is at binding {} of set {}, which the set layout doesn't have",
+                    binding.name, binding.binding, binding.set
+                ))),
+            };
+
+            if !is_compatible(binding.ty, layout_binding.ty) {
+                bail!(ErrorKind::ShaderInterfaceMismatch(format!(
+                    "'{}' at binding {} of set {} is a {:?} in the shader, but a {:?} in the set layout",
+                    binding.name, binding.binding, binding.set, binding.ty, layout_binding.ty
+                )));
+            }
+
+            if layout_binding.count < binding.count {
+                bail!(ErrorKind::ShaderInterfaceMismatch(format!(
+                    "'{}' at binding {} of set {} has {} descriptor(s) in the shader, but {} in the set layout",
+                    binding.name, binding.binding, binding.set, binding.count, layout_binding.count
+                )));
+            }
+
+            if !layout_binding.stage_flags.contains(binding.stage_flags) {
+                bail!(ErrorKind::ShaderInterfaceMismatch(format!(
+                    "'{}' at binding {} of set {} is read by the {:?} stages, but only visible to {:?}",
+                    binding.name, binding.binding, binding.set, binding.stage_flags, layout_binding.stage_flags
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every push constant block lies within a range, measured in words, reserved for its stage
+    pub fn validate_push_constants(&self, push_constants: &[(pso::ShaderStageFlags, Range<u32>)]) -> Result<()> {
+        for block in &self.push_constants {
+            let is_reserved = push_constants.iter().any(|&(stage_flags, ref range)| {
+                stage_flags.contains(block.stage_flags) && range.start * 4 <= block.range.start && block.range.end <= range.end * 4
+            });
+
+            if !is_reserved {
+                bail!(ErrorKind::ShaderInterfaceMismatch(format!(
+                    "push constant block '{}' of the {:?} stage uses bytes {:?}, which aren't reserved for it",
+                    block.name, block.stage_flags, block.range
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every vertex shader input is fed by an attribute of the same format
+    pub fn validate_vertex_layout(&self, vertex_layout: &VertexLayout) -> Result<()> {
+        for input in &self.vertex_inputs {
+            let attribute = match vertex_layout
+                .attributes
+                .iter()
+                .find(|attribute| attribute.location == input.location)
+            {
+                Some(attribute) => attribute,
+                None => bail!(ErrorKind::ShaderInterfaceMismatch(format!(
+                    "vertex input '{}' at location {} has no attribute in the vertex layout",
+                    input.name, input.location
+                ))),
+            };
+
+            match input.format {
+                Some(format) if format != attribute.format => bail!(ErrorKind::ShaderInterfaceMismatch(format!(
+                    "vertex input '{}' at location {} is a {:?} in the shader, but a {:?} in the vertex layout",
+                    input.name, input.location, format, attribute.format
+                ))),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Converts uniform and storage buffer bindings into their dynamic equivalents, for buffers bound with a dynamic offset
+pub fn dynamic_binding(mut binding: pso::DescriptorSetLayoutBinding) -> pso::DescriptorSetLayoutBinding {
+    binding.ty = match binding.ty {
+        pso::DescriptorType::UniformBuffer => pso::DescriptorType::UniformBufferDynamic,
+        pso::DescriptorType::StorageBuffer => pso::DescriptorType::StorageBufferDynamic,
+        ty => ty,
+    };
+
+    binding
+}
+
+/// A shader can't tell whether its buffers are bound with dynamic offsets, so either kind of descriptor will do
+fn is_compatible(shader_ty: pso::DescriptorType, layout_ty: pso::DescriptorType) -> bool {
+    shader_ty == layout_ty
+        || (shader_ty == pso::DescriptorType::UniformBuffer && layout_ty == pso::DescriptorType::UniformBufferDynamic)
+        || (shader_ty == pso::DescriptorType::StorageBuffer && layout_ty == pso::DescriptorType::StorageBufferDynamic)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ScalarKind {
+    Float,
+    Int,
+    Uint,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Type {
+    Scalar { kind: ScalarKind, width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: u32, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray { element: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { storage_class: u32, pointee: u32 },
+}
+
+#[derive(Debug, Clone, Default)]
+struct Decorations {
+    buffer_block: bool,
+    built_in: bool,
+    array_stride: Option<u32>,
+    matrix_stride: Option<u32>,
+    location: Option<u32>,
+    binding: Option<u32>,
+    descriptor_set: Option<u32>,
+    offset: Option<u32>,
+}
+
+impl Decorations {
+    fn set(&mut self, decoration: u32, operand: Option<u32>) {
+        match decoration {
+            DECORATION_BUFFER_BLOCK => self.buffer_block = true,
+            DECORATION_BUILT_IN => self.built_in = true,
+            DECORATION_ARRAY_STRIDE => self.array_stride = operand,
+            DECORATION_MATRIX_STRIDE => self.matrix_stride = operand,
+            DECORATION_LOCATION => self.location = operand,
+            DECORATION_BINDING => self.binding = operand,
+            DECORATION_DESCRIPTOR_SET => self.descriptor_set = operand,
+            DECORATION_OFFSET => self.offset = operand,
+            _ => {}
+        }
+    }
+}
+
+struct Variable {
+    id: u32,
+    type_id: u32,
+    storage_class: u32,
+}
+
+/// The parts of a SPIR-V module needed to find its interface. Everything else, including function bodies, is skipped.
+#[derive(Default)]
+struct Module {
+    execution_model: Option<u32>,
+    names: HashMap<u32, String>,
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+    variables: Vec<Variable>,
+    decorations: HashMap<u32, Decorations>,
+    member_decorations: HashMap<(u32, u32), Decorations>,
+}
+
+impl Module {
+    fn parse(spirv: &[u8]) -> Result<Self> {
+        if spirv.len() % 4 != 0 {
+            bail!(ErrorKind::InvalidSpirv("length is not a multiple of 4 bytes".to_string()));
+        }
+
+        let words: Vec<u32> = spirv
+            .chunks(4)
+            .map(|bytes| u32::from(bytes[0]) | u32::from(bytes[1]) << 8 | u32::from(bytes[2]) << 16 | u32::from(bytes[3]) << 24)
+            .collect();
+
+        if words.len() < HEADER_WORD_COUNT || words[0] != SPIRV_MAGIC {
+            bail!(ErrorKind::InvalidSpirv("missing SPIR-V header".to_string()));
+        }
+
+        let mut module = Module::default();
+        let mut position = HEADER_WORD_COUNT;
+
+        while position < words.len() {
+            let word_count = (words[position] >> 16) as usize;
+            let opcode = (words[position] & 0xffff) as u16;
+
+            if word_count == 0 || position + word_count > words.len() {
+                bail!(ErrorKind::InvalidSpirv(format!("malformed instruction at word {}", position)));
+            }
+
+            module.parse_instruction(opcode, &words[position + 1..position + word_count])?;
+
+            position += word_count;
+        }
+
+        Ok(module)
+    }
+
+    fn parse_instruction(&mut self, opcode: u16, operands: &[u32]) -> Result<()> {
+        let operand = |index: usize| match operands.get(index) {
+            Some(&operand) => Ok(operand),
+            None => Err(Error::from(ErrorKind::InvalidSpirv(format!(
+                "instruction {} is missing operands",
+                opcode
+            )))),
+        };
+
+        match opcode {
+            OP_NAME => {
+                self.names.insert(operand(0)?, parse_string(&operands[1..]));
+            }
+            OP_ENTRY_POINT => {
+                if self.execution_model.is_none() {
+                    self.execution_model = Some(operand(0)?);
+                }
+            }
+            OP_TYPE_INT => {
+                let kind = if operand(2)? == 0 { ScalarKind::Uint } else { ScalarKind::Int };
+                self.types.insert(operand(0)?, Type::Scalar { kind, width: operand(1)? });
+            }
+            OP_TYPE_FLOAT => {
+                self.types.insert(
+                    operand(0)?,
+                    Type::Scalar {
+                        kind: ScalarKind::Float,
+                        width: operand(1)?,
+                    },
+                );
+            }
+            OP_TYPE_VECTOR => {
+                self.types.insert(
+                    operand(0)?,
+                    Type::Vector {
+                        component: operand(1)?,
+                        count: operand(2)?,
+                    },
+                );
+            }
+            OP_TYPE_MATRIX => {
+                self.types.insert(
+                    operand(0)?,
+                    Type::Matrix {
+                        column: operand(1)?,
+                        count: operand(2)?,
+                    },
+                );
+            }
+            OP_TYPE_IMAGE => {
+                self.types.insert(
+                    operand(0)?,
+                    Type::Image {
+                        dim: operand(2)?,
+                        sampled: operand(6)?,
+                    },
+                );
+            }
+            OP_TYPE_SAMPLER => {
+                self.types.insert(operand(0)?, Type::Sampler);
+            }
+            OP_TYPE_SAMPLED_IMAGE => {
+                self.types.insert(operand(0)?, Type::SampledImage);
+            }
+            OP_TYPE_ARRAY => {
+                let length = match self.constants.get(&operand(2)?) {
+                    Some(&length) => length,
+                    None => bail!(ErrorKind::InvalidSpirv("array length is not a constant".to_string())),
+                };
+
+                self.types.insert(
+                    operand(0)?,
+                    Type::Array {
+                        element: operand(1)?,
+                        length,
+                    },
+                );
+            }
+            OP_TYPE_RUNTIME_ARRAY => {
+                self.types.insert(operand(0)?, Type::RuntimeArray { element: operand(1)? });
+            }
+            OP_TYPE_STRUCT => {
+                self.types.insert(
+                    operand(0)?,
+                    Type::Struct {
+                        members: operands[1..].to_vec(),
+                    },
+                );
+            }
+            OP_TYPE_POINTER => {
+                self.types.insert(
+                    operand(0)?,
+                    Type::Pointer {
+                        storage_class: operand(1)?,
+                        pointee: operand(2)?,
+                    },
+                );
+            }
+            OP_CONSTANT => {
+                // Only the low word is kept, which is all an array length needs:
+                self.constants.insert(operand(1)?, operand(2)?);
+            }
+            OP_VARIABLE => {
+                self.variables.push(Variable {
+                    type_id: operand(0)?,
+                    id: operand(1)?,
+                    storage_class: operand(2)?,
+                });
+            }
+            OP_DECORATE => {
+                let decoration = operand(1)?;
+                let decoration_operand = operands.get(2).cloned();
+
+                self.decorations.entry(operand(0)?).or_default().set(decoration, decoration_operand);
+            }
+            OP_MEMBER_DECORATE => {
+                let decoration = operand(2)?;
+                let decoration_operand = operands.get(3).cloned();
+
+                self.member_decorations
+                    .entry((operand(0)?, operand(1)?))
+                    .or_default()
+                    .set(decoration, decoration_operand);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn reflect(&self) -> Result<ShaderReflection> {
+        let stage_flags = match self.execution_model {
+            Some(0) => pso::ShaderStageFlags::VERTEX,
+            Some(1) => pso::ShaderStageFlags::HULL,
+            Some(2) => pso::ShaderStageFlags::DOMAIN,
+            Some(3) => pso::ShaderStageFlags::GEOMETRY,
+            Some(4) => pso::ShaderStageFlags::FRAGMENT,
+            Some(5) => pso::ShaderStageFlags::COMPUTE,
+            Some(model) => bail!(ErrorKind::InvalidSpirv(format!("unsupported execution model {}", model))),
+            None => bail!(ErrorKind::InvalidSpirv("no entry point".to_string())),
+        };
+
+        let mut reflection = ShaderReflection {
+            stage_flags,
+            bindings: Vec::new(),
+            push_constants: None,
+            inputs: Vec::new(),
+        };
+
+        let no_decorations = Decorations::default();
+
+        for variable in &self.variables {
+            let decorations = self.decorations.get(&variable.id).unwrap_or(&no_decorations);
+
+            let pointee = match self.types.get(&variable.type_id) {
+                Some(&Type::Pointer { pointee, .. }) => pointee,
+                _ => bail!(ErrorKind::InvalidSpirv(format!("variable {} is not a pointer", variable.id))),
+            };
+
+            match variable.storage_class {
+                STORAGE_CLASS_UNIFORM_CONSTANT | STORAGE_CLASS_UNIFORM | STORAGE_CLASS_STORAGE_BUFFER => {
+                    let (element, count) = self.array_element(pointee);
+
+                    if let Some(ty) = self.descriptor_type(variable.storage_class, element) {
+                        reflection.bindings.push(ReflectedBinding {
+                            name: self.name(variable.id, element),
+                            set: decorations.descriptor_set.unwrap_or(0),
+                            binding: decorations.binding.unwrap_or(0),
+                            ty,
+                            count,
+                            stage_flags,
+                        });
+                    }
+                }
+                STORAGE_CLASS_PUSH_CONSTANT => {
+                    reflection.push_constants = Some(ReflectedPushConstants {
+                        name: self.name(variable.id, pointee),
+                        stage_flags,
+                        range: self.struct_range(pointee),
+                    });
+                }
+                STORAGE_CLASS_INPUT if stage_flags == pso::ShaderStageFlags::VERTEX && !decorations.built_in => {
+                    if let Some(location) = decorations.location {
+                        reflection.inputs.push(ReflectedInput {
+                            name: self.name(variable.id, pointee),
+                            location,
+                            format: self.vertex_format(pointee),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        reflection.bindings.sort_by_key(|binding| (binding.set, binding.binding));
+        reflection.inputs.sort_by_key(|input| input.location);
+
+        Ok(reflection)
+    }
+
+    /// The name of a variable, or of its type for blocks declared without an instance name
+    fn name(&self, id: u32, type_id: u32) -> String {
+        self.names
+            .get(&id)
+            .filter(|name| !name.is_empty())
+            .or_else(|| self.names.get(&type_id))
+            .cloned()
+            .unwrap_or_else(|| format!("%{}", id))
+    }
+
+    /// Unwraps arrays of descriptors, returning the element type and the number of descriptors
+    fn array_element(&self, type_id: u32) -> (u32, usize) {
+        match self.types.get(&type_id) {
+            Some(&Type::Array { element, length }) => {
+                let (element, count) = self.array_element(element);
+                (element, count * length as usize)
+            }
+            // The number of descriptors in a runtime array is chosen by the set layout, which must have at least one:
+            Some(&Type::RuntimeArray { element }) => self.array_element(element),
+            _ => (type_id, 1),
+        }
+    }
+
+    fn descriptor_type(&self, storage_class: u32, type_id: u32) -> Option<pso::DescriptorType> {
+        let ty = self.types.get(&type_id)?;
+
+        match (storage_class, ty) {
+            (STORAGE_CLASS_UNIFORM_CONSTANT, &Type::Sampler) => Some(pso::DescriptorType::Sampler),
+            (STORAGE_CLASS_UNIFORM_CONSTANT, &Type::SampledImage) => Some(pso::DescriptorType::CombinedImageSampler),
+            (STORAGE_CLASS_UNIFORM_CONSTANT, &Type::Image { dim, sampled }) => Some(match (dim, sampled) {
+                (DIM_SUBPASS_DATA, _) => pso::DescriptorType::InputAttachment,
+                (DIM_BUFFER, 2) => pso::DescriptorType::StorageTexelBuffer,
+                (DIM_BUFFER, _) => pso::DescriptorType::UniformTexelBuffer,
+                (_, 2) => pso::DescriptorType::StorageImage,
+                _ => pso::DescriptorType::SampledImage,
+            }),
+            (STORAGE_CLASS_UNIFORM, &Type::Struct { .. }) => {
+                let is_buffer_block = self.decorations.get(&type_id).map_or(false, |decorations| decorations.buffer_block);
+
+                Some(if is_buffer_block {
+                    pso::DescriptorType::StorageBuffer
+                } else {
+                    pso::DescriptorType::UniformBuffer
+                })
+            }
+            (STORAGE_CLASS_STORAGE_BUFFER, &Type::Struct { .. }) => Some(pso::DescriptorType::StorageBuffer),
+            _ => None,
+        }
+    }
+
+    /// The bytes spanned by the members of a block, from the lowest member offset to the end of the last member
+    fn struct_range(&self, type_id: u32) -> Range<u32> {
+        let members = match self.types.get(&type_id) {
+            Some(&Type::Struct { ref members }) => members,
+            _ => return 0..0,
+        };
+
+        let mut range: Option<Range<u32>> = None;
+
+        for (index, &member) in members.iter().enumerate() {
+            let decorations = self.member_decorations.get(&(type_id, index as u32));
+            let offset = decorations.and_then(|decorations| decorations.offset).unwrap_or(0);
+            let end = offset + self.size_of(member, decorations.and_then(|decorations| decorations.matrix_stride));
+
+            range = Some(match range {
+                Some(range) => range.start.min(offset)..range.end.max(end),
+                None => offset..end,
+            });
+        }
+
+        range.unwrap_or(0..0)
+    }
+
+    fn size_of(&self, type_id: u32, matrix_stride: Option<u32>) -> u32 {
+        match self.types.get(&type_id) {
+            Some(&Type::Scalar { width, .. }) => width / 8,
+            Some(&Type::Vector { component, count }) => count * self.size_of(component, None),
+            Some(&Type::Matrix { column, count }) => count * matrix_stride.unwrap_or_else(|| self.size_of(column, None)),
+            Some(&Type::Array { element, length }) => {
+                let array_stride = self.decorations.get(&type_id).and_then(|decorations| decorations.array_stride);
+
+                length * array_stride.unwrap_or_else(|| self.size_of(element, matrix_stride))
+            }
+            Some(&Type::Struct { .. }) => self.struct_range(type_id).end,
+            _ => 0,
+        }
+    }
+
+    fn vertex_format(&self, type_id: u32) -> Option<f::Format> {
+        let (kind, count) = match *self.types.get(&type_id)? {
+            Type::Scalar { kind, width: 32 } => (kind, 1),
+            Type::Vector { component, count } => match *self.types.get(&component)? {
+                Type::Scalar { kind, width: 32 } => (kind, count),
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        Some(match (kind, count) {
+            (ScalarKind::Float, 1) => f::Format::R32Float,
+            (ScalarKind::Float, 2) => f::Format::Rg32Float,
+            (ScalarKind::Float, 3) => f::Format::Rgb32Float,
+            (ScalarKind::Float, 4) => f::Format::Rgba32Float,
+            (ScalarKind::Int, 1) => f::Format::R32Int,
+            (ScalarKind::Int, 2) => f::Format::Rg32Int,
+            (ScalarKind::Int, 3) => f::Format::Rgb32Int,
+            (ScalarKind::Int, 4) => f::Format::Rgba32Int,
+            (ScalarKind::Uint, 1) => f::Format::R32Uint,
+            (ScalarKind::Uint, 2) => f::Format::Rg32Uint,
+            (ScalarKind::Uint, 3) => f::Format::Rgb32Uint,
+            (ScalarKind::Uint, 4) => f::Format::Rgba32Uint,
+            _ => return None,
+        })
+    }
+}
+
+/// Decodes a nul-terminated UTF-8 literal, packed four bytes to a word
+fn parse_string(words: &[u32]) -> String {
+    let bytes: Vec<u8> = words
+        .iter()
+        .flat_map(|word| (0..4).map(move |shift| (word >> (shift * 8)) as u8))
+        .take_while(|&byte| byte != 0)
+        .collect();
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    #[cfg(feature = "runtime-shaders")]
+    use graphics::{shader, Vertex};
+
+    /// Assembles a module by hand, so that reflection can be tested without the shader compiler
+    struct Assembler {
+        words: Vec<u32>,
+    }
+
+    impl Assembler {
+        fn new() -> Self {
+            Assembler {
+                words: vec![SPIRV_MAGIC, 0x0001_0000, 0, 100, 0],
+            }
+        }
+
+        fn op(mut self, opcode: u16, operands: &[u32]) -> Self {
+            self.words.push(((operands.len() as u32 + 1) << 16) | u32::from(opcode));
+            self.words.extend_from_slice(operands);
+            self
+        }
+
+        fn name(self, id: u32, name: &str) -> Self {
+            let mut bytes = name.as_bytes().to_vec();
+            bytes.resize((name.len() / 4 + 1) * 4, 0);
+
+            let mut operands = vec![id];
+            operands.extend(
+                bytes
+                    .chunks(4)
+                    .map(|b| u32::from(b[0]) | u32::from(b[1]) << 8 | u32::from(b[2]) << 16 | u32::from(b[3]) << 24),
+            );
+
+            self.op(OP_NAME, &operands)
+        }
+
+        fn bytes(&self) -> Vec<u8> {
+            self.words
+                .iter()
+                .flat_map(|word| (0..4).map(move |shift| (word >> (shift * 8)) as u8))
+                .collect()
+        }
+    }
+
+    /// A vertex shader with a vec3 input at location 0, a uniform block at set 1 binding 0,
+    /// an array of two textures at set 0 binding 2 and a push constant block holding a mat4
+    fn vertex_module() -> Vec<u8> {
+        Assembler::new()
+            .op(OP_ENTRY_POINT, &[0, 1, 0x6e69_616d, 0])
+            .name(10, "a_Position")
+            .name(11, "camera")
+            .name(12, "u_Textures")
+            .name(13, "push")
+            .op(OP_DECORATE, &[10, DECORATION_LOCATION, 0])
+            .op(OP_DECORATE, &[11, DECORATION_DESCRIPTOR_SET, 1])
+            .op(OP_DECORATE, &[11, DECORATION_BINDING, 0])
+            .op(OP_DECORATE, &[12, DECORATION_DESCRIPTOR_SET, 0])
+            .op(OP_DECORATE, &[12, DECORATION_BINDING, 2])
+            .op(OP_MEMBER_DECORATE, &[30, 0, DECORATION_OFFSET, 0])
+            .op(OP_MEMBER_DECORATE, &[30, 0, DECORATION_MATRIX_STRIDE, 16])
+            .op(OP_TYPE_FLOAT, &[20, 32])
+            .op(OP_TYPE_VECTOR, &[21, 20, 3])
+            .op(OP_TYPE_VECTOR, &[22, 20, 4])
+            .op(OP_TYPE_MATRIX, &[23, 22, 4])
+            .op(OP_TYPE_INT, &[24, 32, 0])
+            .op(OP_CONSTANT, &[24, 25, 2])
+            .op(OP_TYPE_IMAGE, &[26, 20, 1, 0, 0, 0, 1, 0])
+            .op(OP_TYPE_ARRAY, &[27, 26, 25])
+            .op(OP_TYPE_STRUCT, &[30, 23])
+            .op(OP_TYPE_POINTER, &[40, STORAGE_CLASS_INPUT, 21])
+            .op(OP_TYPE_POINTER, &[41, STORAGE_CLASS_UNIFORM, 30])
+            .op(OP_TYPE_POINTER, &[42, STORAGE_CLASS_UNIFORM_CONSTANT, 27])
+            .op(OP_TYPE_POINTER, &[43, STORAGE_CLASS_PUSH_CONSTANT, 30])
+            .op(OP_VARIABLE, &[40, 10, STORAGE_CLASS_INPUT])
+            .op(OP_VARIABLE, &[41, 11, STORAGE_CLASS_UNIFORM])
+            .op(OP_VARIABLE, &[42, 12, STORAGE_CLASS_UNIFORM_CONSTANT])
+            .op(OP_VARIABLE, &[43, 13, STORAGE_CLASS_PUSH_CONSTANT])
+            .bytes()
+    }
+
+    #[cfg(feature = "runtime-shaders")]
+    fn compile_and_reflect(file_name: &str, kind: shader::Kind) -> ShaderReflection {
+        let file_path = format!("{}/{}", shader::SHADER_DIR, file_name);
+        let source = ::std::fs::read_to_string(&file_path).unwrap();
+        let spirv = shader::Loader::compile_with_options(&file_path, &kind, &source, &shader::CompileOptions::debug()).unwrap();
+
+        ShaderReflection::from_spirv(&spirv).unwrap()
+    }
+
+    fn layout_binding(binding: u32, ty: pso::DescriptorType, count: usize) -> pso::DescriptorSetLayoutBinding {
+        pso::DescriptorSetLayoutBinding {
+            binding,
+            ty,
+            count,
+            stage_flags: pso::ShaderStageFlags::VERTEX,
+            immutable_samplers: false,
+        }
+    }
+
+    #[test]
+    fn should_reflect_shader_interface() {
+        let reflection = ShaderReflection::from_spirv(&vertex_module()).unwrap();
+
+        assert_eq!(pso::ShaderStageFlags::VERTEX, reflection.stage_flags);
+
+        assert_eq!(
+            vec![
+                ReflectedBinding {
+                    name: "u_Textures".to_string(),
+                    set: 0,
+                    binding: 2,
+                    ty: pso::DescriptorType::SampledImage,
+                    count: 2,
+                    stage_flags: pso::ShaderStageFlags::VERTEX,
+                },
+                ReflectedBinding {
+                    name: "camera".to_string(),
+                    set: 1,
+                    binding: 0,
+                    ty: pso::DescriptorType::UniformBuffer,
+                    count: 1,
+                    stage_flags: pso::ShaderStageFlags::VERTEX,
+                },
+            ],
+            reflection.bindings
+        );
+
+        assert_eq!(Some(0..64), reflection.push_constants.map(|push_constants| push_constants.range));
+
+        assert_eq!(
+            vec![ReflectedInput {
+                name: "a_Position".to_string(),
+                location: 0,
+                format: Some(f::Format::Rgb32Float),
+            }],
+            reflection.inputs
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "runtime-shaders")]
+    fn should_reflect_compiled_shaders() {
+        let reflection = PipelineReflection::new(&[
+            compile_and_reflect("quad_150.vert", shader::Kind::Vertex),
+            compile_and_reflect("quad_150.frag", shader::Kind::Fragment),
+        ]);
+
+        let bindings: Vec<_> = reflection
+            .bindings
+            .iter()
+            .map(|binding| (binding.set, binding.binding, binding.ty, binding.stage_flags))
+            .collect();
+
+        assert_eq!(
+            vec![
+                (0, 0, pso::DescriptorType::SampledImage, pso::ShaderStageFlags::FRAGMENT),
+                (0, 1, pso::DescriptorType::Sampler, pso::ShaderStageFlags::FRAGMENT),
+                (1, 0, pso::DescriptorType::UniformBuffer, pso::ShaderStageFlags::VERTEX),
+            ],
+            bindings
+        );
+
+        assert_eq!(vec![(pso::ShaderStageFlags::VERTEX, 0..16)], reflection.push_constant_ranges());
+        assert!(reflection.validate_vertex_layout(&VertexLayout::from_format::<Vertex>()).is_ok());
+    }
+
+    #[test]
+    fn should_reject_invalid_spirv() {
+        assert!(ShaderReflection::from_spirv(&[0, 1, 2]).is_err());
+        assert!(ShaderReflection::from_spirv(&[0; 20]).is_err());
+    }
+
+    #[test]
+    fn should_validate_against_pipeline_layout() {
+        let reflection = PipelineReflection::new(&[ShaderReflection::from_spirv(&vertex_module()).unwrap()]);
+
+        let textures = [layout_binding(2, pso::DescriptorType::SampledImage, 2)];
+        let camera = [layout_binding(0, pso::DescriptorType::UniformBufferDynamic, 1)];
+
+        assert!(reflection.validate_set_layouts(&[&textures[..], &camera[..]]).is_ok());
+        assert!(reflection.validate_set_layouts(&[&textures[..]]).is_err());
+        assert!(reflection
+            .validate_set_layouts(&[&[layout_binding(2, pso::DescriptorType::SampledImage, 1)][..], &camera[..]])
+            .is_err());
+        assert!(reflection
+            .validate_set_layouts(&[&textures[..], &[layout_binding(0, pso::DescriptorType::StorageBuffer, 1)][..]])
+            .is_err());
+
+        assert_eq!(vec![(pso::ShaderStageFlags::VERTEX, 0..16)], reflection.push_constant_ranges());
+        assert!(reflection.validate_push_constants(&reflection.push_constant_ranges()).is_ok());
+        assert!(reflection
+            .validate_push_constants(&[(pso::ShaderStageFlags::VERTEX, 0..8)])
+            .is_err());
+        assert!(reflection
+            .validate_push_constants(&[(pso::ShaderStageFlags::FRAGMENT, 0..16)])
+            .is_err());
+
+        assert!(reflection
+            .validate_vertex_layout(&VertexLayout::new(12).with_attribute(0, f::Format::Rgb32Float, 0))
+            .is_ok());
+        assert!(reflection
+            .validate_vertex_layout(&VertexLayout::new(8).with_attribute(0, f::Format::Rg32Float, 0))
+            .is_err());
+        assert!(reflection
+            .validate_vertex_layout(&VertexLayout::new(12).with_attribute(1, f::Format::Rgb32Float, 0))
+            .is_err());
+    }
+}
@@ -1,426 +1,751 @@
+use camera::Camera;
+use errors::*;
 use gfx_hal::image as gfx_image;
 use gfx_hal::pso::*;
 use gfx_hal::*;
 use graphics::backend::BackendState;
 use graphics::backend::SurfaceTrait;
-use graphics::buffer::BufferState;
+use graphics::constants::ModelPushConstants;
+use graphics::depth::DepthState;
 use graphics::descriptor::DescriptorSetLayout;
 use graphics::device::DeviceState;
 use graphics::framebuffer::FramebufferState;
-use graphics::image::ImageState;
-use graphics::image::Loader;
-use graphics::pipeline::PipelineState;
+use graphics::image::ColorSpace;
+use graphics::model::ModelState;
+use graphics::pipeline::{PipelineDesc, PipelineState, ShaderDesc, VertexLayout};
+use graphics::reflect;
+use graphics::sampler::SamplerDesc;
+use graphics::shader;
+use graphics::streaming::{TextureRegion, TextureStreamer};
 use graphics::swapchain::SwapchainState;
-use graphics::uniform::Uniform;
-use graphics::window::WindowState;
+use graphics::target::{RenderTarget, RenderTargetDesc};
+use graphics::texture::{TextureHandle, TextureManager};
+use graphics::tracker::{LeakCheck, ResourceId};
+use graphics::uniform::{CameraUniformBlock, DynamicUniform};
+use graphics::upload::UploadBatch;
+use graphics::watcher::ShaderWatcher;
 use graphics::Vertex;
+use material::TextureSlot;
+use scene::Node;
 use std::cell::RefCell;
+use std::path::Path;
 use std::rc::Rc;
+use winit::dpi::LogicalSize;
 
 const CLEAR_COLOR: [f32; 4] = [0.255, 0.412, 0.882, 1.0];
 
-const QUAD: [Vertex; 4] = [
-  Vertex {
-    a_Position: [-1.0, -1.0, 0.0],
-    a_TexCoord: [0.0, 0.0],
-  },
-  Vertex {
-    a_Position: [-1.0, 1.0, 0.0],
-    a_TexCoord: [0.0, 1.0],
-  },
-  Vertex {
-    a_Position: [1.0, 1.0, 0.0],
-    a_TexCoord: [1.0, 1.0],
-  },
-  Vertex {
-    a_Position: [1.0, -1.0, 0.0],
-    a_TexCoord: [1.0, 0.0],
-  },
-];
+const CLEAR_DEPTH: f32 = 1.0;
 
-const QUAD_INDICES: [u16; 6] = [0, 1, 2, 2, 3, 0];
+/// Drawn on meshes without a diffuse texture of their own
+const DEFAULT_TEXTURE_PATH: &str = "resources/uv_grid.jpg";
+
+/// The descriptor set holding `CameraBlock`, from `shaders/include/camera.glsl`
+const CAMERA_SET: u32 = 1;
+
+/// The `scale` specialization constant of `quad_150.vert`, which every vertex position is multiplied by
+const MODEL_SCALE: f32 = 0.5;
+
+/// Nearer fragments overwrite those drawn before them
+const DEPTH_STENCIL: pso::DepthStencilDesc = pso::DepthStencilDesc {
+  depth: pso::DepthTest::On {
+    fun: pso::Comparison::LessEqual,
+    write: true,
+  },
+  depth_bounds: false,
+  stencil: pso::StencilTest::Off,
+};
 
 pub struct RenderPassState<B: Backend> {
   pub render_pass: Option<B::RenderPass>,
   device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+  resource_id: ResourceId,
 }
 
 impl<B: Backend> RenderPassState<B> {
-  pub unsafe fn new(swapchain_state: &SwapchainState<B>, device_state: Rc<RefCell<DeviceState<B, Graphics>>>) -> Self {
-    let attachment = pass::Attachment {
-      format: Some(swapchain_state.format),
+  pub unsafe fn new(
+    swapchain_state: &SwapchainState<B>,
+    depth_state: &DepthState<B>,
+    device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+  ) -> Result<Self> {
+    let dependency = pass::SubpassDependency {
+      passes: pass::SubpassRef::External..pass::SubpassRef::Pass(0),
+      stages: (PipelineStage::COLOR_ATTACHMENT_OUTPUT | PipelineStage::EARLY_FRAGMENT_TESTS)
+        ..(PipelineStage::COLOR_ATTACHMENT_OUTPUT | PipelineStage::EARLY_FRAGMENT_TESTS),
+      accesses: gfx_image::Access::empty()
+        ..(gfx_image::Access::COLOR_ATTACHMENT_READ
+          | gfx_image::Access::COLOR_ATTACHMENT_WRITE
+          | gfx_image::Access::DEPTH_STENCIL_ATTACHMENT_READ
+          | gfx_image::Access::DEPTH_STENCIL_ATTACHMENT_WRITE),
+    };
+
+    Self::create(
+      swapchain_state.format,
+      Some(depth_state.format),
+      gfx_image::Layout::Present,
+      &[dependency],
+      device_state,
+    )
+  }
+
+  /// Creates a render pass that leaves its colour attachment ready to be sampled by later passes,
+  /// with an optional depth attachment that is discarded once the pass ends
+  pub unsafe fn new_offscreen(
+    color_format: format::Format,
+    depth_format: Option<format::Format>,
+    device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+  ) -> Result<Self> {
+    let dependencies = [
+      // Frames submitted earlier may still be sampling the attachment, and must finish before it is cleared:
+      pass::SubpassDependency {
+        passes: pass::SubpassRef::External..pass::SubpassRef::Pass(0),
+        stages: (PipelineStage::FRAGMENT_SHADER | PipelineStage::COLOR_ATTACHMENT_OUTPUT | PipelineStage::EARLY_FRAGMENT_TESTS)
+          ..(PipelineStage::COLOR_ATTACHMENT_OUTPUT | PipelineStage::EARLY_FRAGMENT_TESTS),
+        accesses: gfx_image::Access::empty()
+          ..(gfx_image::Access::COLOR_ATTACHMENT_READ
+            | gfx_image::Access::COLOR_ATTACHMENT_WRITE
+            | gfx_image::Access::DEPTH_STENCIL_ATTACHMENT_READ
+            | gfx_image::Access::DEPTH_STENCIL_ATTACHMENT_WRITE),
+      },
+      pass::SubpassDependency {
+        passes: pass::SubpassRef::Pass(0)..pass::SubpassRef::External,
+        stages: PipelineStage::COLOR_ATTACHMENT_OUTPUT..PipelineStage::FRAGMENT_SHADER,
+        accesses: gfx_image::Access::COLOR_ATTACHMENT_WRITE..gfx_image::Access::SHADER_READ,
+      },
+    ];
+
+    Self::create(
+      color_format,
+      depth_format,
+      gfx_image::Layout::ShaderReadOnlyOptimal,
+      &dependencies,
+      device_state,
+    )
+  }
+
+  unsafe fn create(
+    color_format: format::Format,
+    depth_format: Option<format::Format>,
+    final_layout: gfx_image::Layout,
+    dependencies: &[pass::SubpassDependency],
+    device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+  ) -> Result<Self> {
+    let mut attachments = vec![pass::Attachment {
+      format: Some(color_format),
       samples: 1,
       ops: pass::AttachmentOps::new(pass::AttachmentLoadOp::Clear, pass::AttachmentStoreOp::Store),
       stencil_ops: pass::AttachmentOps::DONT_CARE,
-      layouts: gfx_image::Layout::Undefined..gfx_image::Layout::Present,
-    };
+      layouts: gfx_image::Layout::Undefined..final_layout,
+    }];
+
+    if let Some(depth_format) = depth_format {
+      attachments.push(pass::Attachment {
+        format: Some(depth_format),
+        samples: 1,
+        ops: pass::AttachmentOps::new(pass::AttachmentLoadOp::Clear, pass::AttachmentStoreOp::DontCare),
+        stencil_ops: pass::AttachmentOps::DONT_CARE,
+        layouts: gfx_image::Layout::Undefined..gfx_image::Layout::DepthStencilAttachmentOptimal,
+      });
+    }
+
+    let depth_stencil = (1, gfx_image::Layout::DepthStencilAttachmentOptimal);
 
     let subpass = pass::SubpassDesc {
       colors: &[(0, gfx_image::Layout::ColorAttachmentOptimal)],
-      depth_stencil: None,
+      depth_stencil: depth_format.map(|_| &depth_stencil),
       inputs: &[],
       resolves: &[],
       preserves: &[],
     };
 
-    let dependency = pass::SubpassDependency {
-      passes: pass::SubpassRef::External..pass::SubpassRef::Pass(0),
-      stages: PipelineStage::COLOR_ATTACHMENT_OUTPUT..PipelineStage::COLOR_ATTACHMENT_OUTPUT,
-      accesses: gfx_image::Access::empty()..(gfx_image::Access::COLOR_ATTACHMENT_READ | gfx_image::Access::COLOR_ATTACHMENT_WRITE),
-    };
+    let (render_pass, resource_id) = {
+      let device_state = device_state.as_ref().borrow();
 
-    let render_pass = device_state
-      .as_ref()
-      .borrow()
-      .device
-      .create_render_pass(&[attachment], &[subpass], &[dependency])
-      .expect("Failed to create render pass");
+      let render_pass = device_state
+        .device
+        .create_render_pass(&attachments, &[subpass], dependencies)
+        .graphics_err("create render pass")?;
+
+      (render_pass, track_resource!(device_state, RenderPass))
+    };
 
-    RenderPassState {
+    Ok(RenderPassState {
       render_pass: Some(render_pass),
       device_state: Rc::clone(&device_state),
-    }
+      resource_id,
+    })
   }
 }
 
 impl<B: Backend> Drop for RenderPassState<B> {
   fn drop(&mut self) {
-    let device = &self.device_state.as_ref().borrow().device;
+    let device_state = self.device_state.as_ref().borrow();
     unsafe {
-      device.destroy_render_pass(self.render_pass.take().unwrap());
+      device_state.device.destroy_render_pass(self.render_pass.take().unwrap());
     }
+
+    device_state.resource_tracker.borrow_mut().release(self.resource_id);
   }
 }
 
 pub struct RendererState<B: Backend> {
   backend_state: BackendState<B>,
   pub device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+  depth_state: DepthState<B>,
   framebuffer_state: FramebufferState<B>,
-  image_descriptor_pool: Option<B::DescriptorPool>,
-  image_state: ImageState<B>,
-  index_buffer: BufferState<B, Graphics>,
+  texture_manager: TextureManager<B>,
+  default_texture: TextureHandle,
+  texture_streamer: TextureStreamer<B>,
   pipeline_state: PipelineState<B>,
   render_pass_state: RenderPassState<B>,
+  resize_extent: Option<window::Extent2D>,
   swapchain_state: Option<SwapchainState<B>>,
-  uniform_descriptor_pool: Option<B::DescriptorPool>,
-  uniform: Uniform<B>,
+  camera_uniform: DynamicUniform<B, CameraUniformBlock>,
   viewport: pso::Viewport,
-  vertex_buffer: BufferState<B, Graphics>,
-  window_state: WindowState,
+  shader_watcher: Option<ShaderWatcher>,
+  // Counts the reloads of the pipeline, so that render targets can tell when theirs needs rebuilding:
+  pipeline_generation: u64,
+  // Must be the last field, so that it reports leaks once every other field has been dropped:
+  leak_check: LeakCheck<B, Graphics>,
 }
 
 impl<B: Backend> RendererState<B> {
-  pub unsafe fn new(mut backend_state: BackendState<B>, window_state: WindowState, frame_width: u32, frame_height: u32) -> Self {
+  pub unsafe fn new(mut backend_state: BackendState<B>, frame_width: u32, frame_height: u32) -> Result<Self> {
     let device_state = Rc::new(RefCell::new(DeviceState::new(
       backend_state.adapter_state.adapter.take().unwrap(),
       &backend_state.surface,
-    )));
+    )?));
 
-    let mut image_descriptor_pool = device_state
-      .borrow()
-      .device
-      .create_descriptor_pool(
-        1,
-        &[
-          pso::DescriptorRangeDesc {
-            count: 1,
-            ty: pso::DescriptorType::SampledImage,
-          },
-          pso::DescriptorRangeDesc {
-            count: 1,
-            ty: pso::DescriptorType::Sampler,
-          },
-        ],
-      )
-      .ok();
+    let pipeline_desc = Self::create_pipeline_desc();
 
-    let mut uniform_descriptor_pool = device_state
-      .borrow()
-      .device
-      .create_descriptor_pool(
-        1,
-        &[pso::DescriptorRangeDesc {
-          count: 1,
-          ty: pso::DescriptorType::UniformBuffer,
-        }],
-      )
-      .ok();
+    let mut texture_manager = TextureManager::new(Rc::clone(&device_state), &backend_state.adapter_state, &pipeline_desc)?;
 
-    let image_desc_set_layout = DescriptorSetLayout::new(
-      Rc::clone(&device_state),
-      vec![
-        pso::DescriptorSetLayoutBinding {
-          binding: 0,
-          ty: pso::DescriptorType::SampledImage,
-          stage_flags: pso::ShaderStageFlags::FRAGMENT,
-          count: 1,
-          immutable_samplers: false,
-        },
-        pso::DescriptorSetLayoutBinding {
-          binding: 1,
-          ty: pso::DescriptorType::Sampler,
-          stage_flags: pso::ShaderStageFlags::FRAGMENT,
-          count: 1,
-          immutable_samplers: false,
-        },
-      ],
-    );
+    let default_texture = {
+      let mut upload_batch = UploadBatch::new(Rc::clone(&device_state))?;
 
-    let uniform_desc_set_layout = DescriptorSetLayout::new(
-      Rc::clone(&device_state),
-      vec![pso::DescriptorSetLayoutBinding {
-        binding: 0,
-        ty: pso::DescriptorType::UniformBuffer,
-        stage_flags: pso::ShaderStageFlags::VERTEX,
-        count: 1,
-        immutable_samplers: false,
-      }],
-    );
+      let default_texture = texture_manager.load(
+        &mut upload_batch,
+        Path::new(DEFAULT_TEXTURE_PATH),
+        ColorSpace::Srgb,
+        &SamplerDesc::default(),
+      )?;
 
-    let image_descriptor_set = image_desc_set_layout.create_set(image_descriptor_pool.as_mut().unwrap());
+      upload_batch.flush()?;
+      default_texture
+    };
 
-    let uniform_descriptor_set = uniform_desc_set_layout.create_set(uniform_descriptor_pool.as_mut().unwrap());
+    let swapchain_state = SwapchainState::new(&mut backend_state, Rc::clone(&device_state), window::Extent2D { width: frame_width, height: frame_height })?;
 
-    let image_data = Loader::from_file("resources/uv_grid.jpg").expect("Failed to load image");
+    let mut swapchain_state = Some(swapchain_state);
 
-    let vertex_buffer = BufferState::new::<Vertex>(
-      Rc::clone(&device_state),
-      &QUAD,
-      buffer::Usage::VERTEX,
-      &backend_state.adapter_state.mem_types,
-    );
+    let depth_state = DepthState::new(Rc::clone(&device_state), swapchain_state.as_ref().unwrap().extent)?;
 
-    let index_buffer = BufferState::new::<u16>(
-      Rc::clone(&device_state),
-      &QUAD_INDICES,
-      buffer::Usage::INDEX,
-      &backend_state.adapter_state.mem_types,
-    );
+    let render_pass_state = RenderPassState::new(swapchain_state.as_ref().unwrap(), &depth_state, Rc::clone(&device_state))?;
 
-    let uniform = Uniform::new(
+    let framebuffer_state = FramebufferState::new(
       Rc::clone(&device_state),
-      &backend_state.adapter_state.mem_types,
-      &[1.0f32, 1.0f32, 1.0f32, 1.0f32],
-      uniform_descriptor_set,
-      0,
-    );
+      &render_pass_state,
+      swapchain_state.as_mut().unwrap(),
+      &depth_state,
+    )?;
 
-    let mut staging_pool = device_state
-      .as_ref()
-      .borrow()
-      .device
-      .create_command_pool_typed(&device_state.as_ref().borrow().queue_group, pool::CommandPoolCreateFlags::empty())
-      .unwrap();
+    // Each swapchain image has its own region of the camera uniform, written once its fence has signalled:
+    let camera_uniform = Self::create_camera_uniform(
+      Rc::clone(&device_state),
+      &pipeline_desc,
+      framebuffer_state.frame_count(),
+      backend_state.adapter_state.limits.min_uniform_buffer_offset_alignment,
+    )?;
 
-    let image_state = ImageState::new(
-      image_descriptor_set,
-      &mut device_state.borrow_mut(),
+    let texture_streamer = TextureStreamer::new(
+      Rc::clone(&device_state),
       &backend_state.adapter_state,
-      image_data,
-      buffer::Usage::TRANSFER_SRC,
-      &mut staging_pool,
-    );
-
-    image_state.wait_for_transfer();
-
-    device_state.as_ref().borrow().device.destroy_command_pool(staging_pool.into_raw());
-
-    let swapchain_state = SwapchainState::new(&mut backend_state, Rc::clone(&device_state), window::Extent2D { width: frame_width, height: frame_height });
-
-    let mut swapchain_state = Some(swapchain_state);
-
-    let render_pass_state = RenderPassState::new(swapchain_state.as_ref().unwrap(), Rc::clone(&device_state));
+      framebuffer_state.frame_count(),
+    )?;
 
-    let framebuffer_state = FramebufferState::new(Rc::clone(&device_state), &render_pass_state, swapchain_state.as_mut().unwrap());
-
-    let pipeline_state = PipelineState::new(
-      vec![image_state.get_layout(), uniform.get_layout()],
+    let pipeline_state = pipeline_desc.build(
+      &[texture_manager.get_set_layout(), camera_uniform.get_set_layout()],
       render_pass_state.render_pass.as_ref().unwrap(),
       Rc::clone(&device_state),
-    );
+    )?;
 
     let viewport = Self::create_viewport(&swapchain_state.as_ref().unwrap());
 
-    RendererState {
+    // Shaders are only reloaded while developing, release builds never touch the shader sources again:
+    let shader_watcher = if cfg!(debug_assertions) {
+      Some(ShaderWatcher::new(shader::shader_dir()))
+    } else {
+      None
+    };
+
+    let leak_check = LeakCheck::new(Rc::clone(&device_state));
+
+    Ok(RendererState {
       backend_state,
       device_state,
+      depth_state,
       framebuffer_state,
-      image_state,
-      image_descriptor_pool,
-      index_buffer,
+      texture_manager,
+      default_texture,
+      texture_streamer,
       pipeline_state,
       render_pass_state,
+      resize_extent: None,
       swapchain_state,
-      uniform,
-      uniform_descriptor_pool,
-      window_state,
-      vertex_buffer,
+      camera_uniform,
       viewport,
+      shader_watcher,
+      pipeline_generation: 0,
+      leak_check,
+    })
+  }
+
+  /// Uploads every Mesh within the scene hierarchy so that it can be drawn with `render_frame`.
+  /// Textures already loaded for other models are shared with them.
+  pub unsafe fn create_model(&mut self, scene: &Node) -> Result<ModelState<B>> {
+    ModelState::new(
+      Rc::clone(&self.device_state),
+      scene,
+      &mut self.texture_manager,
+      &SamplerDesc::default(),
+    )
+  }
+
+  pub fn texture_manager(&mut self) -> &mut TextureManager<B> {
+    &mut self.texture_manager
+  }
+
+  /// Creates an offscreen target to draw into with `render_to_target`, whose output can be sampled
+  /// through `RenderTarget::get_texture` by materials drawn afterwards
+  pub unsafe fn create_render_target(&mut self, desc: &RenderTargetDesc) -> Result<RenderTarget<B>> {
+    let camera_uniform = Self::create_camera_uniform(
+      Rc::clone(&self.device_state),
+      &self.pipeline_state.desc,
+      1,
+      self.backend_state.adapter_state.limits.min_uniform_buffer_offset_alignment,
+    )?;
+
+    RenderTarget::new(
+      Rc::clone(&self.device_state),
+      &mut self.texture_manager,
+      desc,
+      &self.pipeline_state.desc,
+      self.pipeline_generation,
+      camera_uniform,
+    )
+  }
+
+  /// Draws the model as seen from the camera into the target, rather than the swapchain. The model mustn't
+  /// use the target's own texture, which can't be sampled while it is being drawn to.
+  pub unsafe fn render_to_target(&mut self, target: &mut RenderTarget<B>, model_state: &ModelState<B>, camera: &Camera<f32>) -> Result<()> {
+    // Texture updates are submitted first, so that the target is drawn with their new contents:
+    self.texture_streamer.submit()?;
+
+    // The target's pipeline is rebuilt here rather than on reload, as targets aren't tracked by the renderer:
+    target.update_pipeline(&self.texture_manager, &self.pipeline_state.desc, self.pipeline_generation)?;
+
+    let mut cmd_buffer = target.begin()?;
+
+    let camera_offset = target.write_camera(camera)?;
+    let viewport = target.get_desc().get_viewport();
+
+    cmd_buffer.set_viewports(0, &[viewport.clone()]);
+    cmd_buffer.set_scissors(0, &[viewport.rect]);
+    cmd_buffer.bind_graphics_pipeline(target.get_pipeline_state().pipeline.as_ref().unwrap());
+    cmd_buffer.bind_graphics_descriptor_sets(
+      target.get_pipeline_state().pipeline_layout.as_ref().unwrap(),
+      0,
+      vec![
+        self.texture_manager.get_descriptor_set(&self.default_texture),
+        target.get_camera_uniform().descriptor_set.as_ref().unwrap().set.as_ref().unwrap(),
+      ],
+      &[camera_offset],
+    );
+
+    {
+      let mut encoder = cmd_buffer.begin_render_pass_inline(
+        target.get_render_pass(),
+        target.get_framebuffer(),
+        viewport.rect,
+        &target.clear_values(CLEAR_COLOR, CLEAR_DEPTH),
+      );
+
+      Self::draw_model(
+        &mut encoder,
+        target.get_pipeline_state(),
+        &self.texture_manager,
+        &self.default_texture,
+        model_state,
+      );
     }
+
+    target.submit(cmd_buffer);
+
+    Ok(())
+  }
+
+  /// Replaces a region of a texture with tightly packed texel data in the texture's format, such as a video frame.
+  /// The update is seen by the next frame drawn, while frames already in flight keep drawing the previous contents.
+  pub unsafe fn update_texture(&mut self, texture: &TextureHandle, region: TextureRegion, data: &[u8]) -> Result<()> {
+    self.texture_streamer.update(&self.texture_manager, texture, region, data)
   }
 
-  pub unsafe fn render(&mut self)
+  /// Flags the swapchain to be rebuilt at the given size before the next frame is drawn
+  pub fn resize(&mut self, dimensions: LogicalSize)
   where
     B::Surface: SurfaceTrait,
   {
-    let mut is_running = true;
-    let mut will_recreate_swapchain = false;
+    info!("Window Resized: {:?}", dimensions);
+
+    #[cfg(feature = "gl")]
+    self
+      .backend_state
+      .surface
+      .get_window_t()
+      .resize(dimensions.to_physical(self.backend_state.surface.get_window_t().get_hidpi_factor()));
+
+    self.resize_extent = Some(window::Extent2D {
+      width: dimensions.width as u32,
+      height: dimensions.height as u32,
+    });
+  }
 
-    let mut resize_dimensions = window::Extent2D {
-      width: 640,
-      height: 480,
-    };
+  /// Draws a single frame of the model as seen from the camera, then presents it
+  pub unsafe fn render_frame(&mut self, model_state: &ModelState<B>, camera: &Camera<f32>) -> Result<()> {
+    if let Some(resize_extent) = self.resize_extent.take() {
+      self.recreate_swapchain(resize_extent)?;
+    }
 
-    while is_running {
-      {
-        #[cfg(feature = "gl")]
-        let backend = &self.backend_state;
-
-        // Handles the window event loop:
-        self.window_state.event_loop.poll_events(|event| {
-          if let winit::Event::WindowEvent { event, .. } = event {
-            match event {
-              // Handle the window being closed:
-              winit::WindowEvent::CloseRequested => is_running = false,
-
-              // Handle the window being resized:
-              winit::WindowEvent::Resized(dimensions) => {
-                info!("Window Resized: {:?}", dimensions);
-
-                #[cfg(feature = "gl")]
-                backend
-                  .surface
-                  .get_window_t()
-                  .resize(dimensions.to_physical(backend.surface.get_window_t().get_hidpi_factor()));
-
-                will_recreate_swapchain = true;
-
-                resize_dimensions.width = dimensions.width as u32;
-                resize_dimensions.height = dimensions.height as u32;
-              }
-              _ => {}
-            }
-          }
-        });
-      }
+    self.reload_changed_shaders()?;
 
-      if will_recreate_swapchain {
-        self.recreate_swapchain(resize_dimensions);
-        will_recreate_swapchain = false;
-      }
+    // Texture updates are submitted ahead of the frame, so that it draws with the new contents. They are
+    // submitted before textures are released, as the streamer holds the updated textures until their copies complete:
+    self.texture_streamer.submit()?;
+
+    self.release_unused_textures()?;
+
+    let semaphore_index = self.framebuffer_state.get_next_semaphore_index();
 
-      let semaphore_index = self.framebuffer_state.get_next_semaphore_index();
+    let frame: SwapImageIndex = {
+      let (acquire_semaphore, _) = self.framebuffer_state.get_frame_data(None, Some(semaphore_index)).1.unwrap();
 
-      let frame: SwapImageIndex = {
-        let (acquire_semaphore, _) = self.framebuffer_state.get_frame_data(None, Some(semaphore_index)).1.unwrap();
+      let swapchain = self.swapchain_state.as_mut().unwrap().swapchain.as_mut().unwrap();
 
-        let swapchain = self.swapchain_state.as_mut().unwrap().swapchain.as_mut().unwrap();
+      let acquired_image = swapchain.acquire_image(!0, gfx_hal::FrameSync::Semaphore(acquire_semaphore));
 
-        match swapchain.acquire_image(!0, gfx_hal::FrameSync::Semaphore(acquire_semaphore)) {
-          Ok(img) => img,
-          Err(_) => {
-            will_recreate_swapchain = true;
-            continue;
-          }
+      match acquired_image {
+        Ok(img) => img,
+        Err(_) => {
+          self.request_swapchain_recreation();
+          return Ok(());
         }
-      };
+      }
+    };
 
-      let (frame_data, semaphore_data) = self.framebuffer_state.get_frame_data(Some(frame as usize), Some(semaphore_index));
+    let (frame_data, semaphore_data) = self.framebuffer_state.get_frame_data(Some(frame as usize), Some(semaphore_index));
 
-      let (framebuffer_fence, framebuffer, command_pool) = frame_data.unwrap();
-      let (acquire_semaphore, present_semaphore) = semaphore_data.unwrap();
+    let (framebuffer_fence, framebuffer, command_pool) = frame_data.unwrap();
+    let (acquire_semaphore, present_semaphore) = semaphore_data.unwrap();
 
-      self
-        .device_state
-        .as_ref()
-        .borrow()
-        .device
-        .wait_for_fence(&framebuffer_fence, !0)
-        .unwrap();
+    self
+      .device_state
+      .as_ref()
+      .borrow()
+      .device
+      .wait_for_fence(&framebuffer_fence, !0)
+      .graphics_err("wait for framebuffer fence")?;
 
-      self.device_state.as_ref().borrow().device.reset_fence(&framebuffer_fence).unwrap();
+    self
+      .device_state
+      .as_ref()
+      .borrow()
+      .device
+      .reset_fence(&framebuffer_fence)
+      .graphics_err("reset framebuffer fence")?;
 
-      command_pool.reset();
+    command_pool.reset();
 
-      let mut cmd_buffer = command_pool.acquire_command_buffer::<command::OneShot>();
-      cmd_buffer.begin();
+    // The previous frame using this framebuffer has finished, so its region of the camera uniform can be written:
+    let camera_offset = self.camera_uniform.write(frame as usize, &CameraUniformBlock::from(camera))?;
 
-      // Record a command buffer to get some rendering going:
-      cmd_buffer.set_viewports(0, &[self.viewport.clone()]);
-      cmd_buffer.set_scissors(0, &[self.viewport.rect]);
-      cmd_buffer.bind_graphics_pipeline(self.pipeline_state.pipeline.as_ref().unwrap());
-      cmd_buffer.bind_vertex_buffers(0, Some((self.vertex_buffer.buffer.as_ref().unwrap(), 0)));
-      cmd_buffer.bind_index_buffer(self.index_buffer.get_buffer_view());
-      cmd_buffer.bind_graphics_descriptor_sets(
-        self.pipeline_state.pipeline_layout.as_ref().unwrap(),
-        0,
-        vec![
-          self.image_state.descriptor_set.set.as_ref().unwrap(),
-          self.uniform.descriptor_set.as_ref().unwrap().set.as_ref().unwrap(),
+    let mut cmd_buffer = command_pool.acquire_command_buffer::<command::OneShot>();
+    cmd_buffer.begin();
+
+    // Record a command buffer to get some rendering going:
+    cmd_buffer.set_viewports(0, &[self.viewport.clone()]);
+    cmd_buffer.set_scissors(0, &[self.viewport.rect]);
+    cmd_buffer.bind_graphics_pipeline(self.pipeline_state.pipeline.as_ref().unwrap());
+    cmd_buffer.bind_graphics_descriptor_sets(
+      self.pipeline_state.pipeline_layout.as_ref().unwrap(),
+      0,
+      vec![
+        self.texture_manager.get_descriptor_set(&self.default_texture),
+        self.camera_uniform.descriptor_set.as_ref().unwrap().set.as_ref().unwrap(),
+      ],
+      &[camera_offset],
+    );
+
+    {
+      let mut encoder = cmd_buffer.begin_render_pass_inline(
+        self.render_pass_state.render_pass.as_ref().unwrap(),
+        &framebuffer,
+        self.viewport.rect,
+        &[
+          command::ClearValue::Color(command::ClearColor::Float(CLEAR_COLOR.clone())),
+          command::ClearValue::DepthStencil(command::ClearDepthStencil(CLEAR_DEPTH, 0)),
         ],
+      );
+
+      Self::draw_model(
+        &mut encoder,
+        &self.pipeline_state,
+        &self.texture_manager,
+        &self.default_texture,
+        model_state,
+      );
+    }
+
+    cmd_buffer.finish();
+
+    // Tell GPU we're doing a command buffer:
+    let submission = Submission {
+      command_buffers: std::iter::once(&cmd_buffer),
+      wait_semaphores: std::iter::once((&*acquire_semaphore, PipelineStage::BOTTOM_OF_PIPE)),
+      signal_semaphores: std::iter::once(&*present_semaphore),
+    };
+
+    self.device_state.as_ref().borrow_mut().queue_group.queues[0].submit(submission, Some(framebuffer_fence));
+
+    let present_result = self.swapchain_state.as_ref().unwrap().swapchain.as_ref().unwrap().present(
+      &mut self.device_state.as_ref().borrow_mut().queue_group.queues[0],
+      frame,
+      Some(&*present_semaphore),
+    );
+
+    if present_result.is_err() {
+      // Failed to present image, swapchain should be rebuilt:
+      self.request_swapchain_recreation();
+    }
+
+    Ok(())
+  }
+
+  /// Rebuilds the swapchain at its current size before the next frame is drawn
+  fn request_swapchain_recreation(&mut self) {
+    if self.resize_extent.is_none() {
+      let extent = self.swapchain_state.as_ref().unwrap().extent;
+
+      self.resize_extent = Some(window::Extent2D {
+        width: extent.width,
+        height: extent.height,
+      });
+    }
+  }
+
+  /// Records the draw of each mesh in the model, with the pipeline already bound to the render pass
+  unsafe fn draw_model(
+    encoder: &mut command::RenderPassInlineEncoder<B>,
+    pipeline_state: &PipelineState<B>,
+    texture_manager: &TextureManager<B>,
+    default_texture: &TextureHandle,
+    model_state: &ModelState<B>,
+  ) {
+    for mesh_state in model_state.mesh_iter() {
+      // Meshes without a diffuse texture are drawn with the default texture:
+      let texture = mesh_state.material().texture(TextureSlot::Diffuse).unwrap_or(default_texture);
+
+      encoder.bind_graphics_descriptor_sets(
+        pipeline_state.pipeline_layout.as_ref().unwrap(),
+        0,
+        Some(texture_manager.get_descriptor_set(texture)),
         &[],
       );
 
-      {
-        let mut encoder = cmd_buffer.begin_render_pass_inline(
-          self.render_pass_state.render_pass.as_ref().unwrap(),
-          &framebuffer,
-          self.viewport.rect,
-          &[command::ClearValue::Color(command::ClearColor::Float(CLEAR_COLOR.clone()))],
-        );
+      pipeline_state.push_constants(
+        encoder,
+        pso::ShaderStageFlags::VERTEX,
+        &ModelPushConstants {
+          model: (*mesh_state.transformation()).into(),
+        },
+      );
 
-        encoder.draw_indexed(0..6, 0, 0..1);
-      }
+      encoder.bind_vertex_buffers(0, Some((mesh_state.vertex_buffer.get_buffer(), 0)));
+      encoder.bind_index_buffer(mesh_state.index_buffer.get_buffer_view(IndexType::U32));
+      encoder.draw_indexed(0..mesh_state.index_count(), 0, 0..1);
+    }
+  }
 
-      cmd_buffer.finish();
-
-      // Tell GPU we're doing a command buffer:
-      let submission = Submission {
-        command_buffers: std::iter::once(&cmd_buffer),
-        wait_semaphores: std::iter::once((&*acquire_semaphore, PipelineStage::BOTTOM_OF_PIPE)),
-        signal_semaphores: std::iter::once(&*present_semaphore),
-      };
-
-      self.device_state.as_ref().borrow_mut().queue_group.queues[0].submit(submission, Some(framebuffer_fence));
-
-      if let Err(_) = self.swapchain_state.as_ref().unwrap().swapchain.as_ref().unwrap().present(
-        &mut self.device_state.as_ref().borrow_mut().queue_group.queues[0],
-        frame,
-        Some(&*present_semaphore),
-      ) {
-        // Failed to present image, swapchain should be rebuilt:
-        will_recreate_swapchain = true;
-        continue;
-      }
+  /// Frees the textures of models that have been dropped. Frames drawn with them may still be in flight,
+  /// so this waits for the device to be idle, but only when there is something to free.
+  unsafe fn release_unused_textures(&mut self) -> Result<()> {
+    if !self.texture_manager.has_unused() {
+      return Ok(());
     }
+
+    self
+      .device_state
+      .as_ref()
+      .borrow()
+      .device
+      .wait_idle()
+      .graphics_err("wait for device idle")?;
+
+    self.texture_manager.release_unused();
+
+    Ok(())
   }
 
-  unsafe fn recreate_swapchain(&mut self, frame_extent: window::Extent2D) {
-    let device = &self.device_state.as_ref().borrow().device;
+  unsafe fn recreate_swapchain(&mut self, frame_extent: window::Extent2D) -> Result<()> {
+    // Updates recorded since the last frame are submitted, so that they aren't lost if the streamer is recreated:
+    self.texture_streamer.submit()?;
 
-    device.wait_idle().unwrap();
+    self
+      .device_state
+      .as_ref()
+      .borrow()
+      .device
+      .wait_idle()
+      .graphics_err("wait for device idle")?;
 
     self.swapchain_state.take().unwrap();
 
-    let new_swapchain_state = SwapchainState::new(&mut self.backend_state, Rc::clone(&self.device_state), frame_extent);
+    let new_swapchain_state = SwapchainState::new(&mut self.backend_state, Rc::clone(&self.device_state), frame_extent)?;
 
     self.swapchain_state = Some(new_swapchain_state);
 
-    self.render_pass_state = RenderPassState::new(&self.swapchain_state.as_ref().unwrap(), Rc::clone(&self.device_state));
+    // The depth image must always match the extent of the swapchain images:
+    self.depth_state = DepthState::new(Rc::clone(&self.device_state), self.swapchain_state.as_ref().unwrap().extent)?;
+
+    self.render_pass_state = RenderPassState::new(
+      &self.swapchain_state.as_ref().unwrap(),
+      &self.depth_state,
+      Rc::clone(&self.device_state),
+    )?;
 
     self.framebuffer_state = FramebufferState::new(
       Rc::clone(&self.device_state),
       &self.render_pass_state,
       self.swapchain_state.as_mut().unwrap(),
-    );
+      &self.depth_state,
+    )?;
+
+    // The new swapchain may have a different number of images, and each needs its own camera region and staging buffers:
+    let frame_count = self.framebuffer_state.frame_count();
+
+    if frame_count != self.camera_uniform.region_count() {
+      self.camera_uniform = Self::create_camera_uniform(
+        Rc::clone(&self.device_state),
+        &self.pipeline_state.desc,
+        frame_count,
+        self.backend_state.adapter_state.limits.min_uniform_buffer_offset_alignment,
+      )?;
+
+      self.texture_streamer = TextureStreamer::new(Rc::clone(&self.device_state), &self.backend_state.adapter_state, frame_count)?;
+    }
 
     self.pipeline_state = PipelineState::new(
-      vec![self.image_state.get_layout(), self.uniform.get_layout()],
+      self.pipeline_state.desc.clone(),
+      &[self.texture_manager.get_set_layout(), self.camera_uniform.get_set_layout()],
       self.render_pass_state.render_pass.as_ref().unwrap(),
       Rc::clone(&self.device_state),
-    );
+    )?;
 
     self.viewport = Self::create_viewport(self.swapchain_state.as_ref().unwrap());
+
+    Ok(())
+  }
+
+  /// Rebuilds the pipeline if any shader source has changed on disk. A shader that fails
+  /// to compile is logged and the previous pipeline is kept, so mistakes can be fixed without restarting.
+  /// Render targets rebuild their pipelines from the new one the next time they are drawn to.
+  unsafe fn reload_changed_shaders(&mut self) -> Result<()> {
+    let changed_files = match self.shader_watcher.as_mut() {
+      Some(shader_watcher) => shader_watcher.poll(),
+      None => return Ok(()),
+    };
+
+    // Included files aren't tracked per pipeline, so a change to any other file rebuilds the pipeline too:
+    let desc = &self.pipeline_state.desc;
+    let is_affected = changed_files
+      .iter()
+      .any(|path| desc.uses_shader(path) || path.extension().map_or(false, |extension| extension == "glsl"));
+
+    if !is_affected {
+      return Ok(());
+    }
+
+    info!("Reloading shaders after changes to {:?}", changed_files);
+
+    let pipeline_state = PipelineState::new(
+      self.pipeline_state.desc.clone(),
+      &[self.texture_manager.get_set_layout(), self.camera_uniform.get_set_layout()],
+      self.render_pass_state.render_pass.as_ref().unwrap(),
+      Rc::clone(&self.device_state),
+    );
+
+    match pipeline_state {
+      Ok(pipeline_state) => {
+        // The old pipeline can't be destroyed while frames drawn with it are in flight:
+        self
+          .device_state
+          .as_ref()
+          .borrow()
+          .device
+          .wait_idle()
+          .graphics_err("wait for device idle")?;
+
+        self.pipeline_state = pipeline_state;
+        self.pipeline_generation += 1;
+      }
+      Err(e) => error!("Failed to reload shaders, keeping the previous pipeline: {}", e),
+    }
+
+    Ok(())
+  }
+
+  /// Creates a uniform holding the camera block in `region_count` regions, bound with a dynamic offset
+  unsafe fn create_camera_uniform(
+    device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+    pipeline_desc: &PipelineDesc,
+    region_count: usize,
+    min_offset_alignment: u64,
+  ) -> Result<DynamicUniform<B, CameraUniformBlock>> {
+    // The camera block is bound with a dynamic offset, which the shader can't declare:
+    let uniform_desc_set_layout = DescriptorSetLayout::new(
+      Rc::clone(&device_state),
+      pipeline_desc
+        .reflect()?
+        .set_layout_bindings(CAMERA_SET)
+        .into_iter()
+        .map(reflect::dynamic_binding)
+        .collect(),
+    )?;
+
+    DynamicUniform::new(
+      device_state,
+      uniform_desc_set_layout.create_set()?,
+      0,
+      region_count,
+      min_offset_alignment,
+    )
+  }
+
+  /// Describes the pipeline used to draw opaque, textured meshes
+  fn create_pipeline_desc() -> PipelineDesc {
+    PipelineDesc::new(
+      ShaderDesc::new(
+        "VS_SHADER",
+        shader::Kind::Vertex,
+        &shader::shader_dir().join("quad_150.vert").to_string_lossy(),
+      )
+      .spec_constant(0, MODEL_SCALE),
+      VertexLayout::from_format::<Vertex>(),
+    )
+    .fragment_shader(ShaderDesc::new(
+      "FS_SHADER",
+      shader::Kind::Fragment,
+      &shader::shader_dir().join("quad_150.frag").to_string_lossy(),
+    ))
+    .depth_stencil(DEPTH_STENCIL)
   }
 
   fn create_viewport(swapchain_state: &SwapchainState<B>) -> pso::Viewport {
@@ -440,22 +765,6 @@ impl<B: Backend> Drop for RendererState<B> {
   fn drop(&mut self) {
     self.device_state.as_ref().borrow().device.wait_idle().unwrap();
 
-    unsafe {
-      self
-        .device_state
-        .as_ref()
-        .borrow()
-        .device
-        .destroy_descriptor_pool(self.image_descriptor_pool.take().unwrap());
-
-      self
-        .device_state
-        .as_ref()
-        .borrow()
-        .device
-        .destroy_descriptor_pool(self.uniform_descriptor_pool.take().unwrap());
-
-      self.swapchain_state.take();
-    }
+    self.swapchain_state.take();
   }
 }
@@ -0,0 +1,176 @@
+use errors::*;
+use gfx_hal::image as i;
+use gfx_hal::*;
+use graphics::device::DeviceState;
+use graphics::tracker::ResourceId;
+
+/// The anisotropy asked for by default, which is lowered to whatever the device supports
+pub const DEFAULT_ANISOTROPY: u8 = 16;
+
+/// How a texture is filtered and addressed when it is sampled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplerDesc {
+    pub min_filter: i::Filter,
+    pub mag_filter: i::Filter,
+    pub mip_filter: i::Filter,
+    pub wrap_mode: (i::WrapMode, i::WrapMode, i::WrapMode),
+    /// The maximum number of samples taken along the axis of anisotropy, or `None` to disable anisotropic filtering
+    pub anisotropy: Option<u8>,
+    /// Added to the mip level chosen for each sample, positive values giving blurrier results
+    pub lod_bias: f32,
+}
+
+impl SamplerDesc {
+    /// Creates a description using the same filter for every level, without anisotropic filtering
+    pub fn new(filter: i::Filter, wrap_mode: i::WrapMode) -> Self {
+        SamplerDesc {
+            min_filter: filter,
+            mag_filter: filter,
+            mip_filter: filter,
+            wrap_mode: (wrap_mode, wrap_mode, wrap_mode),
+            anisotropy: None,
+            lod_bias: 0.0,
+        }
+    }
+
+    pub fn filter(mut self, min_filter: i::Filter, mag_filter: i::Filter) -> Self {
+        self.min_filter = min_filter;
+        self.mag_filter = mag_filter;
+        self
+    }
+
+    pub fn mip_filter(mut self, mip_filter: i::Filter) -> Self {
+        self.mip_filter = mip_filter;
+        self
+    }
+
+    pub fn wrap_mode(mut self, u: i::WrapMode, v: i::WrapMode, w: i::WrapMode) -> Self {
+        self.wrap_mode = (u, v, w);
+        self
+    }
+
+    pub fn anisotropy(mut self, anisotropy: u8) -> Self {
+        self.anisotropy = Some(anisotropy);
+        self
+    }
+
+    pub fn lod_bias(mut self, lod_bias: f32) -> Self {
+        self.lod_bias = lod_bias;
+        self
+    }
+
+    /// Converts the description into the sampler's create info, limiting anisotropy to `max_anisotropy`
+    pub fn to_sampler_info(&self, max_anisotropy: u8) -> i::SamplerInfo {
+        let mut sampler_info = i::SamplerInfo::new(self.mag_filter, self.wrap_mode.0);
+
+        sampler_info.min_filter = self.min_filter;
+        sampler_info.mip_filter = self.mip_filter;
+        sampler_info.wrap_mode = self.wrap_mode;
+        sampler_info.lod_bias = i::Lod::from(self.lod_bias);
+        sampler_info.anisotropic = match self.anisotropy.map(|anisotropy| anisotropy.min(max_anisotropy)) {
+            Some(anisotropy) if anisotropy > 1 => i::Anisotropic::On(anisotropy),
+            _ => i::Anisotropic::Off,
+        };
+
+        sampler_info
+    }
+}
+
+/// Trilinear filtering with repeating texture coordinates, as tiled surfaces need
+impl Default for SamplerDesc {
+    fn default() -> Self {
+        SamplerDesc::new(i::Filter::Linear, i::WrapMode::Tile).anisotropy(DEFAULT_ANISOTROPY)
+    }
+}
+
+/// Shares a single sampler between every texture that is sampled the same way.
+/// Samplers belong to the device, and are only destroyed along with it.
+pub struct SamplerCache<B: Backend> {
+    samplers: Vec<(SamplerDesc, B::Sampler, ResourceId)>,
+    max_anisotropy: u8,
+}
+
+impl<B: Backend> SamplerCache<B> {
+    /// `max_anisotropy` should be 0 unless the device was opened with the `SAMPLER_ANISOTROPY` feature
+    pub fn new(max_anisotropy: u8) -> Self {
+        SamplerCache {
+            samplers: Vec::new(),
+            max_anisotropy,
+        }
+    }
+
+    /// Returns the sampler for the description, creating it the first time it is asked for
+    pub unsafe fn get_or_create<C: Capability>(
+        &mut self,
+        device_state: &DeviceState<B, C>,
+        sampler_desc: &SamplerDesc,
+    ) -> Result<&B::Sampler> {
+        let index = match self.samplers.iter().position(|&(ref desc, _, _)| desc == sampler_desc) {
+            Some(index) => index,
+            None => {
+                let sampler = device_state
+                    .device
+                    .create_sampler(sampler_desc.to_sampler_info(self.max_anisotropy))
+                    .graphics_err("create sampler")?;
+
+                self.samplers.push((*sampler_desc, sampler, track_resource!(device_state, Sampler)));
+                self.samplers.len() - 1
+            }
+        };
+
+        Ok(&self.samplers[index].1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.samplers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samplers.is_empty()
+    }
+
+    pub fn max_anisotropy(&self) -> u8 {
+        self.max_anisotropy
+    }
+
+    pub unsafe fn dispose<C: Capability>(&mut self, device_state: &DeviceState<B, C>) {
+        for (_, sampler, resource_id) in self.samplers.drain(..) {
+            device_state.device.destroy_sampler(sampler);
+            device_state.resource_tracker.borrow_mut().release(resource_id);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn should_limit_anisotropy() {
+        let sampler_desc = SamplerDesc::default();
+
+        assert_eq!(i::Anisotropic::On(DEFAULT_ANISOTROPY), sampler_desc.to_sampler_info(16).anisotropic);
+        assert_eq!(i::Anisotropic::On(4), sampler_desc.to_sampler_info(4).anisotropic);
+        assert_eq!(i::Anisotropic::Off, sampler_desc.to_sampler_info(0).anisotropic);
+        assert_eq!(
+            i::Anisotropic::Off,
+            SamplerDesc::new(i::Filter::Nearest, i::WrapMode::Clamp)
+                .to_sampler_info(16)
+                .anisotropic
+        );
+    }
+
+    #[test]
+    fn should_describe_sampler() {
+        let sampler_info = SamplerDesc::new(i::Filter::Linear, i::WrapMode::Tile)
+            .mip_filter(i::Filter::Nearest)
+            .wrap_mode(i::WrapMode::Tile, i::WrapMode::Clamp, i::WrapMode::Mirror)
+            .lod_bias(0.5)
+            .to_sampler_info(0);
+
+        assert_eq!(i::Filter::Linear, sampler_info.min_filter);
+        assert_eq!(i::Filter::Nearest, sampler_info.mip_filter);
+        assert_eq!((i::WrapMode::Tile, i::WrapMode::Clamp, i::WrapMode::Mirror), sampler_info.wrap_mode);
+        assert_eq!(i::Lod::from(0.5), sampler_info.lod_bias);
+    }
+}
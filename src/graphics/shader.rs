@@ -1,44 +1,299 @@
+use errors::*;
+#[cfg(feature = "runtime-shaders")]
 use shaderc;
+use std::env;
+use std::path::{Path, PathBuf};
 
+/// The shaders directory in the source tree, which is only used when the engine isn't run from a packaged release
+pub const SHADER_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/shaders");
+
+/// Environment variable used to read shaders from a directory other than the one `shader_dir` would find
+pub const SHADER_DIR_ENV_VAR: &str = "CORPORATION_SHADER_DIR";
+
+/// The directory shaders are read from, and `#include` directives are resolved against
+pub fn shader_dir() -> PathBuf {
+    find_runtime_dir(SHADER_DIR_ENV_VAR, "shaders", SHADER_DIR)
+}
+
+/// Finds a directory read at runtime: the one named by the environment variable if it is set, otherwise
+/// `relative_path` beside the executable if it exists, as in a packaged release, otherwise the one in the source tree
+pub fn find_runtime_dir(env_var: &str, relative_path: &str, source_tree_dir: &str) -> PathBuf {
+    if let Some(dir) = env::var_os(env_var) {
+        return PathBuf::from(dir);
+    }
+
+    let packaged_dir = env::current_exe()
+        .ok()
+        .and_then(|exe_path| exe_path.parent().map(|exe_dir| exe_dir.join(relative_path)));
+
+    match packaged_dir {
+        Some(packaged_dir) if packaged_dir.is_dir() => packaged_dir,
+        _ => PathBuf::from(source_tree_dir),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Kind {
     Vertex,
     Fragment,
+    Compute,
+    Geometry,
+    TessControl,
+    TessEvaluation,
+}
+
+impl Kind {
+    /// Finds the stage of a shader from the extension of its file, e.g. `quad_150.vert`
+    pub fn from_path(path: &Path) -> Option<Kind> {
+        match path.extension()?.to_str()? {
+            "vert" => Some(Kind::Vertex),
+            "frag" => Some(Kind::Fragment),
+            "comp" => Some(Kind::Compute),
+            "geom" => Some(Kind::Geometry),
+            "tesc" => Some(Kind::TessControl),
+            "tese" => Some(Kind::TessEvaluation),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum Error {
-    CompileError(String),
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptimizationLevel {
+    Zero,
+    Size,
+    Performance,
+}
+
+/// The API the SPIR-V is generated for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TargetEnv {
+    Vulkan,
+    OpenGl,
+}
+
+/// The defines and compiler settings a shader is built with
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileOptions {
+    pub defines: Vec<(String, Option<String>)>,
+    pub optimization_level: OptimizationLevel,
+    pub target_env: TargetEnv,
+    pub include_dir: PathBuf,
+    pub debug_info: bool,
+}
+
+impl Default for CompileOptions {
+    /// Uses the release options in release builds, and the debug options otherwise
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            CompileOptions::debug()
+        } else {
+            CompileOptions::release()
+        }
+    }
+}
+
+impl CompileOptions {
+    /// Unoptimized, with debug info
+    pub fn debug() -> Self {
+        CompileOptions {
+            defines: Vec::new(),
+            optimization_level: OptimizationLevel::Zero,
+            target_env: TargetEnv::Vulkan,
+            include_dir: shader_dir(),
+            debug_info: true,
+        }
+    }
+
+    /// Optimized for performance, without debug info
+    pub fn release() -> Self {
+        CompileOptions {
+            optimization_level: OptimizationLevel::Performance,
+            debug_info: false,
+            ..CompileOptions::debug()
+        }
+    }
+
+    /// Adds `#define name value` before the shader source, replacing any earlier definition of the name
+    pub fn define(mut self, name: &str, value: &str) -> Self {
+        self.set_define(name, Some(value.to_string()));
+        self
+    }
+
+    /// Adds `#define name` before the shader source, for features tested with `#ifdef`
+    pub fn define_flag(mut self, name: &str) -> Self {
+        self.set_define(name, None);
+        self
+    }
+
+    pub fn optimization_level(mut self, optimization_level: OptimizationLevel) -> Self {
+        self.optimization_level = optimization_level;
+        self
+    }
+
+    pub fn target_env(mut self, target_env: TargetEnv) -> Self {
+        self.target_env = target_env;
+        self
+    }
+
+    pub fn include_dir<P: AsRef<Path>>(mut self, include_dir: P) -> Self {
+        self.include_dir = include_dir.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn debug_info(mut self, debug_info: bool) -> Self {
+        self.debug_info = debug_info;
+        self
+    }
+
+    fn set_define(&mut self, name: &str, value: Option<String>) {
+        self.defines.retain(|&(ref defined_name, _)| defined_name != name);
+        self.defines.push((name.to_string(), value));
+    }
 }
 
 pub struct Loader {}
 
 impl Loader {
-    pub fn compile<'a>(name: &str, kind: &Kind, shader_source: &str) -> Result<Vec<u8>, Error> {
-        let mut compiler = shaderc::Compiler::new().unwrap();
-        let opts = shaderc::CompileOptions::new().unwrap();
+    pub fn compile<'a>(name: &str, kind: &Kind, shader_source: &str) -> Result<Vec<u8>> {
+        Self::compile_with_options(name, kind, shader_source, &CompileOptions::default())
+    }
+
+    /// Compiles GLSL into SPIR-V. `name` is reported in errors and is the base of relative `#include` paths,
+    /// so it should be the path of the source file when there is one.
+    #[cfg(feature = "runtime-shaders")]
+    pub fn compile_with_options(name: &str, kind: &Kind, shader_source: &str, options: &CompileOptions) -> Result<Vec<u8>> {
+        let mut compiler = shaderc::Compiler::new().ok_or("Failed to create the shader compiler")?;
+        let mut opts = shaderc::CompileOptions::new().ok_or("Failed to create shader compile options")?;
+
+        for &(ref define_name, ref value) in &options.defines {
+            opts.add_macro_definition(define_name, value.as_ref().map(|value| value.as_str()));
+        }
+
+        opts.set_optimization_level(match options.optimization_level {
+            OptimizationLevel::Zero => shaderc::OptimizationLevel::Zero,
+            OptimizationLevel::Size => shaderc::OptimizationLevel::Size,
+            OptimizationLevel::Performance => shaderc::OptimizationLevel::Performance,
+        });
+
+        match options.target_env {
+            TargetEnv::Vulkan => opts.set_target_env(shaderc::TargetEnv::Vulkan, 0),
+            TargetEnv::OpenGl => opts.set_target_env(shaderc::TargetEnv::OpenGL, 0),
+        }
+
+        if options.debug_info {
+            opts.set_generate_debug_info();
+        }
+
+        let include_dir = options.include_dir.clone();
+
+        opts.set_include_callback(move |requested_source, include_type, requesting_source, _depth| {
+            let relative_to_source = match include_type {
+                shaderc::IncludeType::Relative => true,
+                shaderc::IncludeType::Standard => false,
+            };
+
+            resolve_include(&include_dir, requested_source, requesting_source, relative_to_source).and_then(|path| {
+                match std::fs::read_to_string(&path) {
+                    Ok(content) => Ok(shaderc::ResolvedInclude {
+                        resolved_name: path.to_string_lossy().into_owned(),
+                        content,
+                    }),
+                    Err(e) => Err(format!("Failed to read '{}': {}", path.display(), e)),
+                }
+            })
+        });
 
         let shader_kind = match kind {
             Kind::Fragment => shaderc::ShaderKind::Fragment,
             Kind::Vertex => shaderc::ShaderKind::Vertex,
+            Kind::Compute => shaderc::ShaderKind::Compute,
+            Kind::Geometry => shaderc::ShaderKind::Geometry,
+            Kind::TessControl => shaderc::ShaderKind::TessControl,
+            Kind::TessEvaluation => shaderc::ShaderKind::TessEvaluation,
         };
 
         match compiler.compile_into_spirv(shader_source, shader_kind, name, "main", Some(&opts)) {
-            Ok(compilation_artifact) => Ok(compilation_artifact.as_binary_u8().to_vec()),
-            Err(error) => Err(Error::CompileError(format!(
-                "Failed to compile shader '{}':\nLog: {}",
-                &name, &error
-            ))),
+            Ok(compilation_artifact) => {
+                if compilation_artifact.get_num_warnings() > 0 {
+                    warn!(
+                        "Compiled shader '{}' with warnings:\n{}",
+                        name,
+                        compilation_artifact.get_warning_messages()
+                    );
+                }
+
+                Ok(compilation_artifact.as_binary_u8().to_vec())
+            }
+            Err(error) => {
+                let log = error.to_string();
+
+                match parse_error_location(&log) {
+                    Some((file, line, message)) => {
+                        error!("Failed to compile shader '{}':\n{}", name, log);
+                        Err(ErrorKind::ShaderSourceError(file, line, message).into())
+                    }
+                    None => Err(ErrorKind::ShaderCompileFailed(name.to_string(), log).into()),
+                }
+            }
+        }
+    }
+
+    /// Without the `runtime-shaders` feature, shaders can only be loaded from precompiled SPIR-V
+    #[cfg(not(feature = "runtime-shaders"))]
+    pub fn compile_with_options(name: &str, _kind: &Kind, _shader_source: &str, _options: &CompileOptions) -> Result<Vec<u8>> {
+        bail!(ErrorKind::ShaderCompilerUnavailable(name.to_string()))
+    }
+}
+
+/// Finds the file an `#include` refers to. `"quoted"` includes are looked up next to the including file first,
+/// then in the include directory, which is the only place `<bracketed>` includes are looked up.
+pub fn resolve_include(
+    include_dir: &Path,
+    requested_source: &str,
+    requesting_source: &str,
+    relative_to_source: bool,
+) -> ::std::result::Result<PathBuf, String> {
+    let mut candidates = Vec::new();
+
+    if relative_to_source {
+        if let Some(source_dir) = Path::new(requesting_source).parent() {
+            candidates.push(source_dir.join(requested_source));
         }
     }
+
+    candidates.push(include_dir.join(requested_source));
+
+    candidates
+        .into_iter()
+        .find(|path| path.is_file())
+        .ok_or_else(|| format!("Cannot find '{}' included by '{}'", requested_source, requesting_source))
+}
+
+/// Extracts the file, line and message of the first error in a compiler log,
+/// which are written as `file:line: error: message`
+pub fn parse_error_location(log: &str) -> Option<(String, u32, String)> {
+    log.lines()
+        .filter_map(|line| {
+            let error_start = line.find(": error: ")?;
+            let (location, message) = (&line[..error_start], &line[error_start + ": error: ".len()..]);
+
+            // Split on the last colon, as Windows paths contain one after the drive letter:
+            let line_start = location.rfind(':')?;
+            let line_number = location[line_start + 1..].trim().parse().ok()?;
+
+            Some((location[..line_start].to_string(), line_number, message.trim().to_string()))
+        })
+        .next()
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
 
-    const NAME : &str = "test_vs_shader";
+    const NAME: &str = "test_vs_shader";
 
     #[test]
+    #[cfg(feature = "runtime-shaders")]
     fn should_compile_shader() {
         let source = "#version 150 core\n void main() {}";
 
@@ -54,4 +309,93 @@ pub mod test {
 
         assert!(result.is_err());
     }
+
+    #[test]
+    #[cfg(feature = "runtime-shaders")]
+    fn should_compile_with_defines() {
+        let source = "#version 450\n#ifndef USE_COLOR\n#error USE_COLOR must be defined\n#endif\nvoid main() {}";
+
+        let options = CompileOptions::default().define("USE_COLOR", "1");
+
+        assert!(Loader::compile_with_options(&NAME, &Kind::Fragment, &source, &options).is_ok());
+        assert!(Loader::compile(&NAME, &Kind::Fragment, &source).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "runtime-shaders")]
+    fn should_compile_compute_shader() {
+        let source = "#version 450\nlayout(local_size_x = 64) in;\nvoid main() {}";
+
+        assert!(Loader::compile(&NAME, &Kind::Compute, &source).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "runtime-shaders")]
+    fn should_resolve_includes_from_shader_dir() {
+        let source = "#version 450\n#extension GL_GOOGLE_include_directive : require\n#include \"include/camera.glsl\"\nvoid main() {\n    gl_Position = camera.view_projection * vec4(0.0);\n}";
+
+        let result = Loader::compile(&NAME, &Kind::Vertex, &source);
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn should_find_included_files() {
+        let shader_dir = Path::new(SHADER_DIR);
+
+        assert_eq!(
+            Ok(shader_dir.join("include/camera.glsl")),
+            resolve_include(shader_dir, "include/camera.glsl", NAME, true)
+        );
+        assert!(resolve_include(shader_dir, "include/missing.glsl", NAME, true).is_err());
+    }
+
+    #[test]
+    fn should_find_runtime_dirs() {
+        let env_var = "CORPORATION_TEST_RUNTIME_DIR";
+
+        assert_eq!(PathBuf::from(SHADER_DIR), find_runtime_dir(env_var, "no/such/dir", SHADER_DIR));
+
+        env::set_var(env_var, "/opt/corporation/shaders");
+        assert_eq!(
+            PathBuf::from("/opt/corporation/shaders"),
+            find_runtime_dir(env_var, "no/such/dir", SHADER_DIR)
+        );
+        env::remove_var(env_var);
+    }
+
+    #[test]
+    fn should_find_kind_from_extension() {
+        assert_eq!(Some(Kind::Vertex), Kind::from_path(Path::new("shaders/quad_150.vert")));
+        assert_eq!(Some(Kind::TessEvaluation), Kind::from_path(Path::new("terrain.tese")));
+        assert_eq!(None, Kind::from_path(Path::new("shaders/include/camera.glsl")));
+    }
+
+    #[test]
+    #[cfg(feature = "runtime-shaders")]
+    fn should_report_error_location() {
+        let source = "#version 450\nvoid main() {\n    undeclared = 1.0;\n}";
+
+        match Loader::compile("shaders/broken.frag", &Kind::Fragment, &source) {
+            Err(Error(ErrorKind::ShaderSourceError(file, line, message), _)) => {
+                assert_eq!("shaders/broken.frag", file);
+                assert_eq!(3, line);
+                assert!(message.contains("undeclared"));
+            }
+            _ => panic!("Expected a ShaderSourceError"),
+        }
+    }
+
+    #[test]
+    fn should_parse_error_location() {
+        let log = "C:\\shaders\\quad.vert:12: error: 'model' : undeclared identifier\n1 error generated.";
+        assert_eq!(
+            Some((
+                "C:\\shaders\\quad.vert".to_string(),
+                12,
+                "'model' : undeclared identifier".to_string()
+            )),
+            parse_error_location(log)
+        );
+    }
 }
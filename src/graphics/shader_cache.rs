@@ -0,0 +1,349 @@
+use errors::*;
+use graphics::shader::{self, CompileOptions, Kind};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Environment variable used to read precompiled SPIR-V from a directory other than the one `spirv_cache_dir` would find
+pub const SPIRV_DIR_ENV_VAR: &str = "CORPORATION_SPIRV_DIR";
+
+/// The file in the cache directory that lists the SPIR-V entry for each shader and set of options
+pub const MANIFEST_FILE_NAME: &str = "manifest.txt";
+
+/// Where precompiled SPIR-V is written by the `compile-shaders` tool by default, and read from at runtime:
+/// the directory named by `CORPORATION_SPIRV_DIR` if it is set, otherwise `spirv` within the shaders directory
+pub fn spirv_cache_dir() -> PathBuf {
+    env::var_os(SPIRV_DIR_ENV_VAR).map_or_else(|| shader::shader_dir().join("spirv"), PathBuf::from)
+}
+
+/// A 64-bit FNV-1a hash, which unlike `DefaultHasher` gives the same result across builds and Rust versions
+#[derive(Debug, Clone, Copy)]
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    /// Writes a length before the bytes, so that neighbouring values can't run into each other
+    fn write_field(&mut self, bytes: &[u8]) {
+        self.write(&(bytes.len() as u64).to_le_bytes());
+        self.write(bytes);
+    }
+}
+
+/// Stores compiled SPIR-V in files named by a hash of everything that affects the compiler's output:
+/// the shader source, its stage, the compile options and the contents of the include directory.
+/// Any change to these gives a new key, so stale SPIR-V is never loaded.
+pub struct ShaderCache {
+    dir: PathBuf,
+}
+
+impl ShaderCache {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        ShaderCache {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the SPIR-V for the shader, from the cache if it has been compiled with the same source and options before.
+    /// Otherwise the shader is compiled and added to the cache, which requires the `runtime-shaders` feature.
+    pub fn load_or_compile(&self, file_path: &str, kind: &Kind, source: &str, options: &CompileOptions) -> Result<Vec<u8>> {
+        let key = Self::key(kind, source, options);
+
+        if let Some(spirv) = self.load(file_path, options, &key) {
+            return Ok(spirv);
+        }
+
+        let spirv = shader::Loader::compile_with_options(file_path, kind, source, options)?;
+
+        // A cache that can't be written to only costs compile time, so isn't an error:
+        if let Err(e) = self.store(file_path, options, &key, &spirv) {
+            warn!("Failed to cache SPIR-V for shader '{}': {}", file_path, e);
+        }
+
+        Ok(spirv)
+    }
+
+    pub fn load(&self, file_path: &str, options: &CompileOptions, key: &str) -> Option<Vec<u8>> {
+        fs::read(self.entry_path(file_path, options, key)).ok()
+    }
+
+    /// Returns the SPIR-V the manifest lists for the shader, without reading or hashing its source,
+    /// for builds that can't compile shaders and so have no use for the source
+    pub fn load_precompiled(&self, file_path: &str, options: &CompileOptions) -> Result<Vec<u8>> {
+        let manifest = ShaderManifest::load(&self.dir).chain_err(|| ErrorKind::ShaderCompilerUnavailable(file_path.to_string()))?;
+        let entry_file_name = manifest
+            .get(file_path, options)
+            .ok_or_else(|| ErrorKind::ShaderCompilerUnavailable(file_path.to_string()))?;
+
+        fs::read(self.dir.join(entry_file_name)).chain_err(|| ErrorKind::ShaderCompilerUnavailable(file_path.to_string()))
+    }
+
+    /// Writes the SPIR-V for a shader, removing any entries for the same file and options that were compiled from older sources.
+    /// The manifest is updated to list the new entry, so it never refers to one that has been removed.
+    pub fn store(&self, file_path: &str, options: &CompileOptions, key: &str, spirv: &[u8]) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let entry_path = self.entry_path(file_path, options, key);
+        let prefix = Self::entry_prefix(file_path, options);
+
+        for entry in fs::read_dir(&self.dir)?.filter_map(|entry| entry.ok()) {
+            let is_stale = entry.file_name().to_str().map_or(false, |name| name.starts_with(&prefix));
+
+            if is_stale && entry.path() != entry_path {
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        fs::write(&entry_path, spirv)?;
+
+        let mut manifest = ShaderManifest::load(&self.dir).unwrap_or_default();
+        manifest.insert(file_path, options, &entry_path);
+        manifest.save(&self.dir)
+    }
+
+    /// The path of the SPIR-V for a shader, e.g. `quad_150.vert.89abcdef.0123456789abcdef.spv`,
+    /// where the first hash identifies the options so that each set of options keeps its own entry
+    pub fn entry_path(&self, file_path: &str, options: &CompileOptions, key: &str) -> PathBuf {
+        self.dir.join(format!("{}{}.spv", Self::entry_prefix(file_path, options), key))
+    }
+
+    fn entry_prefix(file_path: &str, options: &CompileOptions) -> String {
+        format!("{}.", entry_name(file_path, options))
+    }
+
+    /// Hashes the inputs of a compilation into a key
+    pub fn key(kind: &Kind, source: &str, options: &CompileOptions) -> String {
+        let mut hasher = Fnv1a::new();
+
+        hasher.write_field(format!("{:?}", kind).as_bytes());
+        hasher.write_field(source.as_bytes());
+        hash_options(&mut hasher, options);
+
+        // Which files are included isn't known until the shader is preprocessed, so every file that could be is hashed:
+        for include_path in find_files(&options.include_dir) {
+            if let Ok(include_source) = fs::read(&include_path) {
+                let relative_path = include_path.strip_prefix(&options.include_dir).unwrap_or(&include_path);
+
+                hasher.write_field(relative_path.to_string_lossy().as_bytes());
+                hasher.write_field(&include_source);
+            }
+        }
+
+        format!("{:016x}", hasher.0)
+    }
+}
+
+/// Maps each shader and set of options to the file holding its SPIR-V, one `<shader> <entry>` line each,
+/// so that builds without the runtime compiler can find the SPIR-V for a shader without its source.
+/// Shaders are listed by file name and options hash, e.g. `quad_150.vert.89abcdef`, which don't depend on where
+/// the shaders directory is.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShaderManifest {
+    entries: BTreeMap<String, String>,
+}
+
+impl ShaderManifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the manifest in a cache directory, which is missing until SPIR-V has been cached there
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let path = dir.as_ref().join(MANIFEST_FILE_NAME);
+
+        if !path.is_file() {
+            bail!(ErrorKind::AssetNotFound(path.to_string_lossy().into()));
+        }
+
+        Self::parse(&fs::read_to_string(&path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        fs::create_dir_all(dir.as_ref())?;
+        fs::write(dir.as_ref().join(MANIFEST_FILE_NAME), self.to_string())?;
+
+        Ok(())
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut manifest = Self::new();
+
+        for (index, line) in contents.lines().enumerate().filter(|&(_, line)| !line.trim().is_empty()) {
+            let mut fields = line.split_whitespace();
+
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(name), Some(entry_file_name), None) => {
+                    manifest.entries.insert(name.to_string(), entry_file_name.to_string());
+                }
+                _ => bail!(ErrorKind::ImportFailed(
+                    MANIFEST_FILE_NAME.to_string(),
+                    format!("line {} should be `<shader> <entry>`", index + 1)
+                )),
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    /// Lists the SPIR-V at `entry_path` as the shader's, replacing any entry listed for it before
+    pub fn insert<P: AsRef<Path>>(&mut self, file_path: &str, options: &CompileOptions, entry_path: P) {
+        let entry_file_name = entry_path
+            .as_ref()
+            .file_name()
+            .map_or_else(|| entry_path.as_ref().to_string_lossy(), |file_name| file_name.to_string_lossy());
+
+        self.entries.insert(entry_name(file_path, options), entry_file_name.into_owned());
+    }
+
+    /// The file name of the shader's SPIR-V within the cache directory
+    pub fn get(&self, file_path: &str, options: &CompileOptions) -> Option<&str> {
+        self.entries.get(&entry_name(file_path, options)).map(|entry| entry.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl std::fmt::Display for ShaderManifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (name, entry_file_name) in &self.entries {
+            writeln!(f, "{} {}", name, entry_file_name)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Names a shader and set of options by the shader's file name and a hash of the options
+fn entry_name(file_path: &str, options: &CompileOptions) -> String {
+    let file_name = Path::new(file_path)
+        .file_name()
+        .map_or(file_path.into(), |file_name| file_name.to_string_lossy());
+
+    let mut hasher = Fnv1a::new();
+    hash_options(&mut hasher, options);
+
+    format!("{}.{:08x}", file_name, hasher.0 as u32)
+}
+
+fn hash_options(hasher: &mut Fnv1a, options: &CompileOptions) {
+    for &(ref name, ref value) in &options.defines {
+        hasher.write_field(name.as_bytes());
+        hasher.write_field(value.as_ref().map_or(&[][..], |value| value.as_bytes()));
+    }
+
+    hasher.write_field(format!("{:?} {:?} {}", options.optimization_level, options.target_env, options.debug_info).as_bytes());
+}
+
+/// Finds the GLSL files beneath a directory, sorted so that they're always visited in the same order
+pub fn find_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+            if path.is_dir() {
+                dirs.push(path);
+            } else if Kind::from_path(&path).is_some() || path.extension().map_or(false, |extension| extension == "glsl") {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+
+    files
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::env;
+
+    const SOURCE: &str = "#version 450\nvoid main() {}";
+
+    #[test]
+    fn should_change_key_with_inputs() {
+        let options = CompileOptions::debug();
+        let key = ShaderCache::key(&Kind::Vertex, SOURCE, &options);
+
+        assert_eq!(16, key.len());
+        assert_eq!(key, ShaderCache::key(&Kind::Vertex, SOURCE, &options));
+        assert_ne!(key, ShaderCache::key(&Kind::Fragment, SOURCE, &options));
+        assert_ne!(key, ShaderCache::key(&Kind::Vertex, "#version 450\nvoid main() { }", &options));
+        assert_ne!(key, ShaderCache::key(&Kind::Vertex, SOURCE, &CompileOptions::release()));
+        assert_ne!(
+            key,
+            ShaderCache::key(&Kind::Vertex, SOURCE, &options.clone().define_flag("SHADOWS"))
+        );
+    }
+
+    #[test]
+    fn should_replace_stale_entries() {
+        let dir = env::temp_dir().join(format!("corporation_shader_cache_{}", std::process::id()));
+        let cache = ShaderCache::new(&dir);
+        let (debug, release) = (CompileOptions::debug(), CompileOptions::release());
+
+        cache.store("shaders/quad.vert", &debug, "0000000000000001", &[1, 2, 3, 4]).unwrap();
+        cache
+            .store("shaders/quad.vert", &release, "0000000000000001", &[5, 6, 7, 8])
+            .unwrap();
+        cache
+            .store("shaders/quad.vert", &debug, "0000000000000002", &[9, 10, 11, 12])
+            .unwrap();
+
+        assert_eq!(None, cache.load("shaders/quad.vert", &debug, "0000000000000001"));
+        assert_eq!(
+            Some(vec![9, 10, 11, 12]),
+            cache.load("shaders/quad.vert", &debug, "0000000000000002")
+        );
+        assert_eq!(
+            Some(vec![5, 6, 7, 8]),
+            cache.load("shaders/quad.vert", &release, "0000000000000001")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn should_load_precompiled_entries_from_manifest() {
+        let dir = env::temp_dir().join(format!("corporation_shader_manifest_{}", std::process::id()));
+        let cache = ShaderCache::new(&dir);
+        let (debug, release) = (CompileOptions::debug(), CompileOptions::release());
+
+        cache.store("shaders/quad.vert", &debug, "0000000000000001", &[1, 2, 3, 4]).unwrap();
+        cache.store("shaders/quad.vert", &debug, "0000000000000002", &[5, 6, 7, 8]).unwrap();
+
+        // Shaders are found by file name, wherever the shaders directory is:
+        assert_eq!(vec![5, 6, 7, 8], cache.load_precompiled("/elsewhere/quad.vert", &debug).unwrap());
+        assert!(cache.load_precompiled("shaders/quad.vert", &release).is_err());
+        assert!(cache.load_precompiled("shaders/quad.frag", &debug).is_err());
+
+        let manifest = ShaderManifest::load(&dir).unwrap();
+        assert_eq!(1, manifest.len());
+        assert_eq!(manifest, ShaderManifest::parse(&manifest.to_string()).unwrap());
+        assert!(ShaderManifest::parse("quad.vert.89abcdef\n").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,578 @@
+use errors::*;
+use gfx_hal::format as f;
+use gfx_hal::image as i;
+use gfx_hal::memory as m;
+use gfx_hal::pso::PipelineStage;
+use gfx_hal::*;
+use graphics::adapter::AdapterState;
+use graphics::buffer::BufferState;
+use graphics::device::DeviceState;
+use graphics::image::TextureState;
+use graphics::texel::{self, BlockLayout};
+use graphics::texture::{TextureHandle, TextureManager};
+use graphics::tracker::ResourceId;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The smallest staging buffer created, so that small updates such as font glyphs share one
+pub const MIN_STAGING_SIZE: u64 = 256 * 1024;
+
+/// Copies from the staging buffer must start on a multiple of the texel block size, which this is for every format
+const STAGING_OFFSET_ALIGNMENT: u64 = 16;
+
+/// A rectangle of texels within one level of a texture
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextureRegion {
+    pub level: i::Level,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl TextureRegion {
+    /// Creates a region of the full size level of a texture
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        TextureRegion {
+            level: 0,
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Covers the whole of a level of the texture
+    pub fn whole_level<B: Backend>(texture_state: &TextureState<B>, level: i::Level) -> Self {
+        let (width, height) = texture_state.get_level_dimensions(level);
+        TextureRegion::new(0, 0, width, height).level(level)
+    }
+
+    pub fn level(mut self, level: i::Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Checks that the region lies within a level of the given size and covers whole blocks,
+    /// returning the number of blocks it covers across and down
+    pub fn block_count(&self, block_layout: &BlockLayout, level_dimensions: (u32, u32)) -> Result<(u32, u32)> {
+        let (level_width, level_height) = level_dimensions;
+
+        // Offsets and sizes come from the caller, so their sums are checked rather than allowed to overflow:
+        let is_within = |offset: u32, size: u32, level_size: u32| offset.checked_add(size).map_or(false, |end| end <= level_size);
+
+        if self.width == 0
+            || self.height == 0
+            || !is_within(self.x, self.width, level_width)
+            || !is_within(self.y, self.height, level_height)
+        {
+            bail!(ErrorKind::InvalidTextureUpdate(format!(
+                "{:?} is empty or lies outside the {}x{} level",
+                self, level_width, level_height
+            )));
+        }
+
+        // Blocks can't be partly written, so the region must start on a block and end on one or at the edge of the level:
+        let is_block_aligned = |offset: u32, size: u32, block_size: u32, level_size: u32| {
+            offset % block_size == 0 && (size % block_size == 0 || offset + size == level_size)
+        };
+
+        if !is_block_aligned(self.x, self.width, block_layout.width, level_width)
+            || !is_block_aligned(self.y, self.height, block_layout.height, level_height)
+        {
+            bail!(ErrorKind::InvalidTextureUpdate(format!(
+                "{:?} does not cover whole {}x{} blocks",
+                self, block_layout.width, block_layout.height
+            )));
+        }
+
+        Ok(block_layout.block_count(self.width, self.height))
+    }
+}
+
+/// Hands out space in a staging buffer, growing it when an update doesn't fit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StagingAllocator {
+    capacity: u64,
+    offset: u64,
+}
+
+impl StagingAllocator {
+    pub fn new() -> Self {
+        StagingAllocator { capacity: 0, offset: 0 }
+    }
+
+    /// Returns where `size` bytes can be written, or `None` if the buffer must grow first
+    pub fn allocate(&mut self, size: u64, alignment: u64) -> Option<u64> {
+        let offset = (self.offset + alignment - 1) / alignment * alignment;
+
+        if offset + size > self.capacity {
+            return None;
+        }
+
+        self.offset = offset + size;
+        Some(offset)
+    }
+
+    /// The capacity of a buffer replacing this one that is large enough for `size` bytes.
+    /// The buffer at least doubles in size, so that a buffer that is too small is only replaced a few times.
+    pub fn grown_capacity(&self, size: u64) -> u64 {
+        size.max(self.capacity * 2).max(MIN_STAGING_SIZE)
+    }
+
+    /// Replaces the buffer with one of `grown_capacity(size)`, returning its capacity
+    pub fn grow(&mut self, size: u64) -> u64 {
+        self.capacity = self.grown_capacity(size);
+        self.offset = 0;
+        self.capacity
+    }
+
+    /// Makes the whole buffer available again, once the copies from it have completed
+    pub fn reset(&mut self) {
+        self.offset = 0;
+    }
+}
+
+impl Default for StagingAllocator {
+    fn default() -> Self {
+        StagingAllocator::new()
+    }
+}
+
+/// Handles to the textures that a frame's copies write to, so they aren't released until the copies have completed
+#[derive(Debug, Default)]
+pub struct PendingTextures {
+    textures: Vec<TextureHandle>,
+}
+
+impl PendingTextures {
+    pub fn new() -> Self {
+        PendingTextures { textures: Vec::new() }
+    }
+
+    /// Keeps the texture alive, holding a single handle however many times it is updated
+    pub fn hold(&mut self, texture: &TextureHandle) {
+        if !self.textures.contains(texture) {
+            self.textures.push(texture.clone());
+        }
+    }
+
+    /// Drops the handles once the copies have completed, letting textures without other handles be released
+    pub fn clear(&mut self) {
+        self.textures.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.textures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.textures.is_empty()
+    }
+}
+
+/// The updates recorded for one frame, which are submitted together
+struct StreamingFrame<B: Backend> {
+    command_pool: Option<CommandPool<B, Graphics>>,
+    command_buffer: Option<command::CommandBuffer<B, Graphics, command::OneShot>>,
+    staging_buffer: Option<BufferState<B, Graphics>>,
+    staging_allocator: StagingAllocator,
+    // Buffers that were too small, which are kept until the copies already recorded from them have completed:
+    retired_buffers: Vec<BufferState<B, Graphics>>,
+    pending_textures: PendingTextures,
+    fence: Option<B::Fence>,
+    submitted: bool,
+}
+
+/// Updates the contents of textures after they have been created, from CPU data such as video frames or font atlases.
+///
+/// Updates are recorded into the current frame and copied from staging buffers that are reused once the frame's
+/// copies have completed, so streaming a texture every frame doesn't allocate. The copies are submitted to the same
+/// queue as the frames that draw with the textures, and wait for frames already in flight to finish reading them.
+pub struct TextureStreamer<B: Backend> {
+    device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+    row_alignment_mask: u32,
+    frames: Vec<StreamingFrame<B>>,
+    frame_index: usize,
+    resource_ids: Vec<ResourceId>,
+}
+
+impl<B: Backend> TextureStreamer<B> {
+    /// Creates a streamer that cycles through `frame_count` sets of staging buffers.
+    /// Updates only wait for earlier copies when every set is still in use.
+    pub unsafe fn new(
+        device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+        adapter_state: &AdapterState<B>,
+        frame_count: usize,
+    ) -> Result<Self> {
+        let mut frames = Vec::with_capacity(frame_count);
+        let mut resource_ids = Vec::new();
+
+        {
+            let device_state = device_state.as_ref().borrow();
+
+            for _ in 0..frame_count.max(1) {
+                let command_pool = device_state.create_command_pool()?;
+                resource_ids.push(track_resource!(device_state, CommandPool));
+
+                let fence = device_state
+                    .device
+                    .create_fence(false)
+                    .graphics_err("create texture streaming fence")?;
+                resource_ids.push(track_resource!(device_state, Fence));
+
+                frames.push(StreamingFrame {
+                    command_pool: Some(command_pool),
+                    command_buffer: None,
+                    staging_buffer: None,
+                    staging_allocator: StagingAllocator::new(),
+                    retired_buffers: Vec::new(),
+                    pending_textures: PendingTextures::new(),
+                    fence: Some(fence),
+                    submitted: false,
+                });
+            }
+        }
+
+        Ok(TextureStreamer {
+            device_state,
+            row_alignment_mask: adapter_state.limits.min_buffer_copy_pitch_alignment as u32 - 1,
+            frames,
+            frame_index: 0,
+            resource_ids,
+        })
+    }
+
+    /// Replaces a region of the texture with `data`, which holds tightly packed rows of texel blocks in the
+    /// texture's format. The new contents are seen by frames submitted after the next call to `submit`.
+    /// Other levels of the texture's mip chain are left as they were.
+    ///
+    /// The streamer keeps a handle to the texture until the copy has completed, so the texture isn't
+    /// released by `TextureManager::release_unused` while the copy may still write to it.
+    pub unsafe fn update(
+        &mut self,
+        texture_manager: &TextureManager<B>,
+        texture: &TextureHandle,
+        region: TextureRegion,
+        data: &[u8],
+    ) -> Result<()> {
+        let texture_state = texture_manager.get_texture(texture);
+        let format = texture_state.get_format();
+        let block_layout = texel::block_layout(format).ok_or_else(|| ErrorKind::UnsupportedTextureFormat(format!("{:?}", format)))?;
+
+        if region.level >= texture_state.mip_level_count() {
+            bail!(ErrorKind::InvalidTextureUpdate(format!(
+                "the texture has no level {}",
+                region.level
+            )));
+        }
+
+        let (blocks_wide, blocks_high) = region.block_count(&block_layout, texture_state.get_level_dimensions(region.level))?;
+
+        // Rows are padded to the pitch required for copies, as with Image::row_pitch:
+        let mask = self.row_alignment_mask;
+        let row_size = blocks_wide.checked_mul(block_layout.size);
+        let row_pitch = row_size
+            .and_then(|row_size| row_size.checked_add(mask))
+            .map(|padded_size| padded_size & !mask);
+        let staging_size = row_pitch.and_then(|row_pitch| row_pitch.checked_mul(blocks_high));
+
+        let (row_size, row_pitch, staging_size) = match (row_size, row_pitch, staging_size) {
+            (Some(row_size), Some(row_pitch), Some(staging_size)) => (row_size, row_pitch, staging_size),
+            _ => bail!(ErrorKind::InvalidTextureUpdate(format!("{:?} is too large to copy", region))),
+        };
+
+        let region_size = u64::from(row_size) * u64::from(blocks_high);
+
+        if data.len() as u64 != region_size {
+            bail!(ErrorKind::InvalidTextureUpdate(format!(
+                "{} bytes were given for a region of {} bytes",
+                data.len(),
+                region_size
+            )));
+        }
+
+        let alignment = STAGING_OFFSET_ALIGNMENT.max(u64::from(self.row_alignment_mask) + 1);
+
+        let device_state = Rc::clone(&self.device_state);
+        let frame = self.begin_frame()?;
+
+        let offset = match frame.staging_allocator.allocate(u64::from(staging_size), alignment) {
+            Some(offset) => offset,
+            None => {
+                // The old buffer is kept, and the allocator left alone, until its replacement has been created:
+                let capacity = frame.staging_allocator.grown_capacity(u64::from(staging_size));
+                let staging_buffer = BufferState::new_host_visible(device_state, capacity, buffer::Usage::TRANSFER_SRC)?;
+
+                if let Some(retired_buffer) = frame.staging_buffer.replace(staging_buffer) {
+                    frame.retired_buffers.push(retired_buffer);
+                }
+
+                frame.staging_allocator.grow(u64::from(staging_size));
+                frame.staging_allocator.allocate(u64::from(staging_size), alignment).unwrap()
+            }
+        };
+
+        let staging_buffer = frame.staging_buffer.as_mut().unwrap();
+
+        if row_pitch == row_size {
+            staging_buffer.update_data(offset, data)?;
+        } else {
+            let mut padded_data = vec![0u8; staging_size as usize];
+
+            for (row_index, row) in data.chunks(row_size as usize).enumerate() {
+                let start = row_index * row_pitch as usize;
+                padded_data[start..start + row.len()].copy_from_slice(row);
+            }
+
+            staging_buffer.update_data(offset, &padded_data)?;
+        }
+
+        let range = i::SubresourceRange {
+            aspects: f::Aspects::COLOR,
+            levels: region.level..region.level + 1,
+            layers: 0..1,
+        };
+
+        let command_buffer = frame.command_buffer.as_mut().unwrap();
+
+        // Frames submitted before this update must finish sampling the texture before it is written:
+        command_buffer.pipeline_barrier(
+            PipelineStage::FRAGMENT_SHADER..PipelineStage::TRANSFER,
+            m::Dependencies::empty(),
+            &[m::Barrier::Image {
+                states: (i::Access::SHADER_READ, i::Layout::ShaderReadOnlyOptimal)
+                    ..(i::Access::TRANSFER_WRITE, i::Layout::TransferDstOptimal),
+                target: texture_state.get_image(),
+                families: None,
+                range: range.clone(),
+            }],
+        );
+
+        command_buffer.copy_buffer_to_image(
+            staging_buffer.get_buffer(),
+            texture_state.get_image(),
+            i::Layout::TransferDstOptimal,
+            &[command::BufferImageCopy {
+                buffer_offset: offset,
+                buffer_width: row_pitch / block_layout.size * block_layout.width,
+                buffer_height: blocks_high * block_layout.height,
+                image_layers: i::SubresourceLayers {
+                    aspects: f::Aspects::COLOR,
+                    level: region.level,
+                    layers: 0..1,
+                },
+                image_offset: i::Offset {
+                    x: region.x as i32,
+                    y: region.y as i32,
+                    z: 0,
+                },
+                image_extent: i::Extent {
+                    width: region.width,
+                    height: region.height,
+                    depth: 1,
+                },
+            }],
+        );
+
+        command_buffer.pipeline_barrier(
+            PipelineStage::TRANSFER..PipelineStage::FRAGMENT_SHADER,
+            m::Dependencies::empty(),
+            &[m::Barrier::Image {
+                states: (i::Access::TRANSFER_WRITE, i::Layout::TransferDstOptimal)
+                    ..(i::Access::SHADER_READ, i::Layout::ShaderReadOnlyOptimal),
+                target: texture_state.get_image(),
+                families: None,
+                range,
+            }],
+        );
+
+        frame.pending_textures.hold(texture);
+
+        Ok(())
+    }
+
+    /// Submits the updates recorded since the last call, which should be made before the frame that draws with them is submitted.
+    /// Textures whose copies have since completed are let go of, so they can be released once they have no other handles.
+    pub unsafe fn submit(&mut self) -> Result<()> {
+        self.reclaim_completed()?;
+
+        let frame = &mut self.frames[self.frame_index];
+
+        let mut command_buffer = match frame.command_buffer.take() {
+            Some(command_buffer) => command_buffer,
+            None => return Ok(()),
+        };
+
+        command_buffer.finish();
+
+        self.device_state.as_ref().borrow_mut().queue_group.queues[0]
+            .submit_nosemaphores(std::iter::once(&command_buffer), frame.fence.as_ref());
+
+        frame.submitted = true;
+        self.frame_index = (self.frame_index + 1) % self.frames.len();
+
+        Ok(())
+    }
+
+    /// Drops the texture handles and retired buffers of every frame whose copies have completed, without blocking
+    unsafe fn reclaim_completed(&mut self) -> Result<()> {
+        let device_state = self.device_state.as_ref().borrow();
+
+        for frame in &mut self.frames {
+            if !frame.submitted || frame.pending_textures.is_empty() {
+                continue;
+            }
+
+            let is_complete = device_state
+                .device
+                .get_fence_status(frame.fence.as_ref().unwrap())
+                .graphics_err("get texture streaming fence status")?;
+
+            if is_complete {
+                frame.pending_textures.clear();
+                frame.retired_buffers.clear();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the current frame, ready to record updates into. A frame's staging buffers are only
+    /// reused once the copies submitted from them last time around have completed.
+    unsafe fn begin_frame(&mut self) -> Result<&mut StreamingFrame<B>> {
+        let frame = &mut self.frames[self.frame_index];
+
+        if frame.command_buffer.is_some() {
+            return Ok(frame);
+        }
+
+        if frame.submitted {
+            let device_state = self.device_state.as_ref().borrow();
+            let fence = frame.fence.as_ref().unwrap();
+
+            device_state
+                .device
+                .wait_for_fence(fence, !0)
+                .graphics_err("wait for texture streaming fence")?;
+            device_state
+                .device
+                .reset_fence(fence)
+                .graphics_err("reset texture streaming fence")?;
+
+            frame.submitted = false;
+        }
+
+        frame.retired_buffers.clear();
+        frame.pending_textures.clear();
+        frame.staging_allocator.reset();
+
+        let command_pool = frame.command_pool.as_mut().unwrap();
+        command_pool.reset();
+
+        let mut command_buffer = command_pool.acquire_command_buffer::<command::OneShot>();
+        command_buffer.begin();
+        frame.command_buffer = Some(command_buffer);
+
+        Ok(frame)
+    }
+}
+
+impl<B: Backend> Drop for TextureStreamer<B> {
+    fn drop(&mut self) {
+        // Updates that were recorded but never submitted are discarded:
+        for frame in &mut self.frames {
+            if frame.submitted {
+                let device_state = self.device_state.as_ref().borrow();
+
+                unsafe {
+                    if let Err(e) = device_state
+                        .device
+                        .wait_for_fence(frame.fence.as_ref().unwrap(), !0)
+                        .graphics_err("wait for texture streaming fence")
+                    {
+                        error!("Failed to wait for texture updates to complete: {}", e);
+                    }
+                }
+            }
+
+            frame.command_buffer.take();
+            frame.staging_buffer.take();
+            frame.retired_buffers.clear();
+            frame.pending_textures.clear();
+        }
+
+        let device_state = self.device_state.as_ref().borrow();
+        let device = &device_state.device;
+
+        for frame in &mut self.frames {
+            unsafe {
+                device.destroy_fence(frame.fence.take().unwrap());
+                device.destroy_command_pool(frame.command_pool.take().unwrap().into_raw());
+            }
+        }
+
+        device_state.resource_tracker.borrow_mut().release_all(&self.resource_ids);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use graphics::texture::TextureSlots;
+
+    #[test]
+    fn should_validate_texture_regions() {
+        let rgba = texel::block_layout(f::Format::Rgba8Srgb).unwrap();
+        let bc1 = texel::block_layout(f::Format::Bc1RgbaSrgb).unwrap();
+
+        assert_eq!((3, 2), TextureRegion::new(1, 2, 3, 2).block_count(&rgba, (4, 4)).unwrap());
+        assert!(TextureRegion::new(2, 2, 3, 2).block_count(&rgba, (4, 4)).is_err());
+        assert!(TextureRegion::new(0, 0, 0, 2).block_count(&rgba, (4, 4)).is_err());
+        assert!(TextureRegion::new(std::u32::MAX, 0, 2, 2).block_count(&rgba, (4, 4)).is_err());
+        assert!(TextureRegion::new(0, 2, 2, std::u32::MAX).block_count(&rgba, (4, 4)).is_err());
+
+        // Regions of compressed textures must cover whole blocks, except at the edges of the level:
+        assert_eq!((2, 1), TextureRegion::new(4, 0, 8, 4).block_count(&bc1, (16, 16)).unwrap());
+        assert_eq!((1, 1), TextureRegion::new(8, 8, 2, 2).block_count(&bc1, (10, 10)).unwrap());
+        assert!(TextureRegion::new(2, 0, 4, 4).block_count(&bc1, (16, 16)).is_err());
+        assert!(TextureRegion::new(0, 0, 6, 4).block_count(&bc1, (16, 16)).is_err());
+    }
+
+    #[test]
+    fn should_hold_updated_textures_until_copies_complete() {
+        let mut textures = TextureSlots::new();
+        let mut pending_textures = PendingTextures::new();
+
+        // The texture is updated twice, then its last handle is dropped before the copies have completed:
+        let handle = textures.insert(Some("video.png"), 1);
+        pending_textures.hold(&handle);
+        pending_textures.hold(&handle);
+        drop(handle);
+
+        assert_eq!(1, pending_textures.len());
+        assert!(!textures.has_unused());
+        assert!(textures.release_unused().is_empty());
+
+        pending_textures.clear();
+
+        assert_eq!(vec![1], textures.release_unused());
+    }
+
+    #[test]
+    fn should_reuse_staging_space() {
+        let mut staging_allocator = StagingAllocator::new();
+
+        assert_eq!(None, staging_allocator.allocate(100, 16));
+        assert_eq!(MIN_STAGING_SIZE, staging_allocator.grown_capacity(100));
+        assert_eq!(None, staging_allocator.allocate(100, 16));
+        assert_eq!(MIN_STAGING_SIZE, staging_allocator.grow(100));
+        assert_eq!(Some(0), staging_allocator.allocate(100, 16));
+        assert_eq!(Some(112), staging_allocator.allocate(100, 16));
+
+        staging_allocator.reset();
+        assert_eq!(Some(0), staging_allocator.allocate(MIN_STAGING_SIZE, 256));
+        assert_eq!(None, staging_allocator.allocate(1, 256));
+        assert_eq!(MIN_STAGING_SIZE * 2, staging_allocator.grow(1));
+    }
+}
@@ -1,3 +1,4 @@
+use errors::*;
 use gfx_hal::format as gfx_format;
 use gfx_hal::format::{AsFormat, ChannelType};
 use gfx_hal::image as gfx_image;
@@ -25,7 +26,7 @@ impl<B: Backend> SwapchainState<B> {
         backend_state: &mut BackendState<B>,
         device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
         frame_extent: window::Extent2D,
-    ) -> Self {
+    ) -> Result<Self> {
         let (caps, formats, _present_modes, _comp_alpha) = backend_state.surface.compatibility(&device_state.as_ref().borrow().phys_device);
 
         info!("Formats: {:?}", &formats);
@@ -50,14 +51,14 @@ impl<B: Backend> SwapchainState<B> {
             .borrow()
             .device
             .create_swapchain(&mut backend_state.surface, swap_config, None)
-            .expect("Failed to create swapchain!");
+            .map_err(|error| ErrorKind::SurfaceLost(format!("{:?}", error)))?;
 
-        SwapchainState {
+        Ok(SwapchainState {
             swapchain: Some(swapchain),
             backbuffer: Some(backbuffer),
             device_state,
             extent,
             format,
-        }
+        })
     }
 }
@@ -0,0 +1,328 @@
+use camera::Camera;
+use errors::*;
+use gfx_hal::format as f;
+use gfx_hal::image as i;
+use gfx_hal::*;
+use graphics::depth::DepthState;
+use graphics::device::DeviceState;
+use graphics::pipeline::{PipelineDesc, PipelineState};
+use graphics::renderer::RenderPassState;
+use graphics::sampler::SamplerDesc;
+use graphics::texture::{TextureHandle, TextureManager};
+use graphics::tracker::ResourceId;
+use graphics::uniform::{CameraUniformBlock, DynamicUniform};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The size and formats of a render target's attachments, and how its output is sampled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderTargetDesc {
+    pub width: u32,
+    pub height: u32,
+    pub color_format: f::Format,
+    /// Whether the target has a depth attachment, which is only needed to draw overlapping meshes
+    pub depth: bool,
+    pub sampler_desc: SamplerDesc,
+}
+
+impl RenderTargetDesc {
+    /// Creates a description of an sRGB colour target with a depth attachment
+    pub fn new(width: u32, height: u32) -> Self {
+        RenderTargetDesc {
+            width,
+            height,
+            color_format: f::Format::Rgba8Srgb,
+            depth: true,
+            sampler_desc: SamplerDesc::default(),
+        }
+    }
+
+    pub fn color_format(mut self, color_format: f::Format) -> Self {
+        self.color_format = color_format;
+        self
+    }
+
+    pub fn without_depth(mut self) -> Self {
+        self.depth = false;
+        self
+    }
+
+    pub fn sampler_desc(mut self, sampler_desc: SamplerDesc) -> Self {
+        self.sampler_desc = sampler_desc;
+        self
+    }
+
+    pub fn get_extent(&self) -> i::Extent {
+        i::Extent {
+            width: self.width,
+            height: self.height,
+            depth: 1,
+        }
+    }
+
+    pub fn get_viewport(&self) -> pso::Viewport {
+        pso::Viewport {
+            rect: pso::Rect {
+                x: 0,
+                y: 0,
+                w: self.width as _,
+                h: self.height as _,
+            },
+            depth: 0.0..1.0,
+        }
+    }
+}
+
+/// An offscreen colour image, and optionally a depth image, that scenes are drawn into rather than the swapchain.
+/// The colour image is held by the TextureManager, so its handle can be given to materials and sampled like
+/// any other texture once the target has been drawn to, e.g. for mirrors, in-game monitors or post-processing.
+///
+/// Each target has its own pipeline, built for its render pass, and its own camera, so it can be drawn from a
+/// different viewpoint to the frame that samples it. Drawing waits for the target's previous drawing to complete.
+/// The pipeline is rebuilt when the renderer's shaders are reloaded, before the target is next drawn to.
+pub struct RenderTarget<B: Backend> {
+    desc: RenderTargetDesc,
+    texture: TextureHandle,
+    depth_state: Option<DepthState<B>>,
+    render_pass_state: RenderPassState<B>,
+    framebuffer: Option<B::Framebuffer>,
+    pipeline_state: PipelineState<B>,
+    /// The generation of the renderer's pipeline that the target's pipeline was built from
+    pipeline_generation: u64,
+    camera_uniform: DynamicUniform<B, CameraUniformBlock>,
+    command_pool: Option<CommandPool<B, Graphics>>,
+    fence: Option<B::Fence>,
+    submitted: bool,
+    device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+    resource_ids: Vec<ResourceId>,
+}
+
+impl<B: Backend> RenderTarget<B> {
+    /// Creates the target's attachments, and a pipeline from `pipeline_desc` for drawing into them.
+    /// The camera uniform only needs a single region, as each drawing waits for the last to complete.
+    pub unsafe fn new(
+        device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+        texture_manager: &mut TextureManager<B>,
+        desc: &RenderTargetDesc,
+        pipeline_desc: &PipelineDesc,
+        pipeline_generation: u64,
+        camera_uniform: DynamicUniform<B, CameraUniformBlock>,
+    ) -> Result<Self> {
+        let texture = texture_manager.create_render_target(desc.color_format, (desc.width, desc.height), &desc.sampler_desc)?;
+
+        let depth_state = if desc.depth {
+            Some(DepthState::new(Rc::clone(&device_state), desc.get_extent())?)
+        } else {
+            None
+        };
+
+        let render_pass_state = RenderPassState::new_offscreen(
+            desc.color_format,
+            depth_state.as_ref().map(|depth_state| depth_state.format),
+            Rc::clone(&device_state),
+        )?;
+
+        let pipeline_state = pipeline_desc.build(
+            &[texture_manager.get_set_layout(), camera_uniform.get_set_layout()],
+            render_pass_state.render_pass.as_ref().unwrap(),
+            Rc::clone(&device_state),
+        )?;
+
+        let (framebuffer, command_pool, fence, resource_ids) = {
+            let device_state = device_state.as_ref().borrow();
+
+            let mut attachments = vec![texture_manager.get_texture(&texture).get_image_view()];
+
+            if let Some(ref depth_state) = depth_state {
+                attachments.push(depth_state.get_image_view());
+            }
+
+            let framebuffer = device_state
+                .device
+                .create_framebuffer(render_pass_state.render_pass.as_ref().unwrap(), attachments, desc.get_extent())
+                .graphics_err("create render target framebuffer")?;
+            let mut resource_ids = vec![track_resource!(device_state, Framebuffer)];
+
+            let command_pool = device_state.create_command_pool()?;
+            resource_ids.push(track_resource!(device_state, CommandPool));
+
+            let fence = device_state.device.create_fence(false).graphics_err("create render target fence")?;
+            resource_ids.push(track_resource!(device_state, Fence));
+
+            (framebuffer, command_pool, fence, resource_ids)
+        };
+
+        Ok(RenderTarget {
+            desc: *desc,
+            texture,
+            depth_state,
+            render_pass_state,
+            framebuffer: Some(framebuffer),
+            pipeline_state,
+            pipeline_generation,
+            camera_uniform,
+            command_pool: Some(command_pool),
+            fence: Some(fence),
+            submitted: false,
+            device_state,
+            resource_ids,
+        })
+    }
+
+    /// The texture holding the target's colour output, for binding to materials
+    pub fn get_texture(&self) -> &TextureHandle {
+        &self.texture
+    }
+
+    pub fn get_desc(&self) -> &RenderTargetDesc {
+        &self.desc
+    }
+
+    pub fn get_render_pass(&self) -> &B::RenderPass {
+        self.render_pass_state.render_pass.as_ref().unwrap()
+    }
+
+    pub fn get_framebuffer(&self) -> &B::Framebuffer {
+        self.framebuffer.as_ref().unwrap()
+    }
+
+    pub fn get_pipeline_state(&self) -> &PipelineState<B> {
+        &self.pipeline_state
+    }
+
+    pub fn get_camera_uniform(&self) -> &DynamicUniform<B, CameraUniformBlock> {
+        &self.camera_uniform
+    }
+
+    /// Writes the camera the target is drawn from, returning the dynamic offset to bind it with.
+    /// Must only be called once `begin` has waited for the previous drawing to complete.
+    pub unsafe fn write_camera(&mut self, camera: &Camera<f32>) -> Result<u32> {
+        self.camera_uniform.write(0, &CameraUniformBlock::from(camera))
+    }
+
+    /// Rebuilds the pipeline from `pipeline_desc` if it was built from an older generation of the renderer's pipeline,
+    /// e.g. before its shaders were reloaded. The old pipeline is only destroyed once the drawing using it has completed.
+    pub unsafe fn update_pipeline(
+        &mut self,
+        texture_manager: &TextureManager<B>,
+        pipeline_desc: &PipelineDesc,
+        pipeline_generation: u64,
+    ) -> Result<()> {
+        if pipeline_generation == self.pipeline_generation {
+            return Ok(());
+        }
+
+        let pipeline_state = pipeline_desc.build(
+            &[texture_manager.get_set_layout(), self.camera_uniform.get_set_layout()],
+            self.render_pass_state.render_pass.as_ref().unwrap(),
+            Rc::clone(&self.device_state),
+        )?;
+
+        self.wait_for_drawing()?;
+
+        self.pipeline_state = pipeline_state;
+        self.pipeline_generation = pipeline_generation;
+
+        Ok(())
+    }
+
+    /// The values the attachments are cleared to at the start of the render pass, one for each attachment
+    pub fn clear_values(&self, clear_color: [f32; 4], clear_depth: f32) -> Vec<command::ClearValue> {
+        let mut clear_values = vec![command::ClearValue::Color(command::ClearColor::Float(clear_color))];
+
+        if self.depth_state.is_some() {
+            clear_values.push(command::ClearValue::DepthStencil(command::ClearDepthStencil(clear_depth, 0)));
+        }
+
+        clear_values
+    }
+
+    /// Returns a command buffer to record the target's drawing into, once the previous drawing has completed.
+    /// The camera uniform can be written from then on.
+    pub unsafe fn begin(&mut self) -> Result<command::CommandBuffer<B, Graphics, command::OneShot>> {
+        self.wait_for_drawing()?;
+
+        let command_pool = self.command_pool.as_mut().unwrap();
+        command_pool.reset();
+
+        let mut command_buffer = command_pool.acquire_command_buffer::<command::OneShot>();
+        command_buffer.begin();
+
+        Ok(command_buffer)
+    }
+
+    /// Submits the drawing recorded into the command buffer returned by `begin`. Frames submitted
+    /// afterwards see the new contents, as the render pass makes them wait for the drawing to complete.
+    pub unsafe fn submit(&mut self, mut command_buffer: command::CommandBuffer<B, Graphics, command::OneShot>) {
+        command_buffer.finish();
+
+        self.device_state.as_ref().borrow_mut().queue_group.queues[0]
+            .submit_nosemaphores(std::iter::once(&command_buffer), self.fence.as_ref());
+
+        self.submitted = true;
+    }
+
+    unsafe fn wait_for_drawing(&mut self) -> Result<()> {
+        if self.submitted {
+            let device_state = self.device_state.as_ref().borrow();
+            let fence = self.fence.as_ref().unwrap();
+
+            device_state
+                .device
+                .wait_for_fence(fence, !0)
+                .graphics_err("wait for render target fence")?;
+            device_state.device.reset_fence(fence).graphics_err("reset render target fence")?;
+
+            self.submitted = false;
+        }
+
+        Ok(())
+    }
+}
+
+impl<B: Backend> Drop for RenderTarget<B> {
+    fn drop(&mut self) {
+        let device_state = self.device_state.as_ref().borrow();
+        let device = &device_state.device;
+
+        unsafe {
+            if self.submitted {
+                if let Err(e) = device
+                    .wait_for_fence(self.fence.as_ref().unwrap(), !0)
+                    .graphics_err("wait for render target fence")
+                {
+                    error!("Failed to wait for render target to complete: {}", e);
+                }
+            }
+
+            device.destroy_framebuffer(self.framebuffer.take().unwrap());
+            device.destroy_fence(self.fence.take().unwrap());
+            device.destroy_command_pool(self.command_pool.take().unwrap().into_raw());
+        }
+
+        device_state.resource_tracker.borrow_mut().release_all(&self.resource_ids);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn should_describe_render_target() {
+        let desc = RenderTargetDesc::new(256, 128).color_format(f::Format::Rgba16Float).without_depth();
+
+        assert_eq!(f::Format::Rgba16Float, desc.color_format);
+        assert!(!desc.depth);
+        assert_eq!(
+            i::Extent {
+                width: 256,
+                height: 128,
+                depth: 1
+            },
+            desc.get_extent()
+        );
+        assert_eq!(256, desc.get_viewport().rect.w);
+        assert_eq!(128, desc.get_viewport().rect.h);
+    }
+}
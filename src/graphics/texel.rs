@@ -0,0 +1,275 @@
+use gfx_hal::format as f;
+
+/// The texels of a format are stored in blocks of `width` x `height` texels taking `size` bytes each.
+/// Uncompressed formats have blocks of a single texel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockLayout {
+    pub width: u32,
+    pub height: u32,
+    pub size: u32,
+}
+
+impl BlockLayout {
+    const fn new(width: u32, height: u32, size: u32) -> Self {
+        BlockLayout { width, height, size }
+    }
+
+    /// The number of blocks across and down an image of the given size, rounding partial blocks up.
+    /// Sizes read from files may be as large as `u32::MAX`, so the rounding mustn't overflow.
+    pub fn block_count(&self, width: u32, height: u32) -> (u32, u32) {
+        let round_up = |size: u32, block_size: u32| size / block_size + if size % block_size != 0 { 1 } else { 0 };
+
+        (round_up(width, self.width), round_up(height, self.height))
+    }
+
+    /// The number of bytes in a tightly packed image of the given size, or `None` if that doesn't fit in a `usize`,
+    /// as can happen for the sizes given by a corrupt file
+    pub fn image_size(&self, width: u32, height: u32) -> Option<usize> {
+        let (blocks_wide, blocks_high) = self.block_count(width, height);
+
+        (blocks_wide as usize)
+            .checked_mul(blocks_high as usize)?
+            .checked_mul(self.size as usize)
+    }
+}
+
+/// The layout of the texture formats that images can be loaded in
+pub fn block_layout(format: f::Format) -> Option<BlockLayout> {
+    use gfx_hal::format::Format::*;
+
+    match format {
+        Rgba8Unorm | Rgba8Srgb => Some(BlockLayout::new(1, 1, 4)),
+        Rgba16Float => Some(BlockLayout::new(1, 1, 8)),
+        Rgba32Float => Some(BlockLayout::new(1, 1, 16)),
+        Bc1RgbUnorm | Bc1RgbSrgb | Bc1RgbaUnorm | Bc1RgbaSrgb | Bc4Unorm | Bc4Inorm => Some(BlockLayout::new(4, 4, 8)),
+        Bc2Unorm | Bc2Srgb | Bc3Unorm | Bc3Srgb | Bc5Unorm | Bc5Inorm | Bc6hUfloat | Bc6hFloat | Bc7Unorm | Bc7Srgb => {
+            Some(BlockLayout::new(4, 4, 16))
+        }
+        _ => None,
+    }
+}
+
+/// Returns true for formats whose colour channels are sRGB encoded, and so are converted to linear values when sampled
+pub fn is_srgb(format: f::Format) -> bool {
+    use gfx_hal::format::Format::*;
+
+    match format {
+        Rgba8Srgb | Bc1RgbSrgb | Bc1RgbaSrgb | Bc2Srgb | Bc3Srgb | Bc7Srgb => true,
+        _ => false,
+    }
+}
+
+/// Decodes a tightly packed image of an uncompressed format into linear RGBA values,
+/// returning `None` for block compressed formats
+pub fn decode_rgba(format: f::Format, data: &[u8]) -> Option<Vec<[f32; 4]>> {
+    match format {
+        f::Format::Rgba8Unorm | f::Format::Rgba8Srgb => {
+            let to_linear: Vec<f32> = (0..256)
+                .map(|value| {
+                    if format == f::Format::Rgba8Srgb {
+                        srgb_to_linear(value as f32 / 255.0)
+                    } else {
+                        value as f32 / 255.0
+                    }
+                })
+                .collect();
+
+            Some(
+                data.chunks(4)
+                    .map(|texel| {
+                        [
+                            to_linear[texel[0] as usize],
+                            to_linear[texel[1] as usize],
+                            to_linear[texel[2] as usize],
+                            f32::from(texel[3]) / 255.0,
+                        ]
+                    })
+                    .collect(),
+            )
+        }
+        f::Format::Rgba16Float => Some(
+            data.chunks(8)
+                .map(|texel| {
+                    let channel = |index: usize| f16_to_f32(u16::from(texel[index * 2]) | u16::from(texel[index * 2 + 1]) << 8);
+                    [channel(0), channel(1), channel(2), channel(3)]
+                })
+                .collect(),
+        ),
+        f::Format::Rgba32Float => Some(
+            data.chunks(16)
+                .map(|texel| {
+                    let channel = |index: usize| {
+                        let bytes = &texel[index * 4..index * 4 + 4];
+                        f32::from_bits(
+                            u32::from(bytes[0]) | u32::from(bytes[1]) << 8 | u32::from(bytes[2]) << 16 | u32::from(bytes[3]) << 24,
+                        )
+                    };
+                    [channel(0), channel(1), channel(2), channel(3)]
+                })
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Encodes linear RGBA values into a tightly packed image of an uncompressed format, the reverse of `decode_rgba`
+pub fn encode_rgba(format: f::Format, texels: &[[f32; 4]]) -> Option<Vec<u8>> {
+    let to_byte = |value: f32| (value * 255.0).round().max(0.0).min(255.0) as u8;
+
+    let mut data = Vec::with_capacity(texels.len() * block_layout(format)?.size as usize);
+
+    for texel in texels {
+        match format {
+            f::Format::Rgba8Unorm => data.extend_from_slice(&[to_byte(texel[0]), to_byte(texel[1]), to_byte(texel[2]), to_byte(texel[3])]),
+            f::Format::Rgba8Srgb => data.extend_from_slice(&[
+                to_byte(linear_to_srgb(texel[0])),
+                to_byte(linear_to_srgb(texel[1])),
+                to_byte(linear_to_srgb(texel[2])),
+                to_byte(texel[3]),
+            ]),
+            f::Format::Rgba16Float => {
+                for &channel in texel {
+                    let half = f32_to_f16(channel);
+                    data.extend_from_slice(&[half as u8, (half >> 8) as u8]);
+                }
+            }
+            f::Format::Rgba32Float => {
+                for &channel in texel {
+                    let bits = channel.to_bits();
+                    data.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    Some(data)
+}
+
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Converts to the nearest half precision float. Values too large for a half become infinity.
+pub fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x007f_ffff;
+
+    if exponent == 0xff {
+        // Infinity stays infinity, and NaN keeps a mantissa bit so that it stays NaN:
+        return sign | 0x7c00 | if mantissa != 0 { 0x0200 } else { 0 };
+    }
+
+    let half_exponent = exponent - 127 + 15;
+
+    if half_exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    if half_exponent <= 0 {
+        // Too small for a normal half, so becomes subnormal, or zero if it is smaller still:
+        if half_exponent < -10 {
+            return sign;
+        }
+
+        let mantissa = mantissa | 0x0080_0000;
+        let shift = (14 - half_exponent) as u32;
+        let half_mantissa = mantissa >> shift;
+        let round_bit = 1 << (shift - 1);
+
+        let rounds_up = mantissa & round_bit != 0 && (mantissa & (3 * round_bit - 1) != 0);
+
+        return sign | (half_mantissa as u16 + rounds_up as u16);
+    }
+
+    let half = sign | ((half_exponent as u16) << 10) | (mantissa >> 13) as u16;
+
+    // Round to nearest, with ties going to even. A carry out of the mantissa correctly moves to the next exponent:
+    let rounds_up = mantissa & 0x1000 != 0 && (mantissa & 0x2fff != 0);
+
+    half + rounds_up as u16
+}
+
+pub fn f16_to_f32(half: u16) -> f32 {
+    let sign = u32::from(half & 0x8000) << 16;
+    let exponent = u32::from((half >> 10) & 0x1f);
+    let mantissa = u32::from(half & 0x03ff);
+
+    let bits = match (exponent, mantissa) {
+        (0, 0) => sign,
+        (0, _) => {
+            // Subnormal halves are normal floats, so the mantissa is shifted until its leading bit is implicit:
+            let shift = mantissa.leading_zeros() - 21;
+            sign | ((127 - 15 + 1 - shift) << 23) | ((mantissa << shift) & 0x03ff) << 13
+        }
+        (0x1f, _) => sign | 0x7f80_0000 | mantissa << 13,
+        _ => sign | ((exponent + 127 - 15) << 23) | mantissa << 13,
+    };
+
+    f32::from_bits(bits)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn should_convert_half_floats() {
+        for &value in &[0.0, 1.0, -2.5, 0.333_251_95, 65504.0, 6.103_515_6e-5, 5.960_464_5e-8] {
+            assert_eq!(value, f16_to_f32(f32_to_f16(value)));
+        }
+
+        assert_eq!(0x3c00, f32_to_f16(1.0));
+        assert_eq!(0x7c00, f32_to_f16(1.0e6));
+        assert_eq!(0x3c00, f32_to_f16(1.000_244_1));
+        assert!(f16_to_f32(f32_to_f16(std::f32::NAN)).is_nan());
+    }
+
+    #[test]
+    fn should_measure_block_compressed_images() {
+        let bc1 = block_layout(f::Format::Bc1RgbaSrgb).unwrap();
+
+        assert_eq!((2, 1), bc1.block_count(5, 3));
+        assert_eq!(Some(16), bc1.image_size(5, 3));
+        assert_eq!(Some(8), bc1.image_size(1, 1));
+        assert_eq!((0x4000_0000, 1), bc1.block_count(std::u32::MAX, 1));
+        assert_eq!(Some(64), block_layout(f::Format::Rgba32Float).unwrap().image_size(2, 2));
+        assert_eq!(
+            None,
+            block_layout(f::Format::Bc3Srgb).unwrap().image_size(std::u32::MAX, std::u32::MAX)
+        );
+    }
+
+    #[test]
+    fn should_round_trip_uncompressed_texels() {
+        let data = vec![255, 128, 0, 255, 10, 20, 30, 40];
+
+        for &format in &[f::Format::Rgba8Srgb, f::Format::Rgba8Unorm] {
+            let texels = decode_rgba(format, &data).unwrap();
+
+            assert_eq!(Some(data.clone()), encode_rgba(format, &texels));
+        }
+
+        let texels = vec![[0.5, 1.0, 2.0, 1.0]];
+
+        assert_eq!(
+            Some(texels.clone()),
+            decode_rgba(f::Format::Rgba16Float, &encode_rgba(f::Format::Rgba16Float, &texels).unwrap())
+        );
+        assert_eq!(None, decode_rgba(f::Format::Bc7Srgb, &[0; 16]));
+    }
+}
@@ -1,66 +1,352 @@
-use std::ops::Range;
+use errors::*;
+use gfx_hal::format as f;
 use gfx_hal::image as i;
-use image;
+use gfx_hal::*;
+use graphics::adapter::AdapterState;
+use graphics::descriptor::{DescriptorSet, DescriptorSetLayout, DescriptorSetWrite};
+use graphics::device::DeviceState;
+use graphics::image::{ColorSpace, Image, Loader, TextureState};
+use graphics::pipeline::PipelineDesc;
+use graphics::sampler::SamplerDesc;
+use graphics::upload::UploadBatch;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
 
-pub const RGBA_IMAGE_STRIDE : usize = 4;
+/// Refers to a texture held by a TextureManager. Handles are cheap to clone, and the texture
+/// is freed by `TextureManager::release_unused` once every handle to it has been dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextureHandle(Rc<usize>);
 
-pub struct Loader;
+impl TextureHandle {
+    /// The slot the texture is held in, which is only reused once the texture has been released
+    pub fn index(&self) -> usize {
+        *self.0
+    }
+}
+
+struct TextureSlot<K, T> {
+    key: Option<K>,
+    handle: Weak<usize>,
+    value: T,
+}
 
-pub struct Texture {
-    pub image: image::RgbaImage,
-    pub kind: i::Kind,
+/// Values referred to by TextureHandles, and optionally found by a key such as the file they were loaded from.
+/// Values are kept until they are released, even once every handle to them has been dropped.
+pub struct TextureSlots<K, T> {
+    slots: Vec<Option<TextureSlot<K, T>>>,
 }
 
-impl Texture {
-    pub fn get_image(&self) -> &image::RgbaImage {
-        &self.image
+impl<K: PartialEq, T> TextureSlots<K, T> {
+    pub fn new() -> Self {
+        TextureSlots { slots: Vec::new() }
     }
 
-    pub fn get_kind(&self) -> &i::Kind {
-        &self.kind
+    /// Returns a handle to the value stored with the key. A value that has lost all its handles
+    /// but hasn't been released yet is given a new one, rather than being loaded again.
+    pub fn find(&mut self, key: &K) -> Option<TextureHandle> {
+        let index = self.slots.iter().position(|slot| match *slot {
+            Some(ref slot) => slot.key.as_ref() == Some(key),
+            None => false,
+        })?;
+
+        let slot = self.slots[index].as_mut().unwrap();
+
+        Some(match slot.handle.upgrade() {
+            Some(handle) => TextureHandle(handle),
+            None => {
+                let handle = Rc::new(index);
+                slot.handle = Rc::downgrade(&handle);
+                TextureHandle(handle)
+            }
+        })
     }
 
-    pub fn get_dimensions(&self) -> (u32, u32) {
-        self.image.dimensions()
+    /// Stores the value in the first free slot, returning the only handle to it
+    pub fn insert(&mut self, key: Option<K>, value: T) -> TextureHandle {
+        let index = match self.slots.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                self.slots.push(None);
+                self.slots.len() - 1
+            }
+        };
+
+        let handle = Rc::new(index);
+
+        self.slots[index] = Some(TextureSlot {
+            key,
+            handle: Rc::downgrade(&handle),
+            value,
+        });
+
+        TextureHandle(handle)
     }
 
-    pub fn row_pitch(&self, row_alignment_mask: u32) -> u32 {
-        let (width, _) = self.get_dimensions();
-        (width * RGBA_IMAGE_STRIDE as u32 + row_alignment_mask) & !row_alignment_mask
+    pub fn get(&self, handle: &TextureHandle) -> &T {
+        &self.slots[handle.index()].as_ref().unwrap().value
     }
 
-    /// Returns a range which denotes the start and finish of bytes
-    /// that belong to the row provided by row_index
-    pub fn row_range(&self, row_index: usize) -> Range<usize> {
-        let (width, _) = self.get_dimensions();
-        let width = width as usize;
+    /// Returns true if any value has lost all its handles
+    pub fn has_unused(&self) -> bool {
+        self.slots.iter().any(|slot| match *slot {
+            Some(ref slot) => slot.handle.upgrade().is_none(),
+            None => false,
+        })
+    }
+
+    /// Removes every value that has lost all its handles, freeing their slots
+    pub fn release_unused(&mut self) -> Vec<T> {
+        let mut released = Vec::new();
+
+        for slot in &mut self.slots {
+            let is_unused = match *slot {
+                Some(ref slot) => slot.handle.upgrade().is_none(),
+                None => false,
+            };
+
+            if is_unused {
+                released.push(slot.take().unwrap().value);
+            }
+        }
 
-        let row_index_start = row_index * width * RGBA_IMAGE_STRIDE;
-        let row_index_end = (row_index + 1) * width * RGBA_IMAGE_STRIDE;
+        released
+    }
 
-        row_index_start..row_index_end
+    /// The number of values held, including those without handles that haven't been released
+    pub fn len(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.is_some()).count()
     }
 
-    /// Returns the complete size of the image
-    pub fn get_upload_size(&self, row_alignment_mask: u32) -> u64 {
-        let (_, height) = self.get_dimensions();
-        let row_pitch = self.row_pitch(row_alignment_mask);
-        (height * row_pitch) as u64
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 }
 
-impl Loader {
-    /// Loads a texture from file
-    pub fn from_file(image_file_path: &str) -> Option<Texture> {
-        match image::open(image_file_path) {
-            Ok(image) => {
-                let image = image.to_rgba();
-                let (width, height) = image.dimensions();
-                let kind = i::Kind::D2(width, height, 1, 1);
+impl<K: PartialEq, T> Default for TextureSlots<K, T> {
+    fn default() -> Self {
+        TextureSlots::new()
+    }
+}
 
-                Some(Texture { image, kind })
-            }
-            Err(_) => None,
+/// Identifies a texture loaded from a file, as the same file may be sampled in different ways
+#[derive(Debug, Clone, PartialEq)]
+struct TextureKey {
+    path: PathBuf,
+    color_space: ColorSpace,
+    sampler_desc: SamplerDesc,
+}
+
+struct TextureEntry<B: Backend> {
+    descriptor_set: DescriptorSet<B, Graphics>,
+    texture_state: TextureState<B>,
+}
+
+/// The descriptor set holding a texture and its sampler, from `shaders/quad_150.frag`
+pub const TEXTURE_SET: u32 = 0;
+
+/// Loads each texture file once, however many materials use it, and hands out handles to the textures.
+/// Each texture has a descriptor set binding it along with its sampler, laid out as `get_set_layout`.
+pub struct TextureManager<B: Backend> {
+    device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+    set_layout: Rc<DescriptorSetLayout<B, Graphics>>,
+    row_alignment_mask: u32,
+    textures: TextureSlots<TextureKey, TextureEntry<B>>,
+}
+
+impl<B: Backend> TextureManager<B> {
+    pub unsafe fn new(
+        device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+        adapter_state: &AdapterState<B>,
+        pipeline_desc: &PipelineDesc,
+    ) -> Result<Self> {
+        Ok(TextureManager {
+            set_layout: Rc::new(Self::create_descriptor_set_layout(Rc::clone(&device_state), pipeline_desc)?),
+            row_alignment_mask: adapter_state.limits.min_buffer_copy_pitch_alignment as u32 - 1,
+            device_state,
+            textures: TextureSlots::new(),
+        })
+    }
+
+    /// Creates a layout for a set binding a sampled image at binding 0 and its sampler at binding 1,
+    /// as the pipeline's shaders declare them in `TEXTURE_SET`
+    pub unsafe fn create_descriptor_set_layout(
+        device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+        pipeline_desc: &PipelineDesc,
+    ) -> Result<DescriptorSetLayout<B, Graphics>> {
+        DescriptorSetLayout::new(device_state, pipeline_desc.reflect()?.set_layout_bindings(TEXTURE_SET))
+    }
+
+    /// Returns a handle to the texture loaded from the file. A file is only loaded once for each
+    /// colour space and sampler it is asked for, and can be drawn once the upload batch has been submitted.
+    pub unsafe fn load(
+        &mut self,
+        upload_batch: &mut UploadBatch<B>,
+        path: &Path,
+        color_space: ColorSpace,
+        sampler_desc: &SamplerDesc,
+    ) -> Result<TextureHandle> {
+        let key = TextureKey {
+            path: path.to_path_buf(),
+            color_space,
+            sampler_desc: *sampler_desc,
+        };
+
+        if let Some(handle) = self.textures.find(&key) {
+            return Ok(handle);
+        }
+
+        let image = Loader::from_file_with_color_space(&path.to_string_lossy(), color_space)?;
+        let texture_entry = self.create_entry(upload_batch.upload_image(&image, self.row_alignment_mask)?, sampler_desc)?;
+
+        Ok(self.textures.insert(Some(key), texture_entry))
+    }
+
+    /// Uploads an image that wasn't loaded from a file, returning the only handle to it
+    pub unsafe fn create(&mut self, upload_batch: &mut UploadBatch<B>, image: &Image, sampler_desc: &SamplerDesc) -> Result<TextureHandle> {
+        let texture_entry = self.create_entry(upload_batch.upload_image(image, self.row_alignment_mask)?, sampler_desc)?;
+
+        Ok(self.textures.insert(None, texture_entry))
+    }
+
+    /// Creates a texture to be drawn to by a `RenderTarget`, returning the only handle to it.
+    /// Its contents are undefined until it has been drawn to, so it mustn't be sampled before then.
+    pub unsafe fn create_render_target(
+        &mut self,
+        format: f::Format,
+        dimensions: (u32, u32),
+        sampler_desc: &SamplerDesc,
+    ) -> Result<TextureHandle> {
+        let texture_state = {
+            let device_state = self.device_state.as_ref().borrow();
+            TextureState::new_render_target(Rc::clone(&self.device_state), &device_state, format, dimensions)?
+        };
+
+        let texture_entry = self.create_entry(texture_state, sampler_desc)?;
+
+        Ok(self.textures.insert(None, texture_entry))
+    }
+
+    unsafe fn create_entry(&self, texture_state: TextureState<B>, sampler_desc: &SamplerDesc) -> Result<TextureEntry<B>> {
+        let mut descriptor_set = DescriptorSetLayout::create_shared_set(Rc::clone(&self.set_layout))?;
+
+        {
+            let device_state = &mut *self.device_state.as_ref().borrow_mut();
+            let mut sampler_cache = device_state.sampler_cache.borrow_mut();
+            let sampler = sampler_cache.get_or_create(device_state, sampler_desc)?;
+
+            descriptor_set.write_to_state(
+                &mut device_state.device,
+                vec![
+                    DescriptorSetWrite {
+                        binding: 0,
+                        array_offset: 0,
+                        descriptors: Some(pso::Descriptor::Image(
+                            texture_state.get_image_view(),
+                            i::Layout::ShaderReadOnlyOptimal,
+                        )),
+                    },
+                    DescriptorSetWrite {
+                        binding: 1,
+                        array_offset: 0,
+                        descriptors: Some(pso::Descriptor::Sampler(sampler)),
+                    },
+                ],
+            );
         }
+
+        Ok(TextureEntry {
+            descriptor_set,
+            texture_state,
+        })
+    }
+
+    /// The layout of every texture's descriptor set, for creating pipelines that sample them
+    pub fn get_set_layout(&self) -> &DescriptorSetLayout<B, Graphics> {
+        &self.set_layout
+    }
+
+    pub fn get_descriptor_set(&self, handle: &TextureHandle) -> &B::DescriptorSet {
+        self.textures.get(handle).descriptor_set.set.as_ref().unwrap()
+    }
+
+    pub fn get_texture(&self, handle: &TextureHandle) -> &TextureState<B> {
+        &self.textures.get(handle).texture_state
+    }
+
+    /// The number of textures held in GPU memory, including those waiting to be released
+    pub fn len(&self) -> usize {
+        self.textures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.textures.is_empty()
+    }
+
+    /// Returns true if any texture has lost all its handles, and can be released
+    pub fn has_unused(&self) -> bool {
+        self.textures.has_unused()
+    }
+
+    /// Frees the GPU memory of every texture that has lost all its handles, returning how many were freed.
+    /// The GPU must have finished with the textures, as any frame that drew with them would otherwise fail.
+    pub unsafe fn release_unused(&mut self) -> usize {
+        let released = self.textures.release_unused().len();
+
+        if released > 0 {
+            info!("Released {} unused textures", released);
+        }
+
+        released
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn should_share_textures_with_the_same_key() {
+        let mut textures = TextureSlots::new();
+
+        let handle = textures.insert(Some("diffuse.png"), 1);
+        textures.insert(None, 2);
+
+        assert_eq!(Some(handle.clone()), textures.find(&"diffuse.png"));
+        assert_eq!(None, textures.find(&"normal.png"));
+        assert_eq!(&1, textures.get(&handle));
+        assert_eq!(2, textures.len());
+    }
+
+    #[test]
+    fn should_release_once_last_handle_drops() {
+        let mut textures = TextureSlots::new();
+
+        let handle = textures.insert(Some("diffuse.png"), 1);
+        let other_handle = handle.clone();
+
+        drop(handle);
+        assert!(!textures.has_unused());
+
+        drop(other_handle);
+        assert!(textures.has_unused());
+        assert_eq!(vec![1], textures.release_unused());
+        assert!(textures.is_empty());
+
+        // The freed slot is reused by the next texture:
+        assert_eq!(0, textures.insert(None, 2).index());
+    }
+
+    #[test]
+    fn should_reuse_unreleased_texture() {
+        let mut textures = TextureSlots::new();
+
+        drop(textures.insert(Some("diffuse.png"), 1));
+
+        let handle = textures.find(&"diffuse.png").unwrap();
+
+        assert_eq!(0, handle.index());
+        assert!(!textures.has_unused());
+        assert!(textures.release_unused().is_empty());
     }
 }
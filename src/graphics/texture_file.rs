@@ -0,0 +1,477 @@
+use errors::*;
+use gfx_hal::format as f;
+use graphics::image::{self, ColorSpace, Image, ImageLevel};
+use graphics::texel;
+
+const DDS_MAGIC: &[u8] = b"DDS ";
+/// The size of the magic number and the header that follows it
+const DDS_HEADER_SIZE: usize = 128;
+/// The size of the header that follows the main header when the FourCC is "DX10"
+const DDS_DX10_HEADER_SIZE: usize = 20;
+
+const DDSD_MIPMAPCOUNT: u32 = 0x2_0000;
+const DDPF_FOURCC: u32 = 0x4;
+const DDPF_RGB: u32 = 0x40;
+const DDSCAPS2_CUBEMAP: u32 = 0x200;
+const DDSCAPS2_VOLUME: u32 = 0x20_0000;
+const DDS_RESOURCE_DIMENSION_TEXTURE2D: u32 = 3;
+const DDS_RESOURCE_MISC_TEXTURECUBE: u32 = 0x4;
+
+const KTX2_IDENTIFIER: &[u8] = &[0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A];
+/// The size of the identifier, header and index that come before the level index
+const KTX2_HEADER_SIZE: usize = 80;
+/// The size of each level's entry in the level index
+const KTX2_LEVEL_INDEX_ENTRY_SIZE: usize = 24;
+
+/// Reads a DirectDraw Surface file, including any mip chain it has.
+/// Legacy files don't say whether they hold sRGB colours, so are read in the given colour space.
+pub fn parse_dds(bytes: &[u8], color_space: ColorSpace) -> Result<Image> {
+    if !bytes.starts_with(DDS_MAGIC) {
+        bail!(ErrorKind::InvalidTextureData("not a DDS file".to_string()));
+    }
+
+    let flags = read_u32(bytes, 8)?;
+    let height = read_u32(bytes, 12)?;
+    let width = read_u32(bytes, 16)?;
+    let level_count = if flags & DDSD_MIPMAPCOUNT != 0 {
+        read_u32(bytes, 28)?.max(1)
+    } else {
+        1
+    };
+    let pixel_flags = read_u32(bytes, 80)?;
+    let four_cc = read_bytes(bytes, 84, 4)?;
+
+    if read_u32(bytes, 112)? & (DDSCAPS2_CUBEMAP | DDSCAPS2_VOLUME) != 0 {
+        bail!(ErrorKind::UnsupportedTextureFormat("DDS cube maps and volume textures".to_string()));
+    }
+
+    let (format, data_offset) = if pixel_flags & DDPF_FOURCC != 0 && four_cc == b"DX10" {
+        let dxgi_format = read_u32(bytes, DDS_HEADER_SIZE)?;
+
+        if read_u32(bytes, DDS_HEADER_SIZE + 4)? != DDS_RESOURCE_DIMENSION_TEXTURE2D
+            || read_u32(bytes, DDS_HEADER_SIZE + 8)? & DDS_RESOURCE_MISC_TEXTURECUBE != 0
+            || read_u32(bytes, DDS_HEADER_SIZE + 12)? > 1
+        {
+            bail!(ErrorKind::UnsupportedTextureFormat(
+                "DDS textures other than a single 2D image".to_string()
+            ));
+        }
+
+        let format = dxgi_to_format(dxgi_format)
+            .ok_or_else(|| ErrorKind::UnsupportedTextureFormat(format!("DXGI format {} in DDS", dxgi_format)))?;
+
+        (format, DDS_HEADER_SIZE + DDS_DX10_HEADER_SIZE)
+    } else if pixel_flags & DDPF_FOURCC != 0 {
+        let format = four_cc_to_format(four_cc, color_space)
+            .ok_or_else(|| ErrorKind::UnsupportedTextureFormat(format!("FourCC '{}' in DDS", String::from_utf8_lossy(four_cc))))?;
+
+        (format, DDS_HEADER_SIZE)
+    } else if pixel_flags & DDPF_RGB != 0
+        && read_u32(bytes, 88)? == 32
+        && read_u32(bytes, 92)? == 0xff
+        && read_u32(bytes, 104)? == 0xff00_0000
+    {
+        let format = match color_space {
+            ColorSpace::Srgb => f::Format::Rgba8Srgb,
+            ColorSpace::Linear => f::Format::Rgba8Unorm,
+        };
+
+        (format, DDS_HEADER_SIZE)
+    } else {
+        bail!(ErrorKind::UnsupportedTextureFormat(
+            "uncompressed DDS pixel layouts other than RGBA8".to_string()
+        ));
+    };
+
+    let block_layout = texel::block_layout(format).ok_or_else(|| ErrorKind::UnsupportedTextureFormat(format!("{:?}", format)))?;
+
+    check_level_count(width, height, level_count)?;
+
+    // Each level follows the one above it, without any padding:
+    let mut offset = data_offset;
+    let mut levels = Vec::with_capacity(level_count as usize);
+
+    for level in 0..level_count {
+        let (level_width, level_height) = level_dimensions(width, height, level);
+        let size = level_size(block_layout, level_width, level_height)?;
+
+        levels.push(ImageLevel {
+            width: level_width,
+            height: level_height,
+            data: read_bytes(bytes, offset, size)?.to_vec(),
+        });
+
+        offset += size;
+    }
+
+    Image::from_levels(format, levels)
+}
+
+/// Reads a KTX2 file, including any mip chain it has. Supercompressed files aren't supported.
+pub fn parse_ktx2(bytes: &[u8]) -> Result<Image> {
+    if !bytes.starts_with(KTX2_IDENTIFIER) {
+        bail!(ErrorKind::InvalidTextureData("not a KTX2 file".to_string()));
+    }
+
+    let vk_format = read_u32(bytes, 12)?;
+    let width = read_u32(bytes, 20)?;
+    let height = read_u32(bytes, 24)?;
+    let depth = read_u32(bytes, 28)?;
+    let layer_count = read_u32(bytes, 32)?;
+    let face_count = read_u32(bytes, 36)?;
+    let level_count = read_u32(bytes, 40)?;
+    let supercompression_scheme = read_u32(bytes, 44)?;
+
+    if height == 0 || depth > 0 || layer_count > 1 || face_count > 1 {
+        bail!(ErrorKind::UnsupportedTextureFormat(
+            "KTX2 textures other than a single 2D image".to_string()
+        ));
+    }
+
+    if supercompression_scheme != 0 {
+        bail!(ErrorKind::UnsupportedTextureFormat(format!(
+            "KTX2 supercompression scheme {}",
+            supercompression_scheme
+        )));
+    }
+
+    let format = vk_to_format(vk_format).ok_or_else(|| ErrorKind::UnsupportedTextureFormat(format!("VkFormat {} in KTX2", vk_format)))?;
+    let block_layout = texel::block_layout(format).ok_or_else(|| ErrorKind::UnsupportedTextureFormat(format!("{:?}", format)))?;
+
+    check_level_count(width, height, level_count.max(1))?;
+
+    // A level count of 0 asks for the mip chain to be generated once the file is loaded:
+    let levels = (0..level_count.max(1))
+        .map(|level| {
+            let entry_offset = KTX2_HEADER_SIZE + level as usize * KTX2_LEVEL_INDEX_ENTRY_SIZE;
+            let offset = read_u64(bytes, entry_offset)? as usize;
+            let length = read_u64(bytes, entry_offset + 8)? as usize;
+
+            let (level_width, level_height) = level_dimensions(width, height, level);
+            let size = level_size(block_layout, level_width, level_height)?;
+
+            if length < size {
+                bail!(ErrorKind::InvalidTextureData(format!(
+                    "level {} holds {} bytes, rather than {}",
+                    level, length, size
+                )));
+            }
+
+            Ok(ImageLevel {
+                width: level_width,
+                height: level_height,
+                data: read_bytes(bytes, offset, size)?.to_vec(),
+            })
+        })
+        .collect::<Result<Vec<ImageLevel>>>()?;
+
+    let image = Image::from_levels(format, levels)?;
+
+    Ok(if level_count == 0 { image.with_mips() } else { image })
+}
+
+/// Checks the level count read from the file before any levels are read, as a corrupt count
+/// could otherwise ask for more levels than can be allocated, or than the image has
+fn check_level_count(width: u32, height: u32, level_count: u32) -> Result<()> {
+    if width == 0 || height == 0 || level_count > image::max_level_count(width, height) {
+        bail!(ErrorKind::InvalidTextureData(format!(
+            "{} levels were given for the {}x{} image",
+            level_count, width, height
+        )));
+    }
+
+    Ok(())
+}
+
+fn level_dimensions(width: u32, height: u32, level: u32) -> (u32, u32) {
+    ((width >> level).max(1), (height >> level).max(1))
+}
+
+/// The number of bytes in a level, whose dimensions come from the file and so may be too large to address
+fn level_size(block_layout: texel::BlockLayout, width: u32, height: u32) -> Result<usize> {
+    block_layout
+        .image_size(width, height)
+        .ok_or_else(|| ErrorKind::InvalidTextureData(format!("the {}x{} level is too large to load", width, height)).into())
+}
+
+/// Returns `size` bytes from `offset`, which may come from the file itself and so may lie anywhere
+fn read_bytes(bytes: &[u8], offset: usize, size: usize) -> Result<&[u8]> {
+    let end = offset
+        .checked_add(size)
+        .ok_or_else(|| ErrorKind::InvalidTextureData(format!("{} bytes at offset {} lie outside the file", size, offset)))?;
+
+    match bytes.get(offset..end) {
+        Some(slice) => Ok(slice),
+        None => bail!(ErrorKind::InvalidTextureData(format!("the file ends before byte {}", end))),
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32> {
+    let slice = read_bytes(bytes, offset, 4)?;
+    Ok(slice.iter().rev().fold(0, |value, &byte| value << 8 | u32::from(byte)))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64> {
+    let slice = read_bytes(bytes, offset, 8)?;
+    Ok(slice.iter().rev().fold(0, |value, &byte| value << 8 | u64::from(byte)))
+}
+
+fn four_cc_to_format(four_cc: &[u8], color_space: ColorSpace) -> Option<f::Format> {
+    use gfx_hal::format::Format::*;
+
+    let srgb = color_space == ColorSpace::Srgb;
+
+    Some(match four_cc {
+        b"DXT1" if srgb => Bc1RgbaSrgb,
+        b"DXT1" => Bc1RgbaUnorm,
+        b"DXT3" if srgb => Bc2Srgb,
+        b"DXT3" => Bc2Unorm,
+        b"DXT5" if srgb => Bc3Srgb,
+        b"DXT5" => Bc3Unorm,
+        b"ATI1" | b"BC4U" => Bc4Unorm,
+        b"BC4S" => Bc4Inorm,
+        b"ATI2" | b"BC5U" => Bc5Unorm,
+        b"BC5S" => Bc5Inorm,
+        // D3DFMT_A16B16G16R16F and D3DFMT_A32B32G32R32F are stored as numbers rather than characters:
+        &[113, 0, 0, 0] => Rgba16Float,
+        &[116, 0, 0, 0] => Rgba32Float,
+        _ => return None,
+    })
+}
+
+fn dxgi_to_format(dxgi_format: u32) -> Option<f::Format> {
+    use gfx_hal::format::Format::*;
+
+    Some(match dxgi_format {
+        2 => Rgba32Float,
+        10 => Rgba16Float,
+        28 => Rgba8Unorm,
+        29 => Rgba8Srgb,
+        71 => Bc1RgbaUnorm,
+        72 => Bc1RgbaSrgb,
+        74 => Bc2Unorm,
+        75 => Bc2Srgb,
+        77 => Bc3Unorm,
+        78 => Bc3Srgb,
+        80 => Bc4Unorm,
+        81 => Bc4Inorm,
+        83 => Bc5Unorm,
+        84 => Bc5Inorm,
+        95 => Bc6hUfloat,
+        96 => Bc6hFloat,
+        98 => Bc7Unorm,
+        99 => Bc7Srgb,
+        _ => return None,
+    })
+}
+
+fn vk_to_format(vk_format: u32) -> Option<f::Format> {
+    use gfx_hal::format::Format::*;
+
+    Some(match vk_format {
+        37 => Rgba8Unorm,
+        43 => Rgba8Srgb,
+        97 => Rgba16Float,
+        109 => Rgba32Float,
+        131 => Bc1RgbUnorm,
+        132 => Bc1RgbSrgb,
+        133 => Bc1RgbaUnorm,
+        134 => Bc1RgbaSrgb,
+        135 => Bc2Unorm,
+        136 => Bc2Srgb,
+        137 => Bc3Unorm,
+        138 => Bc3Srgb,
+        139 => Bc4Unorm,
+        140 => Bc4Inorm,
+        141 => Bc5Unorm,
+        142 => Bc5Inorm,
+        143 => Bc6hUfloat,
+        144 => Bc6hFloat,
+        145 => Bc7Unorm,
+        146 => Bc7Srgb,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+        for index in 0..4 {
+            bytes[offset + index] = (value >> (index * 8)) as u8;
+        }
+    }
+
+    fn dds_header(width: u32, height: u32, level_count: u32, four_cc: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0; DDS_HEADER_SIZE];
+        bytes[0..4].copy_from_slice(DDS_MAGIC);
+        write_u32(&mut bytes, 4, 124);
+        write_u32(&mut bytes, 8, DDSD_MIPMAPCOUNT);
+        write_u32(&mut bytes, 12, height);
+        write_u32(&mut bytes, 16, width);
+        write_u32(&mut bytes, 28, level_count);
+        write_u32(&mut bytes, 76, 32);
+        write_u32(&mut bytes, 80, DDPF_FOURCC);
+        bytes[84..88].copy_from_slice(four_cc);
+        bytes
+    }
+
+    #[test]
+    fn should_load_dds_mip_chain() {
+        let mut bytes = dds_header(8, 4, 4, b"DXT5");
+        bytes.extend((0..80).map(|index| index as u8));
+
+        let image = parse_dds(&bytes, ColorSpace::Srgb).unwrap();
+
+        assert_eq!(f::Format::Bc3Srgb, image.get_format());
+        assert_eq!(4, image.mip_level_count());
+        assert_eq!((8, 4), image.get_dimensions());
+        assert_eq!(32, image.get_level(0).data.len());
+        assert_eq!(64, image.get_level(3).data[0]);
+
+        let image = parse_dds(&bytes, ColorSpace::Linear).unwrap();
+        assert_eq!(f::Format::Bc3Unorm, image.get_format());
+    }
+
+    #[test]
+    fn should_load_dx10_dds() {
+        let mut bytes = dds_header(4, 4, 1, b"DX10");
+        bytes.extend(vec![0; DDS_DX10_HEADER_SIZE]);
+        write_u32(&mut bytes, DDS_HEADER_SIZE, 99);
+        write_u32(&mut bytes, DDS_HEADER_SIZE + 4, DDS_RESOURCE_DIMENSION_TEXTURE2D);
+        write_u32(&mut bytes, DDS_HEADER_SIZE + 12, 1);
+        bytes.extend(vec![0; 16]);
+
+        let image = parse_dds(&bytes, ColorSpace::Linear).unwrap();
+
+        assert_eq!(f::Format::Bc7Srgb, image.get_format());
+        assert_eq!(1, image.mip_level_count());
+
+        // Cube maps hold six faces, which aren't supported:
+        write_u32(&mut bytes, DDS_HEADER_SIZE + 8, DDS_RESOURCE_MISC_TEXTURECUBE);
+        assert!(parse_dds(&bytes, ColorSpace::Linear).is_err());
+    }
+
+    #[test]
+    fn should_reject_truncated_dds() {
+        let mut bytes = dds_header(8, 8, 2, b"DXT1");
+        bytes.extend(vec![0; 32]);
+
+        let error = parse_dds(&bytes, ColorSpace::Srgb).err().unwrap();
+
+        assert_eq!("Invalid texture data: the file ends before byte 168", error.to_string());
+    }
+
+    #[test]
+    fn should_reject_corrupt_level_counts() {
+        let mut bytes = dds_header(8, 8, 0xFFFF_FFFF, b"DXT1");
+        bytes.extend(vec![0; 64]);
+
+        let error = parse_dds(&bytes, ColorSpace::Srgb).err().unwrap();
+        assert_eq!(
+            "Invalid texture data: 4294967295 levels were given for the 8x8 image",
+            error.to_string()
+        );
+
+        // An 8x8 image has 4 levels, down to 1x1:
+        write_u32(&mut bytes, 28, 5);
+        assert!(parse_dds(&bytes, ColorSpace::Srgb).is_err());
+
+        write_u32(&mut bytes, 12, 0xFFFF_FFFF);
+        write_u32(&mut bytes, 16, 0xFFFF_FFFF);
+        write_u32(&mut bytes, 28, 40);
+        assert!(parse_dds(&bytes, ColorSpace::Srgb).is_err());
+    }
+
+    #[test]
+    fn should_reject_images_too_large_to_address() {
+        let bytes = dds_header(0xFFFF_FFFF, 0xFFFF_FFFF, 1, b"DXT5");
+
+        let error = parse_dds(&bytes, ColorSpace::Srgb).err().unwrap();
+        assert_eq!(
+            "Invalid texture data: the 4294967295x4294967295 level is too large to load",
+            error.to_string()
+        );
+
+        // D3DFMT_A32B32G32R32F, which takes 16 bytes for every texel:
+        let bytes = dds_header(0xFFFF_FFFF, 0xFFFF_FFFF, 1, &[116, 0, 0, 0]);
+        assert!(parse_dds(&bytes, ColorSpace::Linear).is_err());
+
+        let mut bytes = vec![0; KTX2_HEADER_SIZE + KTX2_LEVEL_INDEX_ENTRY_SIZE];
+        bytes[0..12].copy_from_slice(KTX2_IDENTIFIER);
+        write_u32(&mut bytes, 12, 109);
+        write_u32(&mut bytes, 20, 0xFFFF_FFFF);
+        write_u32(&mut bytes, 24, 0xFFFF_FFFF);
+        write_u32(&mut bytes, 36, 1);
+        write_u32(&mut bytes, 40, 1);
+        assert!(parse_ktx2(&bytes).is_err());
+    }
+
+    #[test]
+    fn should_load_ktx2() {
+        let mut bytes = vec![0; KTX2_HEADER_SIZE + 2 * KTX2_LEVEL_INDEX_ENTRY_SIZE];
+        bytes[0..12].copy_from_slice(KTX2_IDENTIFIER);
+        write_u32(&mut bytes, 12, 131);
+        write_u32(&mut bytes, 16, 1);
+        write_u32(&mut bytes, 20, 4);
+        write_u32(&mut bytes, 24, 2);
+        write_u32(&mut bytes, 36, 1);
+        write_u32(&mut bytes, 40, 2);
+
+        // KTX2 stores the smallest level first:
+        let data_offset = bytes.len() as u32;
+        write_u32(&mut bytes, KTX2_HEADER_SIZE, data_offset + 8);
+        write_u32(&mut bytes, KTX2_HEADER_SIZE + 8, 8);
+        write_u32(&mut bytes, KTX2_HEADER_SIZE + KTX2_LEVEL_INDEX_ENTRY_SIZE, data_offset);
+        write_u32(&mut bytes, KTX2_HEADER_SIZE + KTX2_LEVEL_INDEX_ENTRY_SIZE + 8, 8);
+        bytes.extend(vec![1; 8]);
+        bytes.extend(vec![0; 8]);
+
+        let image = parse_ktx2(&bytes).unwrap();
+
+        assert_eq!(f::Format::Bc1RgbUnorm, image.get_format());
+        assert_eq!(
+            vec![(4, 2), (2, 1)],
+            (0..2).map(|level| image.get_level_dimensions(level)).collect::<Vec<_>>()
+        );
+        assert_eq!(vec![0; 8], image.get_level(0).data);
+        assert_eq!(vec![1; 8], image.get_level(1).data);
+
+        write_u32(&mut bytes, 44, 2);
+        assert!(parse_ktx2(&bytes).is_err());
+    }
+
+    #[test]
+    fn should_reject_garbage_headers() {
+        // Headers cut short, or holding nothing but the magic number:
+        for length in &[0, 4, 20, DDS_HEADER_SIZE - 1] {
+            let bytes = dds_header(8, 8, 1, b"DXT1");
+            assert!(parse_dds(&bytes[..*length], ColorSpace::Srgb).is_err());
+        }
+
+        for length in &[0, 12, 40, KTX2_HEADER_SIZE + 4] {
+            let mut bytes = [0xFF; KTX2_HEADER_SIZE + KTX2_LEVEL_INDEX_ENTRY_SIZE];
+            bytes[0..12].copy_from_slice(KTX2_IDENTIFIER);
+            assert!(parse_ktx2(&bytes[..*length]).is_err());
+        }
+
+        // A level index whose offset is near the end of the address space:
+        let mut bytes = vec![0; KTX2_HEADER_SIZE + KTX2_LEVEL_INDEX_ENTRY_SIZE];
+        bytes[0..12].copy_from_slice(KTX2_IDENTIFIER);
+        write_u32(&mut bytes, 12, 131);
+        write_u32(&mut bytes, 20, 4);
+        write_u32(&mut bytes, 24, 4);
+        write_u32(&mut bytes, 36, 1);
+        write_u32(&mut bytes, 40, 1);
+
+        for index in 0..8 {
+            bytes[KTX2_HEADER_SIZE + index] = 0xFF;
+        }
+        write_u32(&mut bytes, KTX2_HEADER_SIZE + 8, 8);
+
+        assert!(parse_ktx2(&bytes).is_err());
+        assert!(parse_dds(&[0xFF; DDS_HEADER_SIZE], ColorSpace::Srgb).is_err());
+        assert!(parse_ktx2(&[0xFF; KTX2_HEADER_SIZE]).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use gfx_hal::{Backend, Capability};
+use graphics::device::DeviceState;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+/// The kinds of GPU object whose lifetimes are tracked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ResourceKind {
+    Buffer,
+    Image,
+    ImageView,
+    Sampler,
+    CommandPool,
+    DescriptorPool,
+    DescriptorSetLayout,
+    Framebuffer,
+    RenderPass,
+    GraphicsPipeline,
+    PipelineLayout,
+    Fence,
+    Semaphore,
+}
+
+/// Identifies a tracked object, so it can be released once the object is destroyed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ResourceId(u64);
+
+#[derive(Debug, Clone, Copy)]
+struct LiveResource {
+    kind: ResourceKind,
+    site: &'static str,
+}
+
+/// Counts the GPU objects created through a DeviceState that haven't been destroyed yet,
+/// remembering where each was created so that leaks can be traced back to their source.
+/// Tracking is only enabled in debug builds.
+#[derive(Debug)]
+pub struct ResourceTracker {
+    enabled: bool,
+    next_id: u64,
+    live: BTreeMap<ResourceId, LiveResource>,
+}
+
+impl Default for ResourceTracker {
+    fn default() -> Self {
+        ResourceTracker::new(cfg!(debug_assertions))
+    }
+}
+
+impl ResourceTracker {
+    pub fn new(enabled: bool) -> Self {
+        ResourceTracker {
+            enabled,
+            next_id: 1,
+            live: BTreeMap::new(),
+        }
+    }
+
+    /// Records a newly created object. Use the `track_resource!` macro, which fills in the creation site.
+    pub fn register(&mut self, kind: ResourceKind, site: &'static str) -> ResourceId {
+        if !self.enabled {
+            return ResourceId(0);
+        }
+
+        let id = ResourceId(self.next_id);
+        self.next_id += 1;
+
+        self.live.insert(id, LiveResource { kind, site });
+
+        id
+    }
+
+    /// Records that an object has been destroyed
+    pub fn release(&mut self, id: ResourceId) {
+        if self.enabled && self.live.remove(&id).is_none() {
+            warn!("Released {:?}, which isn't a live resource", id);
+        }
+    }
+
+    pub fn release_all(&mut self, ids: &[ResourceId]) {
+        for &id in ids {
+            self.release(id);
+        }
+    }
+
+    pub fn live_count(&self, kind: ResourceKind) -> usize {
+        self.live.values().filter(|resource| resource.kind == kind).count()
+    }
+
+    pub fn total_live_count(&self) -> usize {
+        self.live.len()
+    }
+
+    /// Counts the live objects of each kind by the site they were created at
+    pub fn live_resources(&self) -> Vec<(ResourceKind, &'static str, usize)> {
+        let mut counts: BTreeMap<(ResourceKind, &'static str), usize> = BTreeMap::new();
+
+        for resource in self.live.values() {
+            *counts.entry((resource.kind, resource.site)).or_insert(0) += 1;
+        }
+
+        counts.into_iter().map(|((kind, site), count)| (kind, site, count)).collect()
+    }
+
+    /// Logs every object that is still alive, returning how many there are
+    pub fn report_leaks(&self) -> usize {
+        for (kind, site, count) in self.live_resources() {
+            error!("Leaked {} {:?} object(s) created at {}", count, kind, site);
+        }
+
+        self.live.len()
+    }
+}
+
+/// Registers an object with the resource tracker of a DeviceState, recording the file and line it was created on:
+///
+/// ```ignore
+/// let resource_id = track_resource!(device_state, Buffer);
+/// ```
+#[macro_export]
+macro_rules! track_resource {
+    ($device_state:expr, $kind:ident) => {
+        $device_state
+            .resource_tracker
+            .borrow_mut()
+            .register($crate::graphics::tracker::ResourceKind::$kind, concat!(file!(), ":", line!()))
+    };
+}
+
+/// Reports the objects left alive once everything declared before it has been dropped.
+/// Declared as the last field of RendererState, so it runs after the renderer's own resources are destroyed.
+pub struct LeakCheck<B: Backend, C: Capability> {
+    device_state: Rc<RefCell<DeviceState<B, C>>>,
+}
+
+impl<B: Backend, C: Capability> LeakCheck<B, C> {
+    pub fn new(device_state: Rc<RefCell<DeviceState<B, C>>>) -> Self {
+        LeakCheck { device_state }
+    }
+}
+
+impl<B: Backend, C: Capability> Drop for LeakCheck<B, C> {
+    fn drop(&mut self) {
+        let device_state = self.device_state.as_ref().borrow();
+
+        // Pools that no longer hold any sets belong to the device rather than to any caller:
+        unsafe {
+            device_state.descriptor_allocator.borrow_mut().trim(&device_state);
+
+            // As do cached samplers, though they can only be destroyed once nothing else holds the device to sample with them:
+            if Rc::strong_count(&self.device_state) == 1 {
+                device_state.sampler_cache.borrow_mut().dispose(&device_state);
+            }
+        }
+
+        let leak_count = device_state.resource_tracker.borrow().report_leaks();
+
+        if leak_count > 0 {
+            error!("{} GPU object(s) were still alive when the renderer was dropped", leak_count);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn should_track_live_resources() {
+        let mut tracker = ResourceTracker::new(true);
+
+        let buffer = tracker.register(ResourceKind::Buffer, "buffer.rs:1");
+        let image = tracker.register(ResourceKind::Image, "image.rs:1");
+        tracker.register(ResourceKind::Buffer, "buffer.rs:1");
+        tracker.register(ResourceKind::Buffer, "upload.rs:1");
+
+        tracker.release_all(&[buffer, image]);
+
+        assert_eq!(2, tracker.live_count(ResourceKind::Buffer));
+        assert_eq!(0, tracker.live_count(ResourceKind::Image));
+        assert_eq!(
+            vec![(ResourceKind::Buffer, "buffer.rs:1", 1), (ResourceKind::Buffer, "upload.rs:1", 1)],
+            tracker.live_resources()
+        );
+        assert_eq!(2, tracker.report_leaks());
+    }
+
+    #[test]
+    fn should_not_track_when_disabled() {
+        let mut tracker = ResourceTracker::new(false);
+
+        let sampler = tracker.register(ResourceKind::Sampler, "image.rs:1");
+        tracker.release(sampler);
+        tracker.register(ResourceKind::Sampler, "image.rs:1");
+
+        assert_eq!(0, tracker.total_live_count());
+    }
+}
@@ -1,49 +1,354 @@
+use camera::Camera;
+use cgmath::{Matrix4, SquareMatrix, Vector3};
+use errors::*;
 use gfx_hal::*;
 use graphics::buffer::BufferState;
-use graphics::descriptor::{DescriptorSet, DescriptorSetWrite};
+use graphics::descriptor::{DescriptorSet, DescriptorSetLayout, DescriptorSetWrite};
 use graphics::device::DeviceState;
+use graphics::Vec4;
 use std::cell::RefCell;
+use std::marker::PhantomData;
 use std::rc::Rc;
 
-pub struct Uniform<B: Backend> {
+/// The GLSL types a uniform block member can have
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UniformType {
+    Float,
+    Int,
+    Uint,
+    Vec2,
+    Vec3,
+    Vec4,
+    IVec4,
+    UVec4,
+    Mat4,
+}
+
+impl UniformType {
+    /// The alignment of the type within a block, following the std140 rules
+    pub fn std140_alignment(&self) -> u32 {
+        match *self {
+            UniformType::Float | UniformType::Int | UniformType::Uint => 4,
+            UniformType::Vec2 => 8,
+            UniformType::Vec3 | UniformType::Vec4 | UniformType::IVec4 | UniformType::UVec4 | UniformType::Mat4 => 16,
+        }
+    }
+
+    pub fn std140_size(&self) -> u32 {
+        match *self {
+            UniformType::Float | UniformType::Int | UniformType::Uint => 4,
+            UniformType::Vec2 => 8,
+            UniformType::Vec3 => 12,
+            UniformType::Vec4 | UniformType::IVec4 | UniformType::UVec4 => 16,
+            UniformType::Mat4 => 64,
+        }
+    }
+}
+
+/// Maps the Rust type of a uniform block field onto its GLSL type
+pub trait Std140Type {
+    const TYPE: UniformType;
+}
+
+macro_rules! std140_type {
+    ($($ty:ty => $uniform_type:ident),*) => {
+        $(
+            impl Std140Type for $ty {
+                const TYPE: UniformType = UniformType::$uniform_type;
+            }
+        )*
+    };
+}
+
+std140_type! {
+    f32 => Float,
+    i32 => Int,
+    u32 => Uint,
+    [f32; 2] => Vec2,
+    [f32; 3] => Vec3,
+    [f32; 4] => Vec4,
+    [i32; 4] => IVec4,
+    [u32; 4] => UVec4,
+    // Matrices are column major, as cgmath converts them:
+    [[f32; 4]; 4] => Mat4
+}
+
+/// Returns the uniform type of a field, inferring its type from the reference
+pub fn uniform_type_of<T: Std140Type>(_field: &T) -> UniformType {
+    T::TYPE
+}
+
+/// A member of a uniform block and where it sits within the Rust struct
+#[derive(Debug, Clone, PartialEq)]
+pub struct UniformField {
+    pub name: &'static str,
+    pub ty: UniformType,
+    pub offset: u32,
+}
+
+/// Describes the memory layout of a `#[repr(C)]` uniform block struct.
+/// Implement with the `uniform_block!` macro rather than by hand.
+pub trait UniformBlock: Copy {
+    fn name() -> &'static str;
+
+    /// Returns the members of the block, in declaration order
+    fn fields() -> Vec<UniformField>;
+
+    /// Checks that every field sits where std140 places it, so the struct can be copied directly into a buffer
+    fn validate_std140() -> Result<()> {
+        validate_std140(Self::name(), &Self::fields())
+    }
+}
+
+/// Checks the offsets of the fields against those assigned by the std140 layout rules
+pub fn validate_std140(block_name: &str, fields: &[UniformField]) -> Result<()> {
+    let mut next_offset = 0;
+
+    for field in fields {
+        let alignment = field.ty.std140_alignment();
+        let expected_offset = (next_offset + alignment - 1) / alignment * alignment;
+
+        if field.offset != expected_offset {
+            bail!(ErrorKind::InvalidUniformLayout(
+                block_name.to_string(),
+                format!(
+                    "field '{}' is at offset {} but std140 places a {:?} at offset {}",
+                    field.name, field.offset, field.ty, expected_offset
+                ),
+            ));
+        }
+
+        next_offset = expected_offset + field.ty.std140_size();
+    }
+
+    Ok(())
+}
+
+/// Implements `UniformBlock` for a `#[repr(C)]` struct that implements `Default`,
+/// listing every field in the order it is declared in the shader:
+///
+/// ```ignore
+/// uniform_block!(CameraUniformBlock { view, projection, view_projection, position });
+/// ```
+#[macro_export]
+macro_rules! uniform_block {
+    ($block:ident { $($field:ident),* $(,)* }) => {
+        impl $crate::graphics::uniform::UniformBlock for $block {
+            fn name() -> &'static str {
+                stringify!($block)
+            }
+
+            fn fields() -> Vec<$crate::graphics::uniform::UniformField> {
+                let block = <$block as Default>::default();
+                let base_address = &block as *const $block as usize;
+
+                vec![$(
+                    $crate::graphics::uniform::UniformField {
+                        name: stringify!($field),
+                        ty: $crate::graphics::uniform::uniform_type_of(&block.$field),
+                        offset: (&block.$field as *const _ as usize - base_address) as u32,
+                    }
+                ),*]
+            }
+        }
+    };
+}
+
+/// The per-frame camera data, laid out to match `CameraBlock` in the vertex shader
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct CameraUniformBlock {
+    pub view: [[f32; 4]; 4],
+    pub projection: [[f32; 4]; 4],
+    pub view_projection: [[f32; 4]; 4],
+    pub position: Vec4,
+}
+
+uniform_block!(CameraUniformBlock {
+    view,
+    projection,
+    view_projection,
+    position,
+});
+
+/// cgmath's projections map to OpenGL's clip space, where y points up and depth runs from -1 to 1. The clip spaces of
+/// the Vulkan, DX12 and Metal backends have y pointing down and depth running from 0 to 1, so projections are corrected for them.
+pub fn clip_correction() -> Matrix4<f32> {
+    if cfg!(feature = "gl") {
+        Matrix4::identity()
+    } else {
+        Matrix4::from_translation(Vector3::new(0.0, 0.0, 0.5)) * Matrix4::from_nonuniform_scale(1.0, -1.0, 0.5)
+    }
+}
+
+impl<'a> From<&'a Camera<f32>> for CameraUniformBlock {
+    fn from(camera: &'a Camera<f32>) -> Self {
+        let view = camera.get_view_matrix();
+        let projection = clip_correction() * camera.get_projection_matrix();
+        let position = camera.get_position();
+
+        CameraUniformBlock {
+            view: view.into(),
+            projection: projection.into(),
+            view_projection: (projection * view).into(),
+            position: [position.x, position.y, position.z, 1.0],
+        }
+    }
+}
+
+/// A uniform buffer with a region for each frame in flight, bound as a `UniformBufferDynamic`.
+/// Each frame writes its own region, so the block can be updated every frame without
+/// waiting for the GPU to finish reading what was written for earlier frames.
+pub struct DynamicUniform<B: Backend, T: UniformBlock> {
     pub buffer_state: Option<BufferState<B, Graphics>>,
     pub descriptor_set: Option<DescriptorSet<B, Graphics>>,
+    region_size: u64,
+    region_count: usize,
+    block: PhantomData<T>,
 }
 
-impl<B: Backend> Uniform<B> {
-    pub unsafe fn new<T>(
+impl<B: Backend, T: UniformBlock> DynamicUniform<B, T> {
+    /// Regions are padded to `min_offset_alignment`, taken from the adapter's `min_uniform_buffer_offset_alignment`
+    pub unsafe fn new(
         device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
-        memory_types: &[MemoryType],
-        uniform_buffer_source: &[T],
         mut descriptor_set: DescriptorSet<B, Graphics>,
         binding: u32,
-    ) -> Self
-    where
-        T: Copy,
-    {
-        let buffer_state = BufferState::new(
-            Rc::clone(&device_state),
-            uniform_buffer_source,
-            buffer::Usage::UNIFORM,
-            memory_types,
-        );
+        region_count: usize,
+        min_offset_alignment: u64,
+    ) -> Result<Self> {
+        T::validate_std140()?;
+
+        let block_size = std::mem::size_of::<T>() as u64;
+        let alignment = min_offset_alignment.max(1);
+        let region_size = (block_size + alignment - 1) / alignment * alignment;
 
+        let initial_data = vec![0u8; (region_size * region_count as u64) as usize];
+        let buffer_state = BufferState::new(Rc::clone(&device_state), &initial_data, buffer::Usage::UNIFORM)?;
+
+        // Dynamic descriptors describe a single region, which the dynamic offset then moves through the buffer:
         descriptor_set.write_to_state(
             &mut device_state.as_ref().borrow_mut().device,
             vec![DescriptorSetWrite {
                 binding,
                 array_offset: 0,
-                descriptors: Some(pso::Descriptor::Buffer(buffer_state.get_buffer(), None..None)),
+                descriptors: Some(pso::Descriptor::Buffer(buffer_state.get_buffer(), Some(0)..Some(block_size))),
             }],
         );
 
-        Uniform {
+        Ok(DynamicUniform {
             buffer_state: Some(buffer_state),
             descriptor_set: Some(descriptor_set),
-        }
+            region_size,
+            region_count,
+            block: PhantomData,
+        })
+    }
+
+    /// Writes the block into the region of the given frame, returning the dynamic offset to bind it with.
+    /// The GPU must have finished the last frame drawn with the same index, e.g. by waiting on its fence.
+    pub unsafe fn write(&mut self, frame_index: usize, uniform_block: &T) -> Result<u32> {
+        let offset = (frame_index % self.region_count) as u64 * self.region_size;
+
+        self.buffer_state.as_mut().unwrap().update_data(offset, &[*uniform_block])?;
+
+        Ok(offset as u32)
+    }
+
+    pub fn region_count(&self) -> usize {
+        self.region_count
     }
 
     pub fn get_layout(&self) -> &B::DescriptorSetLayout {
         self.descriptor_set.as_ref().unwrap().get_layout()
     }
+
+    pub fn get_set_layout(&self) -> &DescriptorSetLayout<B, Graphics> {
+        &self.descriptor_set.as_ref().unwrap().layout
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use cgmath::*;
+
+    #[derive(Debug, Default, Clone, Copy)]
+    #[repr(C)]
+    struct LightUniformBlock {
+        direction: [f32; 3],
+        intensity: f32,
+        color: [f32; 4],
+        texel_size: [f32; 2],
+        cascade_count: u32,
+    }
+
+    uniform_block!(LightUniformBlock {
+        direction,
+        intensity,
+        color,
+        texel_size,
+        cascade_count,
+    });
+
+    #[derive(Debug, Default, Clone, Copy)]
+    #[repr(C)]
+    struct MisalignedUniformBlock {
+        roughness: f32,
+        base_color: [f32; 4],
+    }
+
+    uniform_block!(MisalignedUniformBlock { roughness, base_color });
+
+    #[test]
+    fn should_create_camera_uniform_block() {
+        let mut camera = Camera::<f32>::default();
+        camera.set_position(1.0, 2.0, 3.0);
+        camera.look_at(Point3::new(0.0, 0.0, 0.0), Vector3::unit_y());
+        camera.set_projection_matrix(800.0, 600.0, 65.0, 0.1, 100.0);
+
+        let block = CameraUniformBlock::from(&camera);
+
+        let expected_view_projection: [[f32; 4]; 4] =
+            (clip_correction() * camera.get_projection_matrix() * camera.get_view_matrix()).into();
+        assert_eq!(expected_view_projection, block.view_projection);
+        assert_eq!([1.0, 2.0, 3.0, 1.0], block.position);
+    }
+
+    #[test]
+    #[cfg(not(feature = "gl"))]
+    fn should_correct_projections_for_clip_space() {
+        let mut camera = Camera::<f32>::default();
+        camera.set_projection_matrix(800.0, 600.0, 65.0, 0.1, 100.0);
+
+        let projection = Matrix4::from(CameraUniformBlock::from(&camera).projection);
+        let project = |point: Vector4<f32>| {
+            let clip = projection * point;
+            clip.truncate() / clip.w
+        };
+
+        // The near and far planes are at depths 0 and 1, and points above the camera are at the top of the image:
+        assert!(project(Vector4::new(0.0, 0.0, -0.1, 1.0)).z.abs() < 1e-6);
+        assert!((project(Vector4::new(0.0, 0.0, -100.0, 1.0)).z - 1.0).abs() < 1e-6);
+        assert!(project(Vector4::new(0.0, 1.0, -10.0, 1.0)).y < 0.0);
+    }
+
+    #[test]
+    fn should_accept_std140_layouts() {
+        assert!(CameraUniformBlock::validate_std140().is_ok());
+        assert!(LightUniformBlock::validate_std140().is_ok());
+
+        let offsets: Vec<u32> = LightUniformBlock::fields().iter().map(|field| field.offset).collect();
+        assert_eq!(vec![0, 12, 16, 32, 40], offsets);
+    }
+
+    #[test]
+    fn should_reject_misaligned_fields() {
+        match MisalignedUniformBlock::validate_std140() {
+            Err(Error(ErrorKind::InvalidUniformLayout(block, reason), _)) => {
+                assert_eq!("MisalignedUniformBlock", block);
+                assert!(reason.contains("base_color"));
+            }
+            _ => panic!("Expected an InvalidUniformLayout error"),
+        }
+    }
 }
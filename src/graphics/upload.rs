@@ -0,0 +1,261 @@
+use errors::*;
+use gfx_hal::format as f;
+use gfx_hal::image as i;
+use gfx_hal::memory as m;
+use gfx_hal::pso::PipelineStage;
+use gfx_hal::*;
+use graphics::buffer::BufferState;
+use graphics::device::DeviceState;
+use graphics::image::{Image, TextureState};
+use graphics::tracker::ResourceId;
+use graphics::vertex::VertexFormat;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Copies data into device local buffers and images through host visible staging buffers.
+/// Every copy is recorded into one command buffer and submitted together, with the
+/// staging buffers kept alive until the fence signals that the copies have completed
+pub struct UploadBatch<B: Backend> {
+    device_state: Rc<RefCell<DeviceState<B, Graphics>>>,
+    command_pool: Option<CommandPool<B, Graphics>>,
+    command_buffer: Option<command::CommandBuffer<B, Graphics, command::OneShot>>,
+    staging_buffers: Vec<BufferState<B, Graphics>>,
+    fence: Option<B::Fence>,
+    submitted: bool,
+    resource_ids: Vec<ResourceId>,
+}
+
+impl<B: Backend> UploadBatch<B> {
+    pub unsafe fn new(device_state: Rc<RefCell<DeviceState<B, Graphics>>>) -> Result<Self> {
+        let (mut command_pool, fence, resource_ids) = {
+            let device_state = device_state.as_ref().borrow();
+
+            let command_pool = device_state.create_command_pool()?;
+            let mut resource_ids = vec![track_resource!(device_state, CommandPool)];
+
+            let fence = device_state.device.create_fence(false).graphics_err("create upload fence")?;
+            resource_ids.push(track_resource!(device_state, Fence));
+
+            (command_pool, fence, resource_ids)
+        };
+
+        let mut command_buffer = command_pool.acquire_command_buffer::<command::OneShot>();
+        command_buffer.begin();
+
+        Ok(UploadBatch {
+            device_state,
+            command_pool: Some(command_pool),
+            command_buffer: Some(command_buffer),
+            staging_buffers: Vec::new(),
+            fence: Some(fence),
+            submitted: false,
+            resource_ids,
+        })
+    }
+
+    /// Creates a device local buffer that will hold the data once the batch has been submitted
+    pub unsafe fn upload_buffer<T>(&mut self, buffer_source: &[T], buffer_usage: buffer::Usage) -> Result<BufferState<B, Graphics>>
+    where
+        T: Copy,
+    {
+        assert!(!self.submitted, "Uploads cannot be added to a batch that has been submitted");
+
+        let staging_buffer = BufferState::new(Rc::clone(&self.device_state), buffer_source, buffer::Usage::TRANSFER_SRC)?;
+
+        let buffer_state = BufferState::new_device_local(Rc::clone(&self.device_state), staging_buffer.size(), buffer_usage)?;
+
+        self.command_buffer.as_mut().unwrap().copy_buffer(
+            staging_buffer.get_buffer(),
+            buffer_state.get_buffer(),
+            &[command::BufferCopy {
+                src: 0,
+                dst: 0,
+                size: staging_buffer.size(),
+            }],
+        );
+
+        self.staging_buffers.push(staging_buffer);
+
+        Ok(buffer_state)
+    }
+
+    /// Creates a device local vertex buffer from vertices whose layout is described by VertexFormat
+    pub unsafe fn upload_vertex_buffer<V>(&mut self, vertices: &[V]) -> Result<BufferState<B, Graphics>>
+    where
+        V: VertexFormat,
+    {
+        self.upload_buffer::<V>(vertices, buffer::Usage::VERTEX)
+    }
+
+    /// Creates a device local texture that will hold every level of the image once the batch has been submitted.
+    /// The texture is ready to be sampled by fragment shaders from then on.
+    pub unsafe fn upload_image(&mut self, image: &Image, row_alignment_mask: u32) -> Result<TextureState<B>> {
+        assert!(!self.submitted, "Uploads cannot be added to a batch that has been submitted");
+
+        let (staging_buffer, texture_state) = {
+            let device_state = self.device_state.as_ref().borrow();
+
+            let staging_buffer = BufferState::new_texture(
+                Rc::clone(&self.device_state),
+                &device_state,
+                image,
+                row_alignment_mask,
+                buffer::Usage::TRANSFER_SRC,
+            )?;
+
+            let texture_state = TextureState::new(Rc::clone(&self.device_state), &device_state, image)?;
+
+            (staging_buffer, texture_state)
+        };
+
+        let command_buffer = self.command_buffer.as_mut().unwrap();
+
+        command_buffer.pipeline_barrier(
+            PipelineStage::TOP_OF_PIPE..PipelineStage::TRANSFER,
+            m::Dependencies::empty(),
+            &[m::Barrier::Image {
+                states: (i::Access::empty(), i::Layout::Undefined)..(i::Access::TRANSFER_WRITE, i::Layout::TransferDstOptimal),
+                target: texture_state.get_image(),
+                families: None,
+                range: image.get_range(),
+            }],
+        );
+
+        // Each level was written to the buffer after the one above it, in rows of whole blocks:
+        let regions: Vec<command::BufferImageCopy> = (0..image.mip_level_count())
+            .map(|level| {
+                let (level_width, level_height) = image.get_level_dimensions(level);
+
+                command::BufferImageCopy {
+                    buffer_offset: image.level_offset(level, row_alignment_mask),
+                    buffer_width: image.buffer_width(level, row_alignment_mask),
+                    buffer_height: image.buffer_height(level),
+                    image_layers: i::SubresourceLayers {
+                        aspects: f::Aspects::COLOR,
+                        level,
+                        layers: 0..1,
+                    },
+                    image_offset: i::Offset { x: 0, y: 0, z: 0 },
+                    image_extent: i::Extent {
+                        width: level_width,
+                        height: level_height,
+                        depth: 1,
+                    },
+                }
+            })
+            .collect();
+
+        command_buffer.copy_buffer_to_image(
+            staging_buffer.get_buffer(),
+            texture_state.get_image(),
+            i::Layout::TransferDstOptimal,
+            &regions,
+        );
+
+        command_buffer.pipeline_barrier(
+            PipelineStage::TRANSFER..PipelineStage::FRAGMENT_SHADER,
+            m::Dependencies::empty(),
+            &[m::Barrier::Image {
+                states: (i::Access::TRANSFER_WRITE, i::Layout::TransferDstOptimal)
+                    ..(i::Access::SHADER_READ, i::Layout::ShaderReadOnlyOptimal),
+                target: texture_state.get_image(),
+                families: None,
+                range: image.get_range(),
+            }],
+        );
+
+        self.staging_buffers.push(staging_buffer);
+
+        Ok(texture_state)
+    }
+
+    /// Submits every copy recorded so far to the graphics queue
+    pub unsafe fn submit(&mut self) -> Result<()> {
+        assert!(!self.submitted, "An upload batch can only be submitted once");
+
+        let command_buffer = self.command_buffer.as_mut().unwrap();
+
+        // Make the copies visible to anything reading the buffers afterwards:
+        command_buffer.pipeline_barrier(
+            PipelineStage::TRANSFER..PipelineStage::VERTEX_INPUT | PipelineStage::VERTEX_SHADER | PipelineStage::FRAGMENT_SHADER,
+            m::Dependencies::empty(),
+            &[m::Barrier::AllBuffers(
+                buffer::Access::TRANSFER_WRITE
+                    ..buffer::Access::VERTEX_BUFFER_READ
+                        | buffer::Access::INDEX_BUFFER_READ
+                        | buffer::Access::UNIFORM_READ
+                        | buffer::Access::SHADER_READ,
+            )],
+        );
+
+        command_buffer.finish();
+
+        self.device_state.as_ref().borrow_mut().queue_group.queues[0]
+            .submit_nosemaphores(std::iter::once(&*command_buffer), self.fence.as_ref());
+
+        self.submitted = true;
+
+        info!("Submitted {} uploads", self.staging_buffers.len());
+
+        Ok(())
+    }
+
+    /// Returns true once the submitted copies have finished, without blocking
+    pub unsafe fn is_complete(&self) -> Result<bool> {
+        if !self.submitted {
+            return Ok(false);
+        }
+
+        self.device_state
+            .as_ref()
+            .borrow()
+            .device
+            .get_fence_status(self.fence.as_ref().unwrap())
+            .graphics_err("get upload fence status")
+    }
+
+    /// Blocks until the submitted copies have finished, then releases the staging buffers
+    pub unsafe fn wait(&mut self) -> Result<()> {
+        if self.submitted {
+            self.device_state
+                .as_ref()
+                .borrow()
+                .device
+                .wait_for_fence(self.fence.as_ref().unwrap(), !0)
+                .graphics_err("wait for upload fence")?;
+        }
+
+        self.staging_buffers.clear();
+
+        Ok(())
+    }
+
+    /// Submits the batch and waits for it to complete
+    pub unsafe fn flush(&mut self) -> Result<()> {
+        self.submit()?;
+        self.wait()
+    }
+}
+
+impl<B: Backend> Drop for UploadBatch<B> {
+    fn drop(&mut self) {
+        // The staging buffers and command buffer can't be released while the GPU may still be reading them:
+        unsafe {
+            if let Err(e) = self.wait() {
+                error!("Failed to wait for uploads to complete: {}", e);
+            }
+        }
+
+        let device_state = self.device_state.as_ref().borrow();
+        let device = &device_state.device;
+
+        unsafe {
+            device.destroy_fence(self.fence.take().unwrap());
+
+            self.command_buffer.take();
+            device.destroy_command_pool(self.command_pool.take().unwrap().into_raw());
+        }
+
+        device_state.resource_tracker.borrow_mut().release_all(&self.resource_ids);
+    }
+}
@@ -0,0 +1,144 @@
+use gfx_hal::format as f;
+use graphics::pipeline::{VertexAttribute, VertexLayout};
+
+/// Maps the Rust type of a vertex field onto the format the GPU reads it as
+pub trait AttributeFormat {
+    const FORMAT: f::Format;
+}
+
+macro_rules! attribute_format {
+    ($($ty:ty => $format:ident),*) => {
+        $(
+            impl AttributeFormat for $ty {
+                const FORMAT: f::Format = f::Format::$format;
+            }
+        )*
+    };
+}
+
+attribute_format! {
+    f32 => R32Float,
+    [f32; 2] => Rg32Float,
+    [f32; 3] => Rgb32Float,
+    [f32; 4] => Rgba32Float,
+    u32 => R32Uint,
+    [u32; 2] => Rg32Uint,
+    [u32; 3] => Rgb32Uint,
+    [u32; 4] => Rgba32Uint,
+    i32 => R32Int,
+    [i32; 2] => Rg32Int,
+    [i32; 3] => Rgb32Int,
+    [i32; 4] => Rgba32Int,
+    // Byte colors are normalised into the 0.0..1.0 range:
+    [u8; 4] => Rgba8Unorm
+}
+
+/// Returns the attribute format of a field, inferring its type from the reference
+pub fn attribute_format_of<T: AttributeFormat>(_field: &T) -> f::Format {
+    T::FORMAT
+}
+
+/// Describes the memory layout of a `#[repr(C)]` vertex struct.
+/// Implement with the `vertex_format!` macro rather than by hand.
+pub trait VertexFormat: Copy {
+    /// Returns the shader input attributes of the vertex, in declaration order
+    fn attributes() -> Vec<VertexAttribute>;
+
+    fn stride() -> u32 {
+        std::mem::size_of::<Self>() as u32
+    }
+}
+
+impl VertexLayout {
+    pub fn from_format<V: VertexFormat>() -> Self {
+        VertexLayout {
+            stride: V::stride(),
+            attributes: V::attributes(),
+        }
+    }
+}
+
+/// Implements `VertexFormat` for a `#[repr(C)]` struct that implements `Default`,
+/// assigning each listed field to a shader input location:
+///
+/// ```ignore
+/// vertex_format!(Vertex { a_Position => 0, a_TexCoord => 1 });
+/// ```
+#[macro_export]
+macro_rules! vertex_format {
+    ($vertex:ty { $($field:ident => $location:expr),* $(,)* }) => {
+        impl $crate::graphics::vertex::VertexFormat for $vertex {
+            fn attributes() -> Vec<$crate::graphics::pipeline::VertexAttribute> {
+                let vertex = <$vertex as Default>::default();
+                let base_address = &vertex as *const $vertex as usize;
+
+                vec![$(
+                    $crate::graphics::pipeline::VertexAttribute {
+                        location: $location,
+                        format: $crate::graphics::vertex::attribute_format_of(&vertex.$field),
+                        offset: (&vertex.$field as *const _ as usize - base_address) as u32,
+                    }
+                ),*]
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use graphics::Vertex;
+
+    #[derive(Debug, Default, Clone, Copy)]
+    #[repr(C)]
+    struct SkinnedVertex {
+        position: [f32; 3],
+        normal: [f32; 3],
+        color: [u8; 4],
+        joints: [u32; 4],
+        weight: f32,
+    }
+
+    vertex_format!(SkinnedVertex {
+        position => 0,
+        normal => 1,
+        color => 2,
+        joints => 3,
+        weight => 4,
+    });
+
+    #[test]
+    fn should_describe_vertex_layout() {
+        let layout = VertexLayout::from_format::<Vertex>();
+
+        assert_eq!(20, layout.stride);
+        assert_eq!(
+            vec![
+                VertexAttribute { location: 0, format: f::Format::Rgb32Float, offset: 0 },
+                VertexAttribute { location: 1, format: f::Format::Rg32Float, offset: 12 },
+            ],
+            layout.attributes
+        );
+    }
+
+    #[test]
+    fn should_describe_custom_vertex_layout() {
+        let layout = VertexLayout::from_format::<SkinnedVertex>();
+
+        let formats: Vec<f::Format> = layout.attributes.iter().map(|attribute| attribute.format).collect();
+        let offsets: Vec<u32> = layout.attributes.iter().map(|attribute| attribute.offset).collect();
+
+        assert_eq!(48, layout.stride);
+        assert_eq!(
+            vec![
+                f::Format::Rgb32Float,
+                f::Format::Rgb32Float,
+                f::Format::Rgba8Unorm,
+                f::Format::Rgba32Uint,
+                f::Format::R32Float,
+            ],
+            formats
+        );
+        assert_eq!(vec![0, 12, 24, 28, 44], offsets);
+    }
+}
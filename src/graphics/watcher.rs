@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the shader directory is scanned for changes
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Watches a directory tree for files that are added or modified, by polling their modification times.
+/// Polling avoids a dependency on platform file notification APIs, and is cheap for a directory of shaders.
+pub struct ShaderWatcher {
+    dir: PathBuf,
+    modified_times: HashMap<PathBuf, SystemTime>,
+    poll_interval: Duration,
+    last_poll: Instant,
+}
+
+impl ShaderWatcher {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        let dir = dir.as_ref().to_path_buf();
+        let modified_times = scan_modified_times(&dir);
+
+        info!(
+            "Watching {} shader file(s) in '{}' for changes",
+            modified_times.len(),
+            dir.display()
+        );
+
+        ShaderWatcher {
+            dir,
+            modified_times,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            last_poll: Instant::now(),
+        }
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Returns the files added or modified since the previous scan, scanning at most once per poll interval
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        if self.last_poll.elapsed() < self.poll_interval {
+            return Vec::new();
+        }
+
+        self.last_poll = Instant::now();
+
+        let modified_times = scan_modified_times(&self.dir);
+
+        let mut changed_files: Vec<PathBuf> = modified_times
+            .iter()
+            .filter(|&(path, modified_time)| self.modified_times.get(path) != Some(modified_time))
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        changed_files.sort();
+
+        self.modified_times = modified_times;
+
+        changed_files
+    }
+}
+
+/// Finds the modification time of every file beneath the directory.
+/// Files that can't be read are left out, as they may be part way through being saved.
+fn scan_modified_times(dir: &Path) -> HashMap<PathBuf, SystemTime> {
+    let mut modified_times = HashMap::new();
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+
+            match entry.metadata() {
+                Ok(ref metadata) if metadata.is_dir() => dirs.push(path),
+                Ok(metadata) => {
+                    if let Ok(modified_time) = metadata.modified() {
+                        modified_times.insert(path, modified_time);
+                    }
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    modified_times
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::env;
+
+    fn create_test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("corporation_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("include")).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn should_report_new_files() {
+        let dir = create_test_dir("watcher_new_files");
+        fs::write(dir.join("quad.vert"), "void main() {}").unwrap();
+
+        let mut watcher = ShaderWatcher::new(&dir).with_poll_interval(Duration::from_millis(0));
+
+        assert!(watcher.poll().is_empty());
+
+        fs::write(dir.join("include").join("lighting.glsl"), "vec3 light;").unwrap();
+
+        assert_eq!(vec![dir.join("include").join("lighting.glsl")], watcher.poll());
+        assert!(watcher.poll().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn should_wait_for_poll_interval() {
+        let dir = create_test_dir("watcher_interval");
+
+        let mut watcher = ShaderWatcher::new(&dir).with_poll_interval(Duration::from_secs(60));
+
+        fs::write(dir.join("quad.frag"), "void main() {}").unwrap();
+
+        assert!(watcher.poll().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
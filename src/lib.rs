@@ -1,8 +1,10 @@
 extern crate assimp;
+extern crate assimp_sys;
 extern crate cgmath;
 #[macro_use]
 extern crate error_chain;
 extern crate image;
+#[cfg(feature = "runtime-shaders")]
 extern crate shaderc;
 #[macro_use]
 extern crate log;
@@ -20,40 +22,86 @@ extern crate gfx_backend_metal as gfx_backend;
 extern crate gfx_backend_vulkan as gfx_backend;
 extern crate gfx_hal as gfx_hal;
 
+pub mod application;
 pub mod camera;
 pub mod errors;
 pub mod graphics;
+pub mod material;
 pub mod mesh;
 pub mod scene;
 
+use application::Application;
 use errors::*;
 use graphics::backend::BackendState;
 use graphics::renderer::RendererState;
 use graphics::window::WindowState;
+use std::time::Instant;
 
 const WINDOW_WIDTH: f64 = 640.0;
 const WINDOW_HEIGHT: f64 = 480.0;
 const WINDOW_TITLE: &str = "corporation";
 
+/// The gfx_hal backend selected by the enabled cargo feature
+pub type Backend = gfx_backend::Backend;
+
+pub type Renderer = RendererState<Backend>;
+
 #[cfg(any(feature = "gl", feature = "dx12", feature = "vulkan", feature = "metal"))]
-pub fn run() -> Result<()> {
+pub fn run<A: Application>(application: &mut A) -> Result<()> {
   info!("corporation starting...");
 
   let mut window_state = WindowState::new(WINDOW_TITLE, WINDOW_WIDTH, WINDOW_HEIGHT);
 
-  let (backend_state, _instance) = BackendState::<gfx_backend::Backend>::new(&mut window_state);
+  let (backend_state, _instance) = BackendState::<Backend>::new(&mut window_state, &application.adapter_config().apply_env_override())?;
+
+  let mut renderer = unsafe { RendererState::new(backend_state, WINDOW_WIDTH as u32, WINDOW_HEIGHT as u32)? };
+
+  // The application's GPU resources must be released before the renderer, however the frame loop ends:
+  let result = run_frame_loop(application, &mut window_state, &mut renderer);
+
+  application.shutdown(&mut renderer);
+
+  result
+}
+
+#[cfg(any(feature = "gl", feature = "dx12", feature = "vulkan", feature = "metal"))]
+fn run_frame_loop<A: Application>(application: &mut A, window_state: &mut WindowState, renderer: &mut Renderer) -> Result<()> {
+  application.init(renderer)?;
+
+  let mut is_running = true;
+  let mut last_frame_time = Instant::now();
+
+  while is_running {
+    window_state.event_loop.poll_events(|event| {
+      if let winit::Event::WindowEvent { event, .. } = event {
+        application.handle_event(&event);
+
+        match event {
+          // Handle the window being closed:
+          winit::WindowEvent::CloseRequested => is_running = false,
+
+          // Handle the window being resized:
+          winit::WindowEvent::Resized(dimensions) => renderer.resize(dimensions),
+          _ => {}
+        }
+      }
+    });
+
+    let frame_time = Instant::now();
+    let elapsed = frame_time.duration_since(last_frame_time);
+    last_frame_time = frame_time;
 
-  let mut renderer_state = unsafe { RendererState::new(backend_state, window_state, WINDOW_WIDTH as u32, WINDOW_HEIGHT as u32) };
+    let delta_time = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 * 1e-9;
 
-  unsafe {
-    renderer_state.render();
+    application.update(delta_time)?;
+    application.render(renderer)?;
   }
 
   Ok(())
 }
 
 #[cfg(feature = "empty")]
-pub fn run() -> Result<()> {
+pub fn run<A: Application>(_application: &mut A) -> Result<()> {
   error!("corporation requires a non-empty gfx_hal backend");
 
   Ok(())
@@ -0,0 +1,89 @@
+use graphics::{Vec3, Vec4};
+use std::collections::hash_map;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The purpose of a texture referenced by a Material
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TextureSlot {
+    Diffuse,
+    Specular,
+    Normal,
+    Height,
+    Emissive,
+    Opacity,
+    Lightmap,
+}
+
+/// Describes the surface properties of a Mesh
+#[derive(Debug, Clone, PartialEq)]
+pub struct Material {
+    name: String,
+    pub base_color: Vec4,
+    pub specular: Vec3,
+    pub shininess: f32,
+    pub opacity: f32,
+    textures: HashMap<TextureSlot, PathBuf>,
+}
+
+impl Default for Material {
+    fn default() -> Material {
+        Material {
+            name: String::new(),
+            base_color: [1.0, 1.0, 1.0, 1.0],
+            specular: [0.0, 0.0, 0.0],
+            shininess: 0.0,
+            opacity: 1.0,
+            textures: HashMap::new(),
+        }
+    }
+}
+
+impl Material {
+    pub fn new(name: &str) -> Material {
+        Material {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the path of the texture bound to the slot, if there is one
+    pub fn texture(&self, slot: TextureSlot) -> Option<&Path> {
+        self.textures.get(&slot).map(|path| path.as_path())
+    }
+
+    pub fn set_texture(&mut self, slot: TextureSlot, path: PathBuf) {
+        self.textures.insert(slot, path);
+    }
+
+    pub fn texture_iter(&self) -> hash_map::Iter<TextureSlot, PathBuf> {
+        self.textures.iter()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn should_set_texture() {
+        let mut material = Material::new("test");
+
+        material.set_texture(TextureSlot::Diffuse, PathBuf::from("diffuse.png"));
+
+        assert_eq!(Some(Path::new("diffuse.png")), material.texture(TextureSlot::Diffuse));
+        assert_eq!(None, material.texture(TextureSlot::Normal));
+    }
+
+    #[test]
+    fn should_default_to_opaque_white() {
+        let material = Material::default();
+
+        assert_eq!([1.0, 1.0, 1.0, 1.0], material.base_color);
+        assert_eq!(1.0, material.opacity);
+    }
+}
@@ -1,7 +1,16 @@
 use assimp;
 use assimp::math::matrix4::Matrix4x4;
+use assimp::math::vector3::Vector3D;
+use assimp_sys::{aiGetMaterialColor, aiGetMaterialFloatArray, aiGetMaterialString, aiGetMaterialTexture};
+use assimp_sys::{AiColor4D, AiMaterial, AiReturn, AiString, AiTextureType};
 use cgmath::*;
-use graphics::Vertex;
+use errors::*;
+use graphics::{Vec2, Vec3, Vec4, Vertex};
+use material::{Material, TextureSlot};
+use std::os::raw::{c_char, c_uint};
+use std::path::Path;
+use std::ptr;
+use std::rc::Rc;
 use std::slice::Iter;
 
 use scene;
@@ -16,6 +25,12 @@ pub struct Mesh {
     name: String,
     vertices: Vec<Vertex>,
     faces: Vec<Face>,
+    normals: Option<Vec<Vec3>>,
+    tangents: Option<Vec<Vec3>>,
+    bitangents: Option<Vec<Vec3>>,
+    colors: Vec<Vec<Vec4>>,
+    tex_coords: Vec<Vec<Vec2>>,
+    material: Option<Rc<Material>>,
 }
 
 impl Mesh {
@@ -30,22 +45,101 @@ impl Mesh {
     pub fn name(&self) -> &String {
         &self.name
     }
+
+    /// Returns the indices of every face, flattened into a single list
+    pub fn indices(&self) -> Vec<u32> {
+        self.faces.iter().flat_map(|face| face.indices.iter().cloned()).collect()
+    }
+
+    pub fn normals(&self) -> Option<&[Vec3]> {
+        self.normals.as_ref().map(|normals| normals.as_slice())
+    }
+
+    pub fn tangents(&self) -> Option<&[Vec3]> {
+        self.tangents.as_ref().map(|tangents| tangents.as_slice())
+    }
+
+    pub fn bitangents(&self) -> Option<&[Vec3]> {
+        self.bitangents.as_ref().map(|bitangents| bitangents.as_slice())
+    }
+
+    /// Returns the vertex colors of the given color set, if the mesh has one
+    pub fn colors(&self, color_set: usize) -> Option<&[Vec4]> {
+        self.colors.get(color_set).map(|colors| colors.as_slice())
+    }
+
+    pub fn num_color_sets(&self) -> usize {
+        self.colors.len()
+    }
+
+    /// Returns the texture coordinates of the given UV channel, if the mesh has one
+    pub fn tex_coords(&self, uv_channel: usize) -> Option<&[Vec2]> {
+        self.tex_coords.get(uv_channel).map(|tex_coords| tex_coords.as_slice())
+    }
+
+    pub fn num_uv_channels(&self) -> usize {
+        self.tex_coords.len()
+    }
+
+    /// Returns the material shared by every face of the mesh
+    pub fn material(&self) -> Option<&Rc<Material>> {
+        self.material.as_ref()
+    }
 }
 
+/// The maximum number of UV channels and vertex color sets assimp stores per mesh
+const AI_MAX_NUMBER_OF_TEXTURECOORDS: usize = 8;
+const AI_MAX_NUMBER_OF_COLOR_SETS: usize = 8;
+
+/// Material property keys, as defined by the AI_MATKEY_* macros in assimp's material.h
+const AI_MATKEY_NAME: &[u8] = b"?mat.name\0";
+const AI_MATKEY_COLOR_DIFFUSE: &[u8] = b"$clr.diffuse\0";
+const AI_MATKEY_COLOR_SPECULAR: &[u8] = b"$clr.specular\0";
+const AI_MATKEY_SHININESS: &[u8] = b"$mat.shininess\0";
+const AI_MATKEY_OPACITY: &[u8] = b"$mat.opacity\0";
+
+const TEXTURE_SLOTS: [(AiTextureType, TextureSlot); 7] = [
+    (AiTextureType::Diffuse, TextureSlot::Diffuse),
+    (AiTextureType::Specular, TextureSlot::Specular),
+    (AiTextureType::Normals, TextureSlot::Normal),
+    (AiTextureType::Height, TextureSlot::Height),
+    (AiTextureType::Emissive, TextureSlot::Emissive),
+    (AiTextureType::Opacity, TextureSlot::Opacity),
+    (AiTextureType::Lightmap, TextureSlot::Lightmap),
+];
+
 pub struct Importer {}
 
 impl Importer {
-    pub fn load(mesh_file_path: &str) -> Option<scene::Node> {
+    pub fn load(mesh_file_path: &str) -> Result<scene::Node> {
         use assimp::import::Importer;
 
-        let importer = Importer::new();
-        if let Ok(scene) = importer.read_file(mesh_file_path) {
-            if !scene.is_incomplete() {
-                return Some(Self::process_node(&scene, &scene.root_node()));
-            }
+        if !Path::new(mesh_file_path).is_file() {
+            bail!(ErrorKind::AssetNotFound(mesh_file_path.to_string()));
+        }
+
+        let mut importer = Importer::new();
+
+        // The renderer draws triangle lists, so polygons must be split on import:
+        importer.triangulate(true);
+
+        let scene = importer
+            .read_file(mesh_file_path)
+            .map_err(|error| ErrorKind::ImportFailed(mesh_file_path.to_string(), error.to_string()))?;
+
+        if scene.is_incomplete() {
+            bail!(ErrorKind::ImportFailed(mesh_file_path.to_string(), "the imported scene is incomplete".to_string()));
         }
 
-        None
+        // Texture paths within materials are relative to the model file:
+        let model_directory = Path::new(mesh_file_path).parent().unwrap_or(Path::new(""));
+
+        let materials: Vec<Rc<Material>> = scene
+            .material_iter()
+            .map(|material| Rc::new(unsafe { Self::process_material(&*material, model_directory) }))
+            .collect();
+
+        Ok(Self::process_node(&scene, &scene.root_node(), &materials))
     }
 
     fn convert_assimp_matrix(m: Matrix4x4) -> Matrix4<f32> {
@@ -57,58 +151,94 @@ impl Importer {
         )
     }
 
-    fn process_node(scene: &assimp::Scene, node: &assimp::Node) -> scene::Node {
+    fn process_node(scene: &assimp::Scene, node: &assimp::Node, materials: &[Rc<Material>]) -> scene::Node {
         let scene_node_transform = Self::convert_assimp_matrix(node.transformation());
         let mut scene_node = scene::Node::new(node.name(), scene_node_transform);
 
         // Load all meshes contained within the assimp Scene Node:
         for mesh_index in node.meshes() {
             if let Some(assimp_mesh) = scene.mesh(*mesh_index as usize) {
-                let scene_node_mesh = Self::process_mesh(assimp_mesh);
+                let scene_node_mesh = Self::process_mesh(assimp_mesh, materials);
                 scene_node.add_mesh(scene_node_mesh);
             }
         }
 
         for child_node in node.child_iter() {
-            let scene_node_child = Self::process_node(scene, &child_node);
+            let scene_node_child = Self::process_node(scene, &child_node, materials);
             scene_node.add_child_node(scene_node_child);
         }
 
         scene_node
     }
 
-    fn process_mesh(assimp_mesh: assimp::Mesh) -> Mesh {
+    fn process_mesh(assimp_mesh: assimp::Mesh, materials: &[Rc<Material>]) -> Mesh {
         // Translate assimp datatypes to corporation datatypes:
         let name = String::from_utf8(assimp_mesh.name.data.to_vec()).unwrap();
 
-        let num_vertices = assimp_mesh.num_vertices() as usize;
+        let num_vertices = assimp_mesh.num_vertices();
 
-        let mut vertices = Vec::with_capacity(num_vertices);
+        let material = materials.get(assimp_mesh.material_index as usize).cloned();
 
-        for i in 0..num_vertices {
-            let mut position: [f32; 3] = [0.0, 0.0, 0.0];
-            let mut tex_coord: [f32; 2] = [0.0, 0.0];
+        let normals = if assimp_mesh.has_normals() {
+            Some(Self::collect_vec3(num_vertices, |i| assimp_mesh.get_normal(i)))
+        } else {
+            None
+        };
 
-            if assimp_mesh.has_positions() {
-                if let Some(ai_vertex) = assimp_mesh.get_vertex(i as u32) {
-                    position = [ai_vertex.x, ai_vertex.y, ai_vertex.z];
-                }
-            }
+        let (tangents, bitangents) = if assimp_mesh.has_tangents_and_bitangents() {
+            (
+                Some(Self::collect_vec3(num_vertices, |i| assimp_mesh.get_tangent(i))),
+                Some(Self::collect_vec3(num_vertices, |i| assimp_mesh.get_bitangent(i))),
+            )
+        } else {
+            (None, None)
+        };
 
-            if assimp_mesh.has_texture_coords(i) {
-                // TODO: Implement multiple UV channel lookup
-                const UV_CHANNEL_ZERO: usize = 0;
+        let colors: Vec<Vec<Vec4>> = (0..AI_MAX_NUMBER_OF_COLOR_SETS)
+            .take_while(|color_set| assimp_mesh.has_vertex_colors(*color_set))
+            .map(|color_set| {
+                (0..num_vertices)
+                    .map(|i| {
+                        assimp_mesh
+                            .get_vertex_color(color_set, i)
+                            .map_or([1.0, 1.0, 1.0, 1.0], |color| [color.r, color.g, color.b, color.a])
+                    })
+                    .collect()
+            })
+            .collect();
 
-                if let Some(ai_tex_coord) = assimp_mesh.get_texture_coord(UV_CHANNEL_ZERO, i as u32) {
-                    tex_coord = [ai_tex_coord.x, ai_tex_coord.y];
+        let tex_coords: Vec<Vec<Vec2>> = (0..AI_MAX_NUMBER_OF_TEXTURECOORDS)
+            .take_while(|uv_channel| assimp_mesh.has_texture_coords(*uv_channel))
+            .map(|uv_channel| {
+                (0..num_vertices)
+                    .map(|i| {
+                        assimp_mesh
+                            .get_texture_coord(uv_channel, i)
+                            .map_or([0.0, 0.0], |tex_coord| [tex_coord.x, tex_coord.y])
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let vertices: Vec<Vertex> = (0..num_vertices)
+            .map(|i| {
+                let mut position: Vec3 = [0.0, 0.0, 0.0];
+
+                if assimp_mesh.has_positions() {
+                    if let Some(ai_vertex) = assimp_mesh.get_vertex(i) {
+                        position = [ai_vertex.x, ai_vertex.y, ai_vertex.z];
+                    }
                 }
-            }
 
-            vertices.push(Vertex {
-                a_Position: position,
-                a_TexCoord: tex_coord,
-            });
-        }
+                // The interleaved vertex samples the first UV channel only:
+                let tex_coord = tex_coords.first().map_or([0.0, 0.0], |uv_channel| uv_channel[i as usize]);
+
+                Vertex {
+                    a_Position: position,
+                    a_TexCoord: tex_coord,
+                }
+            })
+            .collect();
 
         let faces: Vec<Face> = assimp_mesh
             .face_iter()
@@ -123,6 +253,103 @@ impl Importer {
                 Face { indices }
             }).collect();
 
-        Mesh { name, vertices, faces }
+        Mesh {
+            name,
+            vertices,
+            faces,
+            normals,
+            tangents,
+            bitangents,
+            colors,
+            tex_coords,
+            material,
+        }
+    }
+
+    /// Reads the supported properties and textures of an assimp material
+    unsafe fn process_material(ai_material: &AiMaterial, model_directory: &Path) -> Material {
+        let mut ai_name: AiString = std::mem::zeroed();
+        let name = match aiGetMaterialString(ai_material, Self::material_key(AI_MATKEY_NAME), 0, 0, &mut ai_name) {
+            AiReturn::Success => Self::convert_assimp_string(&ai_name),
+            _ => String::new(),
+        };
+
+        let mut material = Material::new(&name);
+
+        if let Some(diffuse) = Self::material_color(ai_material, AI_MATKEY_COLOR_DIFFUSE) {
+            material.base_color = [diffuse.r, diffuse.g, diffuse.b, diffuse.a];
+        }
+
+        if let Some(specular) = Self::material_color(ai_material, AI_MATKEY_COLOR_SPECULAR) {
+            material.specular = [specular.r, specular.g, specular.b];
+        }
+
+        if let Some(shininess) = Self::material_float(ai_material, AI_MATKEY_SHININESS) {
+            material.shininess = shininess;
+        }
+
+        if let Some(opacity) = Self::material_float(ai_material, AI_MATKEY_OPACITY) {
+            material.opacity = opacity;
+        }
+
+        for &(texture_type, slot) in TEXTURE_SLOTS.iter() {
+            let mut path: AiString = std::mem::zeroed();
+
+            let result = aiGetMaterialTexture(
+                ai_material,
+                texture_type,
+                0,
+                &mut path,
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            );
+
+            if let AiReturn::Success = result {
+                material.set_texture(slot, model_directory.join(Self::convert_assimp_string(&path)));
+            }
+        }
+
+        material
+    }
+
+    unsafe fn material_color(ai_material: &AiMaterial, key: &[u8]) -> Option<AiColor4D> {
+        let mut color: AiColor4D = std::mem::zeroed();
+
+        match aiGetMaterialColor(ai_material, Self::material_key(key), 0, 0, &mut color) {
+            AiReturn::Success => Some(color),
+            _ => None,
+        }
+    }
+
+    unsafe fn material_float(ai_material: &AiMaterial, key: &[u8]) -> Option<f32> {
+        let mut value = 0.0f32;
+        let mut max: c_uint = 1;
+
+        match aiGetMaterialFloatArray(ai_material, Self::material_key(key), 0, 0, &mut value, &mut max) {
+            AiReturn::Success => Some(value),
+            _ => None,
+        }
+    }
+
+    fn material_key(key: &[u8]) -> *const c_char {
+        key.as_ptr() as *const c_char
+    }
+
+    fn convert_assimp_string(ai_string: &AiString) -> String {
+        String::from_utf8_lossy(&ai_string.data[..ai_string.length as usize]).into_owned()
+    }
+
+    /// Collects a per-vertex stream of assimp vectors, zeroing any that are missing
+    fn collect_vec3<F>(num_vertices: u32, get_vector: F) -> Vec<Vec3>
+    where
+        F: Fn(u32) -> Option<Vector3D>,
+    {
+        (0..num_vertices)
+            .map(|i| get_vector(i).map_or([0.0, 0.0, 0.0], |vector| [vector.x, vector.y, vector.z]))
+            .collect()
     }
 }
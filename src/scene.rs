@@ -41,6 +41,21 @@ impl Node {
     pub fn meshes(&self) -> &Vec<Mesh> {
         &self.meshes
     }
+
+    /// Visits this node and all of its descendants, passing each node along
+    /// with its transformation accumulated from the parent transformation.
+    pub fn visit<F>(&self, parent_transformation: &Matrix4<f32>, visitor: &mut F)
+    where
+        F: FnMut(&Node, &Matrix4<f32>),
+    {
+        let transformation = parent_transformation * self.transformation;
+
+        visitor(self, &transformation);
+
+        for child in &self.children {
+            child.visit(&transformation, visitor);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -59,4 +74,20 @@ pub mod tests {
         assert_eq!(1, root.children.len());
 
     }
+
+    #[test]
+    fn should_accumulate_transformations_when_visiting() {
+        let mut root = Node::new("root", Matrix4::from_translation(Vector3::new(1.0, 0.0, 0.0)));
+        let child = Node::new("child", Matrix4::from_translation(Vector3::new(0.0, 2.0, 0.0)));
+        root.add_child_node(child);
+
+        let mut visited = Vec::new();
+        root.visit(&Matrix4::identity(), &mut |node, transformation| {
+            visited.push((node.name().to_string(), *transformation));
+        });
+
+        assert_eq!(2, visited.len());
+        assert_eq!(("root".to_string(), Matrix4::from_translation(Vector3::new(1.0, 0.0, 0.0))), visited[0]);
+        assert_eq!(("child".to_string(), Matrix4::from_translation(Vector3::new(1.0, 2.0, 0.0))), visited[1]);
+    }
 }
\ No newline at end of file
@@ -1,10 +1,14 @@
 extern crate libcorporation;
 extern crate cgmath;
 
+use libcorporation::errors::{Error, ErrorKind};
+use libcorporation::material::TextureSlot;
 use libcorporation::mesh::Importer;
+use std::path::Path;
 use self::cgmath::*;
 
 const MESH_FILE_PATH : &str = "/tests/resources/box.obj";
+const MATERIAL_MESH_FILE_PATH : &str = "/resources/models/box/box.obj";
 
 #[test]
 fn should_process_node() {
@@ -12,13 +16,16 @@ fn should_process_node() {
     const EXPECTED_MESH_NAME : &str = "TestBoxModel";
     const EXPECTED_MESH_COUNT : usize = 1;
     const EXPECTED_VERTEX_COUNT : usize = 24;
+    const EXPECTED_INDEX_COUNT : usize = 36;
+    const EXPECTED_UV_CHANNEL_COUNT : usize = 1;
+    const EXPECTED_FIRST_NORMAL : [f32; 3] = [0.0, -1.0, 0.0];
 
     let mat4_identity = Matrix4::<f32>::identity();
 
     let file_path = env!("CARGO_MANIFEST_DIR").to_owned() + MESH_FILE_PATH;
 
     let scene_node_root = Importer::load(&file_path);
-    assert!(scene_node_root.is_some());
+    assert!(scene_node_root.is_ok());
 
     // Ensure we have a scene root node:
     let scene_node_root = scene_node_root.unwrap();
@@ -34,4 +41,46 @@ fn should_process_node() {
     // Ensure the mesh has the correct attributes:
     let mesh = &mesh_node.meshes()[0];
     assert_eq!(EXPECTED_VERTEX_COUNT, mesh.vertex_iter().len());
+    assert_eq!(EXPECTED_INDEX_COUNT, mesh.indices().len());
+
+    // Ensure the per-vertex attribute streams were imported:
+    let normals = mesh.normals().unwrap();
+    assert_eq!(EXPECTED_VERTEX_COUNT, normals.len());
+    assert_eq!(EXPECTED_FIRST_NORMAL, normals[0]);
+
+    assert_eq!(EXPECTED_UV_CHANNEL_COUNT, mesh.num_uv_channels());
+    assert_eq!(EXPECTED_VERTEX_COUNT, mesh.tex_coords(0).unwrap().len());
+
+    assert!(mesh.tangents().is_none());
+    assert!(mesh.colors(0).is_none());
+}
+
+#[test]
+fn should_raise_asset_not_found_error() {
+    let file_path = env!("CARGO_MANIFEST_DIR").to_owned() + "/tests/resources/missing.obj";
+
+    match Importer::load(&file_path) {
+        Err(Error(ErrorKind::AssetNotFound(path), _)) => assert_eq!(file_path, path),
+        _ => panic!("Expected an AssetNotFound error"),
+    }
+}
+
+#[test]
+fn should_process_material() {
+    const EXPECTED_MATERIAL_NAME : &str = "01___Default";
+    const EXPECTED_SHININESS : f32 = 44.0;
+
+    let file_path = env!("CARGO_MANIFEST_DIR").to_owned() + MATERIAL_MESH_FILE_PATH;
+
+    let scene_node_root = Importer::load(&file_path).unwrap();
+    let mesh = &scene_node_root.children()[0].meshes()[0];
+
+    let material = mesh.material().unwrap();
+    assert_eq!(EXPECTED_MATERIAL_NAME, material.name());
+    assert_eq!(EXPECTED_SHININESS, material.shininess);
+    assert_eq!([0.588, 0.588, 0.588, 1.0], material.base_color);
+
+    // Texture paths are resolved relative to the model file:
+    let expected_diffuse_path = Path::new(&file_path).parent().unwrap().join("diffuse.png");
+    assert_eq!(Some(expected_diffuse_path.as_path()), material.texture(TextureSlot::Diffuse));
 }
\ No newline at end of file